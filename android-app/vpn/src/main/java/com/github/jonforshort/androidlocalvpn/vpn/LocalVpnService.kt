@@ -32,12 +32,21 @@ import android.content.Intent
 import android.net.VpnService
 import android.os.Build.VERSION
 import android.os.Build.VERSION_CODES
+import android.os.Handler
+import android.os.Looper
 import android.os.ParcelFileDescriptor
 import android.os.Parcelable
+import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_ACTION_CLOSE_SESSIONS
+import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_ACTION_RELOAD_RULES
 import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_ACTION_START_VPN
 import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_ACTION_STOP_VPN
 import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_EXTRA_CONFIGURATION
+import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_EXTRA_ALLOWED_SOURCES
+import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_EXTRA_BLOCKED_DESTINATIONS
+import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_EXTRA_DESTINATION
+import com.github.jonforshort.androidlocalvpn.vpn.LocalVpnService.Companion.INTENT_EXTRA_ENFORCE
 import timber.log.Timber.e
+import timber.log.Timber.i
 import java.io.IOException
 import java.net.NetworkInterface
 
@@ -56,6 +65,37 @@ internal fun stopVpn(context: Context) {
     context.startService(intent)
 }
 
+internal fun closeSessions(context: Context, destination: String) {
+    val intent = Intent(context, LocalVpnService::class.java).apply {
+        action = INTENT_ACTION_CLOSE_SESSIONS
+        putExtra(INTENT_EXTRA_DESTINATION, destination)
+    }
+    context.startService(intent)
+}
+
+/**
+ * Replaces the rules of the running vpn without restarting it.
+ *
+ * Only the networks passed are replaced, null keeps the current ones and the other rules stay as
+ * they are. A list replaces the networks whole: an empty [blockedDestinations] also drops the
+ * default blocked destinations, loopback, multicast, link-local and unspecified. With [enforce] the
+ * sessions the new rules refuse are closed as well.
+ */
+internal fun reloadRules(
+    context: Context,
+    blockedDestinations: List<String>?,
+    allowedSources: List<String>?,
+    enforce: Boolean
+) {
+    val intent = Intent(context, LocalVpnService::class.java).apply {
+        action = INTENT_ACTION_RELOAD_RULES
+        blockedDestinations?.let { putExtra(INTENT_EXTRA_BLOCKED_DESTINATIONS, it.joinToString(",")) }
+        allowedSources?.let { putExtra(INTENT_EXTRA_ALLOWED_SOURCES, it.joinToString(",")) }
+        putExtra(INTENT_EXTRA_ENFORCE, enforce)
+    }
+    context.startService(intent)
+}
+
 internal fun isVpnRunning(context: Context) = isVpnTunnelUp() && isVpnServiceRunning(context)
 
 @Suppress("DEPRECATION")
@@ -84,7 +124,13 @@ internal class LocalVpnService : VpnService() {
 
         internal const val INTENT_ACTION_START_VPN = "LocalVpnServiceStartVpn"
         internal const val INTENT_ACTION_STOP_VPN = "LocalVpnServiceStopVpn"
+        internal const val INTENT_ACTION_CLOSE_SESSIONS = "LocalVpnServiceCloseSessions"
+        internal const val INTENT_ACTION_RELOAD_RULES = "LocalVpnServiceReloadRules"
         internal const val INTENT_EXTRA_CONFIGURATION = "LocalVpnServiceConfiguration"
+        internal const val INTENT_EXTRA_DESTINATION = "LocalVpnServiceDestination"
+        internal const val INTENT_EXTRA_BLOCKED_DESTINATIONS = "LocalVpnServiceBlockedDestinations"
+        internal const val INTENT_EXTRA_ALLOWED_SOURCES = "LocalVpnServiceAllowedSources"
+        internal const val INTENT_EXTRA_ENFORCE = "LocalVpnServiceEnforce"
 
         init {
             System.loadLibrary("vpn")
@@ -100,6 +146,21 @@ internal class LocalVpnService : VpnService() {
             INTENT_ACTION_STOP_VPN -> {
                 stopVpn()
             }
+
+            INTENT_ACTION_CLOSE_SESSIONS -> {
+                intent.getStringExtra(INTENT_EXTRA_DESTINATION)?.let { onCloseSessions(it) }
+            }
+
+            INTENT_ACTION_RELOAD_RULES -> {
+                val closed = onReloadRules(
+                    intent.getStringExtra(INTENT_EXTRA_BLOCKED_DESTINATIONS),
+                    intent.getStringExtra(INTENT_EXTRA_ALLOWED_SOURCES),
+                    intent.getBooleanExtra(INTENT_EXTRA_ENFORCE, false)
+                )
+                if (closed < 0) {
+                    e("failed to reload rules")
+                }
+            }
         }
         return START_STICKY
     }
@@ -122,7 +183,11 @@ internal class LocalVpnService : VpnService() {
     private fun startVpn(configuration: LocalVpnConfiguration?) {
         setUpVpnInterface(configuration)
         onCreateNative(this)
-        onStartVpn(vpnInterface.detachFd())
+        i("starting native vpn, ${onGetVersion()}")
+        if (!onStartVpn(vpnInterface.detachFd())) {
+            e("failed to start native vpn")
+            stopVpn()
+        }
     }
 
     private fun setUpVpnInterface(configuration: LocalVpnConfiguration?) {
@@ -145,6 +210,23 @@ internal class LocalVpnService : VpnService() {
             .establish()!!
     }
 
+    //
+    // called by the native vpn from its own thread once it stopped on its own, e.g. because the tun
+    // kept failing after the system revoked the vpn, with the reason and whether the kill switch
+    // now drops the packets. stopping joins that thread and the native calls wait for it, so both
+    // are done elsewhere. with the kill switch the vpn stays up, so no traffic falls back to the
+    // default route, until it is stopped by the user.
+    //
+    @Suppress("unused")
+    private fun onTunFailed(reason: String, blocking: Boolean) {
+        if (blocking) {
+            e("native vpn failed, reason=$reason, blocking traffic until the vpn is stopped")
+            return
+        }
+        e("native vpn stopped, reason=$reason")
+        Handler(Looper.getMainLooper()).post { stopVpn() }
+    }
+
     override fun onDestroy() {
         super.onDestroy()
         onDestroyNative()
@@ -154,9 +236,15 @@ internal class LocalVpnService : VpnService() {
 
     private external fun onDestroyNative()
 
-    private external fun onStartVpn(fileDescriptor: Int)
+    private external fun onStartVpn(fileDescriptor: Int): Boolean
 
     private external fun onStopVpn()
+
+    private external fun onCloseSessions(destination: String): Int
+
+    private external fun onGetVersion(): String
+
+    private external fun onReloadRules(blockedDestinations: String?, allowedSources: String?, enforce: Boolean): Int
 }
 
 private inline fun <reified T : Parcelable> Intent.getParcelableExtraCompat(key: String) = when {
@@ -1,7 +1,7 @@
 mod jni_context;
 
 use jni::{
-    objects::{GlobalRef, JClass, JMethodID, JObject},
+    objects::{GlobalRef, JClass, JMethodID, JObject, JValue},
     JNIEnv, JavaVM,
 };
 pub use jni_context::JniContext;
@@ -35,7 +35,7 @@ impl Jni {
         *jni = None;
     }
 
-    pub fn new_context(&self) -> Option<JniContext> {
+    pub fn new_context(&self) -> Option<JniContext<'_>> {
         match self.java_vm.attach_current_thread_permanently() {
             Ok(jni_env) => match Jni::get_protect_method_id(unsafe { jni_env.unsafe_clone() }) {
                 Some(protect_method_id) => {
@@ -57,6 +57,25 @@ impl Jni {
         None
     }
 
+    /// Calls the method `name`, taking a string and a boolean and returning nothing, on the vpn
+    /// service from the current thread.
+    pub fn call_void_method(&self, name: &str, string: &str, boolean: bool) {
+        match self.java_vm.attach_current_thread() {
+            Ok(mut jni_env) => {
+                let result = jni_env.new_string(string).and_then(|string| {
+                    let args = [JValue::Object(&string), JValue::Bool(boolean.into())];
+                    jni_env.call_method(self.object.as_obj(), name, "(Ljava/lang/String;Z)V", &args)
+                });
+                if let Err(error) = result {
+                    log::error!("failed to call method, name={} error={:?}", name, error);
+                }
+            }
+            Err(error) => {
+                log::error!("failed to attach to current thread, error={:?}", error);
+            }
+        }
+    }
+
     fn get_protect_method_id(mut jni_env: JNIEnv) -> Option<JMethodID> {
         match jni_env.find_class("android/net/VpnService") {
             Ok(class) => match jni_env.get_method_id(class, "protect", "(I)Z") {
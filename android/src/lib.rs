@@ -1,17 +1,35 @@
+#[cfg(feature = "android")]
 #[macro_use]
 mod jni;
 
+#[cfg(feature = "android")]
 #[macro_use]
 mod socket_protector;
 
+#[cfg(feature = "android")]
 pub mod android {
 
     use crate::{jni::Jni, socket_protector::SocketProtector};
     use android_logger::Config;
     use jni::{
-        objects::{JClass, JObject},
+        objects::{JClass, JObject, JString},
+        sys::{jboolean, jint, JNI_FALSE, JNI_TRUE},
         JNIEnv,
     };
+    use std::{
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+    };
+
+    // whether the vpn was started and not stopped yet, so it is stopped exactly once.
+    static IS_VPN_STARTED: AtomicBool = AtomicBool::new(false);
+
+    // the rules last taken by `onReloadRules`, unset while the default ones apply. a reload only
+    // replaces the rules it is passed, like the control socket of the host.
+    static RULES: Mutex<Option<tuncore::Rules>> = Mutex::new(None);
 
     /// # Safety
     ///
@@ -32,7 +50,12 @@ pub mod android {
     #[no_mangle]
     pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onDestroyNative(_: JNIEnv, _: JClass) {
         log::trace!("onDestroyNative");
+        // the service may be destroyed without stopping the vpn first. the processor has to be
+        // stopped before the socket protector and the jni it calls into are released.
+        stop_vpn();
         tuncore::tun::destroy();
+        // the reloaded rules go with the vpn, the next one starts with the default ones.
+        *RULES.lock().unwrap() = None;
         SocketProtector::release();
         Jni::release();
         remove_panic_handler();
@@ -42,12 +65,26 @@ pub mod android {
     ///
     /// This function should only be used in jni context.
     #[no_mangle]
-    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onStartVpn(_: JNIEnv, _: JClass, file_descriptor: i32) {
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onStartVpn(
+        _: JNIEnv,
+        _: JClass,
+        file_descriptor: i32,
+    ) -> jboolean {
         log::trace!("onStartVpn, pid={}, fd={}", std::process::id(), file_descriptor);
         #[cfg(unix)]
         tuncore::tun_callbacks::set_socket_created_callback(Some(on_socket_created));
+        #[cfg(unix)]
+        tuncore::tun_callbacks::set_tun_failed_callback(Some(on_tun_failed));
         socket_protector!().start();
-        tuncore::tun::start(file_descriptor);
+        if let Err(error) = tuncore::tun::start(file_descriptor) {
+            log::error!("failed to start vpn, error={:?}", error);
+            release_vpn();
+            // detached by the service, nobody else closes it.
+            libc::close(file_descriptor);
+            return JNI_FALSE;
+        }
+        IS_VPN_STARTED.store(true, Ordering::SeqCst);
+        JNI_TRUE
     }
 
     /// # Safety
@@ -56,10 +93,143 @@ pub mod android {
     #[no_mangle]
     pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onStopVpn(_: JNIEnv, _: JClass) {
         log::trace!("onStopVpn, pid={}", std::process::id());
-        tuncore::tun::stop();
+        stop_vpn();
+    }
+
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onCloseSessions(
+        mut env: JNIEnv,
+        _: JClass,
+        destination: JString,
+    ) -> jint {
+        let destination: String = match env.get_string(&destination) {
+            Ok(destination) => destination.into(),
+            Err(error) => {
+                log::error!("failed to get destination, error={:?}", error);
+                return 0;
+            }
+        };
+        log::trace!("onCloseSessions, pid={}, destination={}", std::process::id(), destination);
+        match destination.parse::<IpAddr>() {
+            Ok(ip) => match tuncore::tun::close_sessions(move |info| info.destination.ip() == ip) {
+                Ok(count) => count as jint,
+                Err(error) => {
+                    log::error!("failed to close sessions, error={:?}", error);
+                    0
+                }
+            },
+            Err(error) => {
+                log::error!("invalid destination, destination={} error={:?}", destination, error);
+                0
+            }
+        }
+    }
+
+    /// Replaces the blocked destinations and allowed sources, comma separated networks, and returns
+    /// the count of sessions closed when `enforce` is set, or -1 when the networks are invalid or the
+    /// processor did not take the rules. A null string keeps the current networks, an empty one
+    /// clears them. The other rules stay as they are.
+    ///
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onReloadRules(
+        mut env: JNIEnv,
+        _: JClass,
+        blocked_destinations: JString,
+        allowed_sources: JString,
+        enforce: jboolean,
+    ) -> jint {
+        log::trace!("onReloadRules, pid={}, enforce={}", std::process::id(), enforce);
+        let mut rules = RULES.lock().unwrap();
+        let mut new_rules = rules.clone().unwrap_or_default();
+        for (networks_string, networks) in [
+            (&blocked_destinations, &mut new_rules.blocked_destinations),
+            (&allowed_sources, &mut new_rules.allowed_sources),
+        ] {
+            if networks_string.is_null() {
+                continue;
+            }
+            match parse_networks(&mut env, networks_string) {
+                Some(parsed) => *networks = parsed,
+                None => return -1,
+            }
+        }
+        match tuncore::tun::reload_rules(new_rules.clone(), enforce != 0) {
+            Ok(count) => {
+                *rules = Some(new_rules);
+                count as jint
+            }
+            Err(error) => {
+                log::error!("failed to reload rules, error={:?}", error);
+                -1
+            }
+        }
+    }
+
+    fn parse_networks(env: &mut JNIEnv, networks_string: &JString) -> Option<Vec<tuncore::IpNetwork>> {
+        let networks_string: String = match env.get_string(networks_string) {
+            Ok(networks_string) => networks_string.into(),
+            Err(error) => {
+                log::error!("failed to get networks, error={:?}", error);
+                return None;
+            }
+        };
+        let parsed = networks_string
+            .split(',')
+            .filter(|network| !network.is_empty())
+            .map(|network| network.parse::<tuncore::IpNetwork>())
+            .collect::<tuncore::Result<Vec<_>>>();
+        match parsed {
+            Ok(parsed) => Some(parsed),
+            Err(error) => {
+                log::error!("invalid networks, networks={} error={:?}", networks_string, error);
+                None
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// This function should only be used in jni context.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_jonforshort_androidlocalvpn_vpn_LocalVpnService_onGetVersion<'local>(
+        env: JNIEnv<'local>,
+        _: JClass,
+    ) -> JString<'local> {
+        let version = tuncore::tun::version().to_string();
+        log::trace!("onGetVersion, version={}", version);
+        match env.new_string(version) {
+            Ok(version) => version,
+            Err(error) => {
+                log::error!("failed to create version string, error={:?}", error);
+                JString::default()
+            }
+        }
+    }
+
+    // the processor is joined first, so no protect request is in flight when the socket protector
+    // stops.
+    fn stop_vpn() {
+        if !IS_VPN_STARTED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Err(error) = tuncore::tun::stop() {
+            log::error!("failed to stop vpn, error={:?}", error);
+        }
+        release_vpn();
+    }
+
+    fn release_vpn() {
         socket_protector!().stop();
         #[cfg(unix)]
         tuncore::tun_callbacks::set_socket_created_callback(None);
+        #[cfg(unix)]
+        tuncore::tun_callbacks::set_tun_failed_callback(None);
     }
 
     fn set_panic_handler() {
@@ -73,7 +243,12 @@ pub mod android {
     }
 
     #[allow(dead_code)]
-    fn on_socket_created(socket: i32) {
-        socket_protector!().protect_socket(socket);
+    fn on_socket_created(socket: i32) -> bool {
+        socket_protector!().protect_socket(socket)
+    }
+
+    #[allow(dead_code)]
+    fn on_tun_failed(reason: tuncore::ShutdownReason, state: tuncore::TunState) {
+        jni!().call_void_method("onTunFailed", &format!("{:?}", reason), state == tuncore::TunState::Blocking);
     }
 }
@@ -9,8 +9,12 @@ use std::{
         Arc, Mutex,
     },
     thread::JoinHandle,
+    time::Duration,
 };
 
+// a protect takes a binder call at most, waiting longer means the protecting thread is gone.
+const PROTECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 lazy_static::lazy_static! {
     pub static ref SOCKET_PROTECTOR: Mutex<Option<SocketProtector>> = Mutex::new(None);
 }
@@ -21,8 +25,9 @@ macro_rules! socket_protector {
     };
 }
 
-type SenderChannel = Sender<(i32, Sender<bool>)>;
-type ReceiverChannel = Receiver<(i32, Sender<bool>)>;
+type Request = (i32, Sender<bool>);
+type SenderChannel = Sender<Request>;
+type ReceiverChannel = Receiver<Request>;
 type ChannelPair = (SenderChannel, ReceiverChannel);
 
 pub struct SocketProtector {
@@ -55,7 +60,18 @@ impl SocketProtector {
             log::trace!("socket protecting thread is started");
             if let Some(mut jni_context) = jni!().new_context() {
                 while is_thread_running.load(Ordering::SeqCst) {
-                    SocketProtector::handle_protect_socket_request(&receiver_channel, &mut jni_context);
+                    match receiver_channel.recv() {
+                        Ok(request) => SocketProtector::handle_protect_socket_request(request, &mut jni_context),
+                        Err(error) => {
+                            log::error!("failed to receive request, error={:?}", error);
+                            break;
+                        }
+                    }
+                }
+                // answer the requests queued behind the one waking the thread up to stop, so none
+                // of their senders waits forever.
+                while let Ok(request) = receiver_channel.try_recv() {
+                    SocketProtector::handle_protect_socket_request(request, &mut jni_context);
                 }
             }
             log::trace!("socket protecting thread is stopping");
@@ -69,11 +85,18 @@ impl SocketProtector {
         // solely used for unblocking thread responsible for protecting sockets.
         //
         self.protect_socket(-1);
-        self.thread_join_handle.take().unwrap().join().unwrap();
+        match self.thread_join_handle.take() {
+            Some(handle) => {
+                if let Err(error) = handle.join() {
+                    log::error!("failed to join socket protecting thread, error={:?}", error);
+                }
+            }
+            None => log::error!("socket protecting thread is not running"),
+        }
     }
 
-    fn handle_protect_socket_request(receiver: &ReceiverChannel, jni_context: &mut JniContext) {
-        let (socket, reply_sender) = receiver.recv().unwrap();
+    fn handle_protect_socket_request(request: Request, jni_context: &mut JniContext) {
+        let (socket, reply_sender) = request;
         let is_socket_protected = if socket <= 0 {
             log::trace!("found invalid socket, socket={:?}", socket);
             false
@@ -98,7 +121,7 @@ impl SocketProtector {
         let reply_channel: (Sender<bool>, Receiver<bool>) = unbounded();
         match self.channel.0.send((socket, reply_channel.0)) {
             Ok(_) => {
-                let result = reply_channel.1.recv();
+                let result = reply_channel.1.recv_timeout(PROTECT_TIMEOUT);
                 match result {
                     Ok(is_socket_protected) => {
                         if is_socket_protected {
@@ -0,0 +1,271 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Serves line-based control commands on a unix socket at `path`.
+///
+/// Supported commands:
+/// * `list` - prints every session, one per line, followed by the session count.
+/// * `close <ip>|<ip:port>` - closes every session whose destination matches.
+/// * `health` - prints the session count, the upstream connect and first byte latency histograms
+///   and the count of short packets dropped, the count of upstream connects in progress and the count of
+///   sessions deferred by the per destination limit, the bytes buffered and the count of sessions
+///   throttled by the buffer budget, and the moving averages of new sessions and
+///   bytes per second.
+/// * `reset-stats [sessions]` - prints the health as it was, then zeroes its cumulative counters
+///   and with `sessions` prints and zeroes the bytes exchanged by every session.
+/// * `version` - prints the versions the packet engine was built from.
+/// * `rules` - prints the rules deciding which sessions are refused.
+/// * `dump tcp|udp <source> <destination>` - prints the internal state of the session between the
+///   given addresses, e.g. `dump tcp 192.0.2.2:40000 10.0.0.4:8000`.
+/// * `mtu <bytes>` - changes the MTU of the existing and new sessions.
+/// * `capture start <directory> [dst=<cidr>,...] [port=<port>] [proto=tcp|udp]` - records the
+///   packets of the matching sessions to a pcap file per session in the directory.
+/// * `capture stop [dst=<cidr>,...] [port=<port>] [proto=tcp|udp]` - stops the capture started
+///   with the same filter.
+/// * `reload [blocked=<cidr>,...] [allowed=<cidr>,...] [block-quic=on|off] [enforce]` - replaces
+///   the given rules, keeping the others, and with `enforce` closes the sessions they refuse.
+///
+/// `rules` are the rules the packet engine was started with.
+pub fn start(path: &Path, rules: tuncore::Rules) -> std::io::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let rules = Arc::new(Mutex::new(rules));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(error) = handle_client(stream, &rules) {
+                        eprintln!("control client failed, error={:?}", error);
+                    }
+                }
+                Err(error) => {
+                    eprintln!("failed to accept control client, error={:?}", error);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, rules: &Mutex<tuncore::Rules>) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = handle_command(line?.trim(), rules);
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+fn handle_command(line: &str, rules: &Mutex<tuncore::Rules>) -> String {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("list"), None) => match tuncore::tun::sessions() {
+            Ok(sessions) => {
+                let mut reply = sessions.iter().map(|info| format!("{}\n", info)).collect::<String>();
+                reply.push_str(&format!("sessions {}", sessions.len()));
+                reply
+            }
+            Err(error) => format!("error: {}", error),
+        },
+        (Some("health"), None) => match tuncore::tun::health() {
+            Ok(health) => format_health(&health),
+            Err(error) => format!("error: {}", error),
+        },
+        (Some("reset-stats"), sessions @ (None | Some("sessions"))) => match tuncore::tun::reset_stats(sessions.is_some()) {
+            Ok(snapshot) => {
+                let mut reply = format_health(&snapshot.health);
+                for counters in &snapshot.sessions {
+                    reply.push_str(&format!(
+                        "\n{} from guest {} to guest {}",
+                        counters.session_info, counters.bytes_from_guest, counters.bytes_to_guest
+                    ));
+                }
+                reply
+            }
+            Err(error) => format!("error: {}", error),
+        },
+        (Some("version"), None) => tuncore::tun::version().to_string(),
+        (Some("rules"), None) => {
+            let rules = rules.lock().unwrap();
+            format!(
+                "blocked={}\nallowed={}\nblock-quic={}",
+                format_networks(&rules.blocked_destinations),
+                format_networks(&rules.allowed_sources),
+                if rules.block_quic { "on" } else { "off" }
+            )
+        }
+        (Some("reload"), first) => match reload_rules(first.into_iter().chain(parts), rules) {
+            Ok(count) => format!("reloaded, closed {}", count),
+            Err(error) => format!("error: {}", error),
+        },
+        (Some("dump"), protocol) => match parse_session(protocol.into_iter().chain(parts)) {
+            Some(session_info) => match tuncore::tun::dump_session(session_info) {
+                Ok(Some(dump)) => format_dump(&dump),
+                Ok(None) => format!("error: no session {}", session_info),
+                Err(error) => format!("error: {}", error),
+            },
+            None => format!("error: invalid session {}", line),
+        },
+        (Some("mtu"), Some(mtu)) => match mtu.parse::<usize>() {
+            Ok(mtu) => match tuncore::tun::set_mtu(mtu) {
+                Ok(count) => format!("mtu {}, updated {}", mtu, count),
+                Err(error) => format!("error: {}", error),
+            },
+            Err(error) => format!("error: invalid mtu {}, error={}", mtu, error),
+        },
+        (Some("capture"), Some("start")) => match parts.next() {
+            Some(directory) => match parse_capture_filter(parts) {
+                Ok(filter) => match tuncore::tun::start_capture(filter, directory.into()) {
+                    Ok(count) => format!("capturing {}", count),
+                    Err(error) => format!("error: {}", error),
+                },
+                Err(error) => format!("error: {}", error),
+            },
+            None => "error: missing capture directory".to_string(),
+        },
+        (Some("capture"), Some("stop")) => match parse_capture_filter(parts) {
+            Ok(filter) => match tuncore::tun::stop_capture(filter) {
+                Ok(count) => format!("stopped {}", count),
+                Err(error) => format!("error: {}", error),
+            },
+            Err(error) => format!("error: {}", error),
+        },
+        (Some("close"), Some(destination)) => match parse_destination(destination) {
+            Some(matches) => match tuncore::tun::close_sessions(matches) {
+                Ok(count) => format!("closed {}", count),
+                Err(error) => format!("error: {}", error),
+            },
+            None => format!("error: invalid destination {}", destination),
+        },
+        _ => format!("error: unknown command {}", line),
+    }
+}
+
+fn reload_rules<'a>(arguments: impl Iterator<Item = &'a str>, rules: &Mutex<tuncore::Rules>) -> Result<usize, String> {
+    let mut rules = rules.lock().unwrap();
+    let mut new_rules = rules.clone();
+    let mut enforce = false;
+    for argument in arguments {
+        match argument.split_once('=') {
+            Some(("blocked", networks)) => new_rules.blocked_destinations = parse_networks(networks)?,
+            Some(("allowed", networks)) => new_rules.allowed_sources = parse_networks(networks)?,
+            Some(("block-quic", "on")) => new_rules.block_quic = true,
+            Some(("block-quic", "off")) => new_rules.block_quic = false,
+            None if argument == "enforce" => enforce = true,
+            _ => return Err(format!("invalid argument {}", argument)),
+        }
+    }
+    let count = tuncore::tun::reload_rules(new_rules.clone(), enforce).map_err(|error| error.to_string())?;
+    *rules = new_rules;
+    Ok(count)
+}
+
+fn parse_networks(networks: &str) -> Result<Vec<tuncore::IpNetwork>, String> {
+    networks
+        .split(',')
+        .filter(|network| !network.is_empty())
+        .map(|network| network.parse::<tuncore::IpNetwork>().map_err(|error| error.to_string()))
+        .collect()
+}
+
+fn format_networks(networks: &[tuncore::IpNetwork]) -> String {
+    networks.iter().map(|network| network.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn parse_session<'a>(mut arguments: impl Iterator<Item = &'a str>) -> Option<tuncore::SessionInfo> {
+    let ip_protocol = match arguments.next()? {
+        "tcp" => smoltcp::wire::IpProtocol::Tcp,
+        "udp" => smoltcp::wire::IpProtocol::Udp,
+        _ => return None,
+    };
+    let source = arguments.next()?.parse::<SocketAddr>().ok()?;
+    let destination = arguments.next()?.parse::<SocketAddr>().ok()?;
+    if arguments.next().is_some() || source.is_ipv4() != destination.is_ipv4() {
+        return None;
+    }
+    Some(tuncore::SessionInfo::from_addresses(ip_protocol, source, destination))
+}
+
+fn parse_capture_filter<'a>(arguments: impl Iterator<Item = &'a str>) -> Result<tuncore::CaptureFilter, String> {
+    let mut filter = tuncore::CaptureFilter::default();
+    for argument in arguments {
+        match argument.split_once('=') {
+            Some(("dst", networks)) => filter.destinations = parse_networks(networks)?,
+            Some(("port", port)) => filter.port = Some(port.parse::<u16>().map_err(|error| error.to_string())?),
+            Some(("proto", "tcp")) => filter.protocol = Some(smoltcp::wire::IpProtocol::Tcp),
+            Some(("proto", "udp")) => filter.protocol = Some(smoltcp::wire::IpProtocol::Udp),
+            _ => return Err(format!("invalid argument {}", argument)),
+        }
+    }
+    Ok(filter)
+}
+
+fn format_health(health: &tuncore::ProcessorHealth) -> String {
+    let mut reply = format!("sessions {}\nconnects {}", health.sessions, health.connect_latency.total());
+    for (index, count) in health.connect_latency.counts.iter().enumerate() {
+        match tuncore::CONNECT_LATENCY_BOUNDS.get(index) {
+            Some(bound) => reply.push_str(&format!("\nconnect <{:?} {}", bound, count)),
+            None => reply.push_str(&format!("\nconnect >= {:?} {}", tuncore::CONNECT_LATENCY_BOUNDS[index - 1], count)),
+        }
+    }
+    reply.push_str(&format!("\nfirst bytes {}", health.first_byte_latency.total()));
+    for (index, count) in health.first_byte_latency.counts.iter().enumerate() {
+        match tuncore::CONNECT_LATENCY_BOUNDS.get(index) {
+            Some(bound) => reply.push_str(&format!("\nfirst byte <{:?} {}", bound, count)),
+            None => reply.push_str(&format!("\nfirst byte >= {:?} {}", tuncore::CONNECT_LATENCY_BOUNDS[index - 1], count)),
+        }
+    }
+    reply.push_str(&format!("\nshort packets {}", health.short_packets));
+    reply.push_str(&format!("\nhalf-open connections {}", health.half_open_connections));
+    reply.push_str(&format!("\ndestination limited {}", health.destination_limited));
+    reply.push_str(&format!("\naddress not available {}", health.address_not_available));
+    reply.push_str(&format!("\nbuffered bytes {}", health.buffered_bytes));
+    reply.push_str(&format!("\nthrottled sessions {}", health.throttled_sessions));
+    reply.push_str(&format!("\nsessions per second {:.2}", health.sessions_per_second));
+    reply.push_str(&format!("\nbytes per second {:.0}", health.bytes_per_second));
+    reply
+}
+
+fn format_dump(dump: &tuncore::SessionDump) -> String {
+    let mut reply = format!("session {}\ntoken {}", dump.session_info, dump.token);
+    if let Some(tcp_state) = &dump.tcp_state {
+        reply.push_str(&format!("\ntcp state {}", tcp_state));
+    }
+    reply.push_str(&format!("\npending to server {}", dump.pending_to_server));
+    reply.push_str(&format!("\npending to client {}", dump.pending_to_client));
+    reply.push_str(&format!("\nsmoltcp send queue {}", dump.smoltcp_send_queue));
+    reply.push_str(&format!("\nsmoltcp recv queue {}", dump.smoltcp_recv_queue));
+    reply.push_str(&format!("\nconnected {}", dump.connected));
+    reply.push_str(&format!("\nconnect failed {}", dump.connect_failed));
+    reply.push_str(&format!("\nupstream closed {}", dump.upstream_closed));
+    reply.push_str(&format!("\nupstream write shutdown {}", dump.upstream_write_shutdown));
+    reply.push_str(&format!("\ncontinue read {}", dump.continue_read));
+    reply.push_str(&format!("\nage {:?}", dump.age));
+    reply.push_str(&format!("\nidle {:?}", dump.idle));
+    if let Some(expires_in) = dump.expires_in {
+        reply.push_str(&format!("\nexpires in {:?}", expires_in));
+    }
+    reply.push_str(&format!("\nbytes from guest {}", dump.bytes_from_guest));
+    reply.push_str(&format!("\nbytes to guest {}", dump.bytes_to_guest));
+    if let Some(guest_rtt) = dump.guest_rtt {
+        reply.push_str(&format!("\nguest rtt {:?}", guest_rtt));
+    }
+    reply.push_str(&format!("\npriority {}", dump.priority));
+    reply
+}
+
+fn parse_destination(destination: &str) -> Option<impl Fn(&tuncore::SessionInfo) -> bool + Send + 'static> {
+    let (ip, port) = if let Ok(address) = destination.parse::<SocketAddr>() {
+        (address.ip(), Some(address.port()))
+    } else {
+        (destination.parse::<IpAddr>().ok()?, None)
+    };
+    Some(move |info: &tuncore::SessionInfo| info.destination.ip() == ip && port.is_none_or(|port| info.destination.port() == port))
+}
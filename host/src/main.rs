@@ -1,8 +1,15 @@
-use std::ffi::CString;
+#[cfg(target_os = "linux")]
+mod control;
+
 #[cfg(target_os = "linux")]
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::{
+    ffi::CString,
+    net::{Ipv4Addr, Ipv6Addr},
+};
 
 static OUT_INTERFACE: std::sync::OnceLock<CString> = std::sync::OnceLock::new();
+static SOURCE_ADDRESSES: std::sync::OnceLock<(Option<Ipv4Addr>, Option<Ipv6Addr>)> = std::sync::OnceLock::new();
 
 /// Tunnel traffic through sockets.
 #[derive(::clap::Parser, Debug)]
@@ -16,11 +23,289 @@ struct Args {
     #[arg(short, long)]
     out: String,
 
+    /// Source address of the IPv4 upstream sockets.
+    #[arg(long, value_name = "ip")]
+    src4: Option<Ipv4Addr>,
+
+    /// Source address of the IPv6 upstream sockets.
+    #[arg(long, value_name = "ip")]
+    src6: Option<Ipv6Addr>,
+
+    /// Path of the unix socket serving control commands.
+    #[arg(short, long, value_name = "path")]
+    control: Option<std::path::PathBuf>,
+
+    /// Address of the internal smoltcp interface.
+    #[arg(long, value_name = "ip")]
+    address: Option<Ipv4Addr>,
+
+    /// Prefix length of the internal smoltcp interface address.
+    #[arg(long, value_name = "len")]
+    prefix_len: Option<u8>,
+
+    /// Default gateway of the internal smoltcp interface.
+    #[arg(long, value_name = "ip")]
+    gateway: Option<Ipv4Addr>,
+
+    /// Drop QUIC traffic so that browsers fall back to TCP.
+    #[arg(long)]
+    block_quic: bool,
+
+    /// UDP port treated as QUIC when blocking it.
+    #[arg(long, value_name = "port", default_value_t = 443)]
+    quic_port: u16,
+
+    /// Answer blocked QUIC datagrams with ICMP port unreachable.
+    #[arg(long)]
+    quic_port_unreachable: bool,
+
+    /// Comma separated networks sessions are never created for, replacing the default list of
+    /// loopback, multicast, link-local and unspecified networks.
+    #[arg(long, value_name = "cidr", num_args = 0.., value_delimiter = ',')]
+    blocked_destinations: Option<Vec<tuncore::IpNetwork>>,
+
+    /// Comma separated networks the interface does not accept packets to at all, e.g.
+    /// 10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7 to leave the local network out.
+    #[arg(long, value_name = "cidr", value_delimiter = ',')]
+    any_ip_excluded: Vec<tuncore::IpNetwork>,
+
+    /// Comma separated networks the tunneled traffic is expected from, packets from other
+    /// sources are dropped.
+    #[arg(long, value_name = "cidr", value_delimiter = ',')]
+    allowed_sources: Vec<tuncore::IpNetwork>,
+
+    /// Largest UDP datagram forwarded from servers, bigger ones are dropped.
+    #[arg(long, value_name = "bytes")]
+    udp_max_datagram_size: Option<usize>,
+
+    /// Directory receiving a copy of the client to server bytes, one file per session.
+    #[arg(long, value_name = "path", conflicts_with = "tee_socket")]
+    tee_dir: Option<std::path::PathBuf>,
+
+    /// Unix socket receiving a copy of the client to server bytes, one connection per session.
+    #[arg(long, value_name = "path")]
+    tee_socket: Option<std::path::PathBuf>,
+
+    /// Interval in milliseconds between scans for expired sessions.
+    #[arg(long, value_name = "ms")]
+    reaper_interval: Option<u64>,
+
+    /// DNS-over-HTTPS URL resolving the DNS queries of the tunnel.
+    #[arg(long, value_name = "url", conflicts_with = "dns_upstream")]
+    doh_url: Option<String>,
+
+    /// Address of the DNS-over-HTTPS server, skips looking up the URL host.
+    #[arg(long, value_name = "ip", requires = "doh_url")]
+    doh_address: Option<std::net::IpAddr>,
+
+    /// File a line per ended session is appended to.
+    #[arg(long, value_name = "path")]
+    connection_log: Option<std::path::PathBuf>,
+
+    /// Size in bytes the connection log is rotated at.
+    #[arg(long, value_name = "bytes", default_value_t = 10 * 1024 * 1024, requires = "connection_log")]
+    connection_log_size: u64,
+
+    /// Rotated connection logs kept.
+    #[arg(long, value_name = "n", default_value_t = 5, requires = "connection_log")]
+    connection_log_files: usize,
+
+    /// Compress the pcap files of the captures started with the control socket.
+    #[arg(long, value_name = "format", value_enum)]
+    capture_compression: Option<ArgCaptureCompression>,
+
+    /// Log one packet out of this many.
+    #[arg(long, value_name = "n", default_value_t = 1)]
+    packet_log_sample_rate: u32,
+
+    /// Comma separated networks, only packets of sessions to them are logged.
+    #[arg(long, value_name = "cidr", value_delimiter = ',')]
+    packet_log_destinations: Vec<tuncore::IpNetwork>,
+
+    /// Only packets of sessions to this destination port are logged.
+    #[arg(long, value_name = "port")]
+    packet_log_port: Option<u16>,
+
+    /// Most bytes of the payload of a logged packet dumped in hex, 0 logs the headers only.
+    #[arg(long, value_name = "bytes", default_value_t = 0)]
+    packet_log_bytes: usize,
+
+    /// Idle timeout in seconds of UDP sessions.
+    #[arg(long, value_name = "secs")]
+    udp_timeout: Option<u64>,
+
+    /// Comma separated idle timeouts of UDP sessions by destination port, e.g. `53=2,443=30`.
+    #[arg(long, value_name = "port=secs", value_delimiter = ',', value_parser = parse_port_timeout)]
+    udp_port_timeouts: Option<Vec<(u16, std::time::Duration)>>,
+
+    /// Milliseconds a UDP session the server has not answered yet stays without traffic.
+    #[arg(long, value_name = "ms")]
+    udp_unanswered_timeout: Option<u64>,
+
+    /// Milliseconds a UDP datagram waits for the upstream socket before it is dropped.
+    #[arg(long, value_name = "ms")]
+    udp_send_deadline: Option<u64>,
+
+    /// Milliseconds a closed TCP session lingers to finish the close handshake before it is destroyed.
+    #[arg(long, value_name = "ms")]
+    tcp_close_grace: Option<u64>,
+
+    /// Milliseconds the addresses of a destroyed TCP session reject all but a new SYN, 0 turns it off.
+    #[arg(long, value_name = "ms")]
+    tcp_quarantine: Option<u64>,
+
+    /// smoltcp sockets of destroyed sessions kept per protocol for reuse, 0 turns the pool off.
+    #[arg(long, value_name = "n")]
+    socket_pool_size: Option<usize>,
+
+    /// TTL or hop limit of the packets written to the tun interface.
+    #[arg(long, value_name = "n")]
+    hop_limit: Option<u8>,
+
+    /// MTU of the tun interface, the TCP segments to the guest are sized to fit.
+    #[arg(long, value_name = "bytes")]
+    mtu: Option<usize>,
+
+    /// Refuse sessions whose upstream socket fails to bind to the output interface or source
+    /// address, instead of letting it use the default route.
+    #[arg(long)]
+    strict_bind: bool,
+
+    /// Kernel receive buffer size of the upstream sockets.
+    #[arg(long, value_name = "bytes")]
+    upstream_recv_buffer_size: Option<usize>,
+
+    /// Kernel send buffer size of the upstream sockets.
+    #[arg(long, value_name = "bytes")]
+    upstream_send_buffer_size: Option<usize>,
+
+    /// DSCP (0 to 63) marking the packets of the upstream TCP sockets.
+    #[arg(long, value_name = "dscp")]
+    tcp_dscp: Option<u8>,
+
+    /// DSCP (0 to 63) marking the packets of the upstream UDP sockets.
+    #[arg(long, value_name = "dscp")]
+    udp_dscp: Option<u8>,
+
+    /// Comma separated priorities of the sessions by destination port, higher first, e.g. `22=7,5060=6`.
+    #[arg(long, value_name = "port=priority", value_delimiter = ',', value_parser = parse_port_priority)]
+    port_priorities: Option<Vec<(u16, u8)>>,
+
+    /// Take the DSCP of the packet opening a session as its priority when its port has none.
+    #[arg(long)]
+    dscp_priority: bool,
+
+    /// Connect TCP sessions upstream from the source port of the guest when it is free.
+    #[arg(long, conflicts_with_all = ["src4", "src6"])]
+    preserve_source_port: bool,
+
+    /// Bind the upstream TCP and UDP sockets to a local port of this range, e.g. `20000-60999`.
+    #[arg(long, value_name = "first-last", value_parser = parse_port_range, conflicts_with_all = ["src4", "src6"])]
+    upstream_port_range: Option<std::ops::RangeInclusive<u16>>,
+
+    /// Seconds an upstream TCP connection is idle before keepalive probes are sent, off when unset.
+    #[arg(long, value_name = "s")]
+    tcp_keepalive: Option<u64>,
+
+    /// Seconds between the keepalive probes of the upstream TCP connections.
+    #[arg(long, value_name = "s", default_value_t = 15, requires = "tcp_keepalive")]
+    tcp_keepalive_interval: u64,
+
+    /// Unanswered keepalive probes after which an upstream TCP connection is dropped.
+    #[arg(long, value_name = "n", default_value_t = 4, requires = "tcp_keepalive")]
+    tcp_keepalive_count: u32,
+
+    /// Echo back whatever is sent through the tunnel instead of connecting to the destinations.
+    #[arg(long)]
+    loopback: bool,
+
+    /// Where DNS sessions, to port 53, are forwarded to instead of where the rest goes.
+    #[arg(long, value_name = "upstream", value_enum)]
+    dns_upstream: Option<ArgUpstream>,
+
+    /// Send a PROXY protocol header with the addresses of the guest ahead of the data of every TCP session.
+    #[arg(long, value_name = "version", value_enum)]
+    proxy_protocol: Option<ArgProxyProtocol>,
+
+    /// IP versions sessions are created for, packets of the other one are dropped.
+    #[arg(long, value_name = "versions", value_enum, default_value = "dual")]
+    ip_versions: ArgIpVersions,
+
+    /// Relay protocols other than TCP and UDP, e.g. GRE, through raw sockets instead of dropping them.
+    #[arg(long)]
+    forward_raw_protocols: bool,
+
+    /// Relay UDP to multicast groups and to 255.255.255.255 onto the local network, e.g. for mDNS or SSDP.
+    #[arg(long)]
+    forward_multicast: bool,
+
+    /// Most TCP connects in progress at once, further SYNs are dropped until some complete.
+    #[arg(long, value_name = "n")]
+    max_half_open_connections: Option<usize>,
+
+    /// Most sessions to the same destination address at once, further ones are dropped until some end.
+    #[arg(long, value_name = "n")]
+    max_sessions_per_destination: Option<usize>,
+
+    /// Most bytes buffered by all sessions together, the busiest stop reading until below.
+    #[arg(long, value_name = "bytes")]
+    max_buffered_bytes: Option<usize>,
+
+    /// Most packets read from the tun before the events of the existing sessions are served.
+    #[arg(long, value_name = "n")]
+    tun_read_budget: Option<usize>,
+
+    /// Most sessions created before the events of the existing sessions are served.
+    #[arg(long, value_name = "n")]
+    new_session_budget: Option<usize>,
+
+    /// Report IPv6 link-local and multicast housekeeping packets as failed sessions instead of
+    /// dropping them silently.
+    #[arg(long)]
+    keep_ipv6_housekeeping: bool,
+
+    /// Relay the unreachable errors of upstream UDP sockets to the guest as ICMP messages.
+    #[arg(long)]
+    forward_icmp_errors: bool,
+
+    /// Retry refused upstream writes on every loop iteration, for latency over CPU usage.
+    #[arg(long)]
+    immediate_forward: bool,
+
+    /// Drop the packets of the tun interface instead of exiting when the packet engine fails.
+    #[arg(long)]
+    kill_switch: bool,
+
+    /// Print the errors the packet engine recovers from, like failed upstream connects, to stderr.
+    #[arg(long)]
+    print_session_errors: bool,
+
     /// Verbosity level
     #[arg(short, long, value_name = "level", value_enum, default_value = "info")]
     verbosity: ArgVerbosity,
 }
 
+fn parse_port_timeout(value: &str) -> Result<(u16, std::time::Duration), String> {
+    let (port, secs) = value.split_once('=').ok_or_else(|| format!("expected port=secs, got {}", value))?;
+    let port = port.parse::<u16>().map_err(|e| format!("invalid port {}, error={}", port, e))?;
+    let secs = secs.parse::<u64>().map_err(|e| format!("invalid timeout {}, error={}", secs, e))?;
+    Ok((port, std::time::Duration::from_secs(secs)))
+}
+
+fn parse_port_priority(value: &str) -> Result<(u16, u8), String> {
+    let (port, priority) = value.split_once('=').ok_or_else(|| format!("expected port=priority, got {}", value))?;
+    let port = port.parse::<u16>().map_err(|e| format!("invalid port {}, error={}", port, e))?;
+    let priority = priority.parse::<u8>().map_err(|e| format!("invalid priority {}, error={}", priority, e))?;
+    Ok((port, priority))
+}
+
+fn parse_port_range(value: &str) -> Result<std::ops::RangeInclusive<u16>, String> {
+    let (first, last) = value.split_once('-').ok_or_else(|| format!("expected first-last, got {}", value))?;
+    let first = first.parse::<u16>().map_err(|e| format!("invalid port {}, error={}", first, e))?;
+    let last = last.parse::<u16>().map_err(|e| format!("invalid port {}, error={}", last, e))?;
+    Ok(first..=last)
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 enum ArgVerbosity {
     Off,
@@ -31,6 +316,31 @@ enum ArgVerbosity {
     Trace,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum ArgIpVersions {
+    Dual,
+    V4Only,
+    V6Only,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum ArgCaptureCompression {
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum ArgUpstream {
+    Direct,
+    Loopback,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum ArgProxyProtocol {
+    V1,
+    V2,
+}
+
 #[cfg(target_os = "linux")]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     use env_logger::Env;
@@ -43,15 +353,150 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(environment).init();
 
     OUT_INTERFACE.set(CString::new(args.out)?).map_err(|e| e.to_string_lossy().to_string())?;
+    SOURCE_ADDRESSES.set((args.src4, args.src6)).map_err(|_| "source addresses are already set")?;
+
+    let mut config = tuncore::TunConfig::default();
+    if let Some(address) = args.address {
+        config.interface_address = address;
+    }
+    if let Some(prefix_len) = args.prefix_len {
+        config.interface_prefix_len = prefix_len;
+    }
+    if let Some(gateway) = args.gateway {
+        config.gateway = gateway;
+    }
+    config.block_quic = args.block_quic;
+    config.quic_port = args.quic_port;
+    config.quic_port_unreachable = args.quic_port_unreachable;
+    if let Some(blocked_destinations) = args.blocked_destinations {
+        config.blocked_destinations = blocked_destinations;
+    }
+    config.any_ip_excluded = args.any_ip_excluded;
+    config.allowed_sources = args.allowed_sources;
+    if let Some(udp_max_datagram_size) = args.udp_max_datagram_size {
+        config.udp_max_datagram_size = udp_max_datagram_size;
+    }
+    config.tee = match (args.tee_dir, args.tee_socket) {
+        (Some(path), _) => Some(tuncore::TeeTarget::Directory(path)),
+        (_, Some(path)) => Some(tuncore::TeeTarget::UnixSocket(path)),
+        _ => None,
+    };
+    if let Some(reaper_interval) = args.reaper_interval {
+        config.reaper_interval = std::time::Duration::from_millis(reaper_interval);
+    }
+    config.doh = args.doh_url.map(|url| tuncore::DohConfig {
+        url,
+        address: args.doh_address,
+    });
+    config.connection_log = args.connection_log.map(|path| tuncore::ConnectionLogConfig {
+        path,
+        max_bytes: args.connection_log_size,
+        max_files: args.connection_log_files,
+    });
+    config.capture_compression = args.capture_compression.map(|compression| match compression {
+        ArgCaptureCompression::Gzip => tuncore::CaptureCompression::Gzip,
+        ArgCaptureCompression::Zstd => tuncore::CaptureCompression::Zstd,
+    });
+    config.packet_log = tuncore::PacketLogConfig {
+        sample_rate: args.packet_log_sample_rate,
+        destinations: args.packet_log_destinations,
+        port: args.packet_log_port,
+        max_bytes: args.packet_log_bytes,
+    };
+    if let Some(udp_timeout) = args.udp_timeout {
+        config.udp_timeout = std::time::Duration::from_secs(udp_timeout);
+    }
+    if let Some(udp_port_timeouts) = args.udp_port_timeouts {
+        config.udp_port_timeouts = udp_port_timeouts.into_iter().collect();
+    }
+    config.udp_unanswered_timeout = args.udp_unanswered_timeout.map(std::time::Duration::from_millis);
+    if let Some(udp_send_deadline) = args.udp_send_deadline {
+        config.udp_send_deadline = std::time::Duration::from_millis(udp_send_deadline);
+    }
+    if let Some(tcp_close_grace) = args.tcp_close_grace {
+        config.tcp_close_grace = std::time::Duration::from_millis(tcp_close_grace);
+    }
+    if let Some(tcp_quarantine) = args.tcp_quarantine {
+        config.tcp_quarantine = std::time::Duration::from_millis(tcp_quarantine);
+    }
+    if let Some(socket_pool_size) = args.socket_pool_size {
+        config.socket_pool_size = socket_pool_size;
+    }
+    if let Some(hop_limit) = args.hop_limit {
+        config.hop_limit = hop_limit;
+    }
+    if let Some(mtu) = args.mtu {
+        config.mtu = mtu;
+    }
+    config.strict_socket_setup = args.strict_bind;
+    config.upstream_recv_buffer_size = args.upstream_recv_buffer_size;
+    config.upstream_send_buffer_size = args.upstream_send_buffer_size;
+    config.tcp_dscp = args.tcp_dscp;
+    config.udp_dscp = args.udp_dscp;
+    if let Some(port_priorities) = args.port_priorities {
+        config.port_priorities = port_priorities.into_iter().collect();
+    }
+    config.dscp_priority = args.dscp_priority;
+    config.preserve_tcp_source_port = args.preserve_source_port;
+    config.upstream_port_range = args.upstream_port_range;
+    config.upstream_tcp_keepalive = args.tcp_keepalive.map(|idle| tuncore::TcpKeepaliveConfig {
+        idle: std::time::Duration::from_secs(idle),
+        interval: std::time::Duration::from_secs(args.tcp_keepalive_interval),
+        retries: args.tcp_keepalive_count,
+    });
+    config.max_half_open_connections = args.max_half_open_connections;
+    config.max_sessions_per_destination = args.max_sessions_per_destination;
+    config.max_buffered_bytes = args.max_buffered_bytes;
+    config.tun_read_budget = args.tun_read_budget;
+    config.new_session_budget = args.new_session_budget;
+    config.drop_ipv6_housekeeping = !args.keep_ipv6_housekeeping;
+    config.forward_icmp_errors = args.forward_icmp_errors;
+    config.forward_multicast = args.forward_multicast;
+    config.immediate_forward = args.immediate_forward;
+    config.kill_switch |= args.kill_switch;
+    // every upstream socket is bound to the out interface, so link-local destinations are on it.
+    match unsafe { libc::if_nametoindex(OUT_INTERFACE.get().unwrap().as_ptr()) } {
+        0 => eprintln!("failed to get index of interface {:?}", OUT_INTERFACE.get().unwrap()),
+        index => config.link_local_scope_id = Some(index),
+    }
+    if args.loopback {
+        config.upstream = tuncore::Upstream::Loopback;
+    }
+    config.dns_upstream = args.dns_upstream.map(|upstream| match upstream {
+        ArgUpstream::Direct => tuncore::Upstream::Direct,
+        ArgUpstream::Loopback => tuncore::Upstream::Loopback,
+    });
+    config.proxy_protocol = args.proxy_protocol.map(|version| match version {
+        ArgProxyProtocol::V1 => tuncore::ProxyProtocol::V1,
+        ArgProxyProtocol::V2 => tuncore::ProxyProtocol::V2,
+    });
+    config.ip_version_policy = match args.ip_versions {
+        ArgIpVersions::Dual => tuncore::IpVersionPolicy::Dual,
+        ArgIpVersions::V4Only => tuncore::IpVersionPolicy::V4Only,
+        ArgIpVersions::V6Only => tuncore::IpVersionPolicy::V6Only,
+    };
+    if args.forward_raw_protocols {
+        config.unsupported_protocols = tuncore::UnsupportedProtocols::ForwardRaw;
+    }
+    config.validate()?;
+    let rules = config.rules();
 
     tuncore::tun_callbacks::set_socket_created_callback(Some(on_socket_created));
+    tuncore::tun_callbacks::set_tun_failed_callback(Some(on_tun_failed));
+    if args.print_session_errors {
+        tuncore::tun_callbacks::set_session_error_callback(Some(on_session_error));
+    }
 
     let tun = TunTapInterface::new(&args.tun, Medium::Ip)?;
 
     set_panic_handler();
 
     tuncore::tun::create();
-    tuncore::tun::start(tun.as_raw_fd());
+    tuncore::tun::start_with_config(tun.as_raw_fd(), config)?;
+
+    if let Some(path) = &args.control {
+        control::start(path, rules)?;
+    }
 
     {
         let (tx, rx) = std::sync::mpsc::channel();
@@ -64,21 +509,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         handle.join().expect("Couldn't join on the associated thread");
     }
 
-    tuncore::tun::stop();
+    if let Some(path) = &args.control {
+        let _ = std::fs::remove_file(path);
+    }
+
+    tuncore::tun::stop()?;
+    println!("the packet engine stopped, reason={:?}", tuncore::tun::shutdown_reason());
     tuncore::tun::destroy();
     tuncore::tun_callbacks::set_socket_created_callback(None);
+    tuncore::tun_callbacks::set_tun_failed_callback(None);
+    tuncore::tun_callbacks::set_session_error_callback(None);
 
     remove_panic_handler();
     Ok(())
 }
 
+// without the kill switch the processor stopped, there is nothing left to wait for.
+fn on_tun_failed(reason: tuncore::ShutdownReason, state: tuncore::TunState) {
+    if state == tuncore::TunState::Blocking {
+        eprintln!("the packet engine failed, reason={:?}, dropping packets until exit", reason);
+        return;
+    }
+    eprintln!("the packet engine failed, reason={:?}, exiting", reason);
+    std::process::exit(1);
+}
+
+fn on_session_error(error: tuncore::SessionError) {
+    match error.session_info {
+        Some(session_info) => eprintln!("session error, {:?} {:?}", error.cause, session_info),
+        None => eprintln!("session error, {:?}", error.cause),
+    }
+}
+
 #[cfg(target_os = "linux")]
-fn on_socket_created(socket: RawFd) {
-    bind_socket_to_interface(socket, OUT_INTERFACE.get().unwrap());
+fn on_socket_created(socket: RawFd) -> bool {
+    let bound = bind_socket_to_interface(socket, OUT_INTERFACE.get().unwrap());
+    match SOURCE_ADDRESSES.get() {
+        Some((source4, source6)) => bind_socket_to_source(socket, *source4, *source6) && bound,
+        None => bound,
+    }
 }
 
 #[cfg(target_os = "linux")]
-fn bind_socket_to_interface(socket: RawFd, interface: &CString) {
+fn bind_socket_to_interface(socket: RawFd, interface: &CString) -> bool {
     let result = unsafe {
         libc::setsockopt(
             socket,
@@ -92,7 +565,62 @@ fn bind_socket_to_interface(socket: RawFd, interface: &CString) {
         let error_code = unsafe { *libc::__errno_location() };
         let error: std::io::Result<libc::c_int> = Err(std::io::Error::from_raw_os_error(error_code));
         eprint!("failed to bind socket to interface, error={:?}", error);
+        return false;
+    }
+    true
+}
+
+// binds the socket to the source address configured for its family, if any.
+#[cfg(target_os = "linux")]
+fn bind_socket_to_source(socket: RawFd, source4: Option<Ipv4Addr>, source6: Option<Ipv6Addr>) -> bool {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(socket, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    if result == -1 {
+        eprintln!("failed to get socket family, error={:?}", std::io::Error::last_os_error());
+        return false;
+    }
+    let result = match (storage.ss_family as libc::c_int, source4, source6) {
+        (libc::AF_INET, Some(source), _) => {
+            let address = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: 0,
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(source).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                libc::bind(
+                    socket,
+                    &address as *const _ as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        (libc::AF_INET6, _, Some(source)) => {
+            let address = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: 0,
+                sin6_flowinfo: 0,
+                sin6_addr: libc::in6_addr { s6_addr: source.octets() },
+                sin6_scope_id: 0,
+            };
+            unsafe {
+                libc::bind(
+                    socket,
+                    &address as *const _ as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+        _ => return true,
+    };
+    if result == -1 {
+        eprintln!("failed to bind socket to source address, error={:?}", std::io::Error::last_os_error());
+        return false;
     }
+    true
 }
 
 #[cfg(target_os = "linux")]
@@ -111,4 +639,5 @@ fn remove_panic_handler() {
 fn main() {
     eprintln!("This program is only supported on Linux");
     OUT_INTERFACE.set(CString::new("dummy".to_string()).unwrap()).unwrap();
+    SOURCE_ADDRESSES.set((None, None)).unwrap();
 }
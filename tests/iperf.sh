@@ -50,3 +50,6 @@ iperf3 -c 10.0.0.4
 
 iperf3 -c 10.0.0.4 -R -P 10
 
+
+# Both directions at once, the upstream sockets turn readable and writable in the same events
+iperf3 -c 10.0.0.4 --bidir -P 4
@@ -0,0 +1,33 @@
+use std::{path::Path, process::Command};
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let root = Path::new(&manifest_dir).join("..");
+
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .current_dir(&manifest_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TUNCORE_GIT_HASH={}", git_hash);
+
+    // the version smoltcp was resolved to, as recorded by the lock file of the workspace.
+    let lock_file = root.join("Cargo.lock");
+    let smoltcp_version = std::fs::read_to_string(&lock_file)
+        .ok()
+        .and_then(|lock| {
+            let mut lines = lock.lines().skip_while(|line| *line != "name = \"smoltcp\"");
+            lines.nth(1)?.strip_prefix("version = \"")?.strip_suffix('"').map(str::to_string)
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TUNCORE_SMOLTCP_VERSION={}", smoltcp_version);
+
+    println!("cargo:rustc-env=TUNCORE_TARGET={}", std::env::var("TARGET").unwrap());
+
+    println!("cargo:rerun-if-changed={}", lock_file.display());
+    println!("cargo:rerun-if-changed={}", root.join(".git/HEAD").display());
+    println!("cargo:rerun-if-changed={}", root.join(".git/refs/heads").display());
+}
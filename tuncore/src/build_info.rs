@@ -0,0 +1,29 @@
+use std::fmt;
+
+/// Versions the running library was built from, see `tun::version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Short hash of the commit, `unknown` when built outside of a git checkout.
+    pub git_hash: &'static str,
+    pub smoltcp_version: &'static str,
+    pub target: &'static str,
+}
+
+pub(crate) const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_hash: env!("TUNCORE_GIT_HASH"),
+    smoltcp_version: env!("TUNCORE_SMOLTCP_VERSION"),
+    target: env!("TUNCORE_TARGET"),
+};
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "tuncore {} ({}) smoltcp {} {}",
+            self.version, self.git_hash, self.smoltcp_version, self.target
+        )
+    }
+}
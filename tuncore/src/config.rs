@@ -0,0 +1,889 @@
+use crate::{IpNetwork, SessionInfo, UpstreamConnector};
+use smoltcp::wire::IpVersion;
+#[cfg(feature = "doh")]
+use std::net::IpAddr;
+use std::{collections::BTreeMap, net::Ipv4Addr, path::PathBuf, time::Duration};
+
+// the DSCP is the upper six bits of the IPv4 type of service and the IPv6 traffic class.
+const MAX_DSCP: u8 = 63;
+
+// the smallest MTU IPv6 links have to support.
+const MIN_MTU: usize = 1280;
+
+/// Destinations sessions are never created for: loopback, multicast, link-local and unspecified
+/// addresses. The interface accepts any destination, so without this a crafted packet could make
+/// the tunnel connect to services of the device itself or of the local network.
+pub const DEFAULT_BLOCKED_DESTINATIONS: [IpNetwork; 8] = [
+    IpNetwork::v4(127, 0, 0, 0, 8),
+    IpNetwork::v4(224, 0, 0, 0, 4),
+    IpNetwork::v4(169, 254, 0, 0, 16),
+    IpNetwork::v4(0, 0, 0, 0, 32),
+    IpNetwork::v6([0, 0, 0, 0, 0, 0, 0, 1], 128),
+    IpNetwork::v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+    IpNetwork::v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+    IpNetwork::v6([0, 0, 0, 0, 0, 0, 0, 0], 128),
+];
+
+/// The private networks of RFC 1918 and the IPv6 unique local addresses, for `TunConfig::any_ip_excluded`
+/// to keep the local network out of a tunnel meant for the internet.
+pub const PRIVATE_NETWORKS: [IpNetwork; 4] = [
+    IpNetwork::v4(10, 0, 0, 0, 8),
+    IpNetwork::v4(172, 16, 0, 0, 12),
+    IpNetwork::v4(192, 168, 0, 0, 16),
+    IpNetwork::v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+];
+
+/// Where `TunConfig::tee` copies the client to server bytes of each session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TeeTarget {
+    /// A new file per session is created in this directory.
+    Directory(PathBuf),
+    /// A new connection per session is made to this unix socket, starting with a line that
+    /// describes the session.
+    UnixSocket(PathBuf),
+}
+
+/// DNS-over-HTTPS server answering the DNS queries of the guest, see `TunConfig::doh`.
+#[cfg(feature = "doh")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DohConfig {
+    /// URL the queries are posted to, e.g. `https://1.1.1.1/dns-query`. Plain `http://` is
+    /// accepted for resolvers on a trusted network.
+    pub url: String,
+    /// Address of the server. When unset the host of `url` is looked up with the system
+    /// resolver, which must not itself be routed through the tunnel.
+    pub address: Option<IpAddr>,
+}
+
+/// Sampling of the per packet logs, see `TunConfig::packet_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketLogConfig {
+    /// Logs one packet out of `sample_rate`, 1 logs every packet.
+    pub sample_rate: u32,
+    /// Only packets of sessions to these networks are logged, empty logs every session.
+    pub destinations: Vec<IpNetwork>,
+    /// Only packets of sessions to this destination port are logged.
+    pub port: Option<u16>,
+    /// Most bytes of the TCP or UDP payload dumped in hex after the headers, the count of the bytes
+    /// left out follows. 0 logs the headers only.
+    pub max_bytes: usize,
+}
+
+impl Default for PacketLogConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 1,
+            destinations: Vec::new(),
+            port: None,
+            max_bytes: 0,
+        }
+    }
+}
+
+/// File the ended sessions are logged to, see `TunConfig::connection_log`.
+#[cfg(feature = "connection-log")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionLogConfig {
+    pub path: PathBuf,
+    /// Size the file is rotated at, it is renamed to `<path>.1` and the older files are shifted
+    /// up by one.
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one, the oldest is removed. With 0 the file is
+    /// started over when full.
+    pub max_files: usize,
+}
+
+/// TCP keepalive probes of the upstream sockets, see `TunConfig::upstream_tcp_keepalive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepaliveConfig {
+    /// Idle time of a connection before the first probe is sent.
+    pub idle: Duration,
+    /// Time between the probes while the server does not answer.
+    pub interval: Duration,
+    /// Unanswered probes after which the kernel drops the connection, the session then ends like
+    /// on any other connection error.
+    pub retries: u32,
+}
+
+impl Default for TcpKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(15),
+            retries: 4,
+        }
+    }
+}
+
+/// Compression of the pcap files of the captures, see `TunConfig::capture_compression`.
+#[cfg(feature = "capture-compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureCompression {
+    /// `.pcap.gz` files, which Wireshark opens as they are.
+    Gzip,
+    /// `.pcap.zst` files, smaller for the same effort. Recent Wireshark versions open them, older
+    /// ones after a `zstd -d`.
+    Zstd,
+}
+
+/// Where sessions are forwarded to, see `TunConfig::upstream`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Upstream {
+    /// Connects a socket to the destination of each session.
+    #[default]
+    Direct,
+    /// Echoes back whatever the guest sends, in memory and without any network access. Meant for
+    /// deterministic tests of the whole packet path.
+    Loopback,
+    /// Hands the TCP and UDP sessions to the transports the connector creates, e.g. to carry them
+    /// over a tunnel of the app. Raw forwarded protocols still use raw sockets.
+    Custom(UpstreamConnector),
+}
+
+/// Version of the PROXY protocol header sent to the servers, see `TunConfig::proxy_protocol`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// The human readable text header, e.g. `PROXY TCP4 192.0.2.2 10.0.0.4 40000 443`.
+    V1,
+    /// The binary header.
+    V2,
+}
+
+/// What happens to the packets of protocols other than TCP and UDP, see
+/// `TunConfig::unsupported_protocols`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsupportedProtocols {
+    /// Drops them.
+    #[default]
+    Drop,
+    /// Relays their IP payload through a raw socket per source, destination and protocol, e.g. for
+    /// the GRE or ESP of a nested tunnel. Raw sockets need `CAP_NET_RAW`, which Android apps lack.
+    ForwardRaw,
+}
+
+/// Which IP versions sessions are created for, see `TunConfig::ip_version_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpVersionPolicy {
+    #[default]
+    Dual,
+    V4Only,
+    V6Only,
+}
+
+impl IpVersionPolicy {
+    pub fn allows(&self, ip_version: IpVersion) -> bool {
+        match self {
+            IpVersionPolicy::Dual => true,
+            IpVersionPolicy::V4Only => ip_version == IpVersion::Ipv4,
+            IpVersionPolicy::V6Only => ip_version == IpVersion::Ipv6,
+        }
+    }
+}
+
+/// The part of a `TunConfig` deciding which sessions are refused, swapped at runtime by
+/// `tun::reload_rules`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rules {
+    /// See `TunConfig::blocked_destinations`.
+    pub blocked_destinations: Vec<IpNetwork>,
+    /// See `TunConfig::allowed_sources`.
+    pub allowed_sources: Vec<IpNetwork>,
+    /// See `TunConfig::block_quic`.
+    pub block_quic: bool,
+    /// See `TunConfig::quic_port`.
+    pub quic_port: u16,
+    /// See `TunConfig::quic_port_unreachable`.
+    pub quic_port_unreachable: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        TunConfig::default().rules()
+    }
+}
+
+/// Configuration of the packet engine, passed to `tun::start_with_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunConfig {
+    /// Address assigned to the smoltcp interface of each session.
+    ///
+    /// The interface accepts packets for any destination (any-ip), so this address is never the
+    /// target of real traffic. It only needs to be a valid host address that does not collide
+    /// with a destination used by the guest.
+    pub interface_address: Ipv4Addr,
+    /// Prefix length of `interface_address`. The default `/0` covers every IPv4 destination.
+    pub interface_prefix_len: u8,
+    /// Default IPv4 gateway of the smoltcp interface, must be inside the interface network.
+    pub gateway: Ipv4Addr,
+    /// Drops UDP traffic to `quic_port` so browsers fall back from HTTP/3 (QUIC) to TCP.
+    pub block_quic: bool,
+    /// UDP destination port treated as QUIC by `block_quic`.
+    pub quic_port: u16,
+    /// Answers blocked QUIC datagrams with ICMP port unreachable, making the fallback immediate
+    /// instead of waiting for the QUIC handshake to time out.
+    pub quic_port_unreachable: bool,
+    /// Sessions to destinations inside these networks are refused with `Error::Blocked`.
+    pub blocked_destinations: Vec<IpNetwork>,
+    /// Destinations the any-ip interface does not accept at all, their packets are dropped without
+    /// a session, a refusal or a raw forward, e.g. `PRIVATE_NETWORKS` to tunnel the internet but
+    /// not the local network. Unlike `blocked_destinations` it is not part of the reloadable rules.
+    /// Empty accepts any destination.
+    pub any_ip_excluded: Vec<IpNetwork>,
+    /// Restricts the sessions to one IP version, e.g. IPv4 only on a network whose IPv6 is broken.
+    /// The packets of the other version are dropped like those of `any_ip_excluded`, so the apps
+    /// of the guest give up on it and fall back to the addresses of the allowed version.
+    pub ip_version_policy: IpVersionPolicy,
+    /// Largest UDP datagram forwarded from the server, bigger ones are dropped rather than
+    /// delivered truncated.
+    pub udp_max_datagram_size: usize,
+    /// Copies the reassembled client to server stream of every session for protocol debugging.
+    /// The copy is best effort and never changes what is forwarded to the server.
+    pub tee: Option<TeeTarget>,
+    /// How often sessions are scanned for expiry. Reaping runs on this cadence no matter how
+    /// often the poll loop is woken up by traffic.
+    pub reaper_interval: Duration,
+    /// Resolves the DNS queries of the guest, over UDP and TCP, through a DNS-over-HTTPS server
+    /// instead of forwarding them, so no plaintext DNS leaves the device. The DNS over TCP sessions
+    /// take the place of `dns_upstream`, which must be unset.
+    #[cfg(feature = "doh")]
+    pub doh: Option<DohConfig>,
+    /// Networks the guest sends from. Packets with a source outside of them are dropped instead of
+    /// creating sessions whose replies could not be routed back. Empty accepts any source.
+    pub allowed_sources: Vec<IpNetwork>,
+    /// Thins out the packet logs, keeping some visibility on a busy tunnel.
+    pub packet_log: PacketLogConfig,
+    /// Writes a line per ended TCP or UDP session, with its addresses, start, duration and bytes,
+    /// to a rotated file: the access log of the tunnel, apart from the debug logs. The lines are
+    /// written by a thread of their own, the processor never waits for the file. Off when unset.
+    #[cfg(feature = "connection-log")]
+    pub connection_log: Option<ConnectionLogConfig>,
+    /// Compresses the pcap files of the captures started with `tun::start_capture`, for long
+    /// captures on a device short of storage. The packets are compressed as they are recorded, on
+    /// the processor thread, with the fast levels of the encoders. A file is only complete once
+    /// the capture of its session ends; a file cut short, e.g. by a crash, still decompresses up
+    /// to the last full block. Uncompressed when unset.
+    #[cfg(feature = "capture-compression")]
+    pub capture_compression: Option<CaptureCompression>,
+    /// Idle timeout of UDP sessions to ports without an entry in `udp_port_timeouts`.
+    pub udp_timeout: Duration,
+    /// Idle timeouts of UDP sessions by destination port. By default DNS sessions expire fast
+    /// and QUIC sessions are kept longer.
+    pub udp_port_timeouts: BTreeMap<u16, Duration>,
+    /// Idle timeout of UDP sessions the server has not sent anything to yet, when shorter than their
+    /// usual one. Such a session, e.g. to a host that is down or of an app killed before the answer
+    /// came, otherwise holds its upstream socket for the whole usual timeout. The usual timeout
+    /// applies from the first datagram of the server on. Sessions whose app is gone once answered
+    /// end as soon as the guest reports their port unreachable. Off when unset.
+    pub udp_unanswered_timeout: Option<Duration>,
+    /// Longest a UDP datagram of the guest waits for the upstream socket to accept it. Datagrams the
+    /// socket refused with WouldBlock are retried once it is writable again, those still queued past
+    /// the deadline are dropped, bounding the latency and memory of a stuck flow.
+    pub udp_send_deadline: Duration,
+    /// How long a TCP session lingers once it is closed, by the guest or the server, before it is
+    /// destroyed. It is destroyed at once when the close handshake with the guest completes earlier,
+    /// and traffic of the guest after the close keeps it for the usual idle timeout instead.
+    /// Meanwhile smoltcp still sends the final data and answers the FIN and the last ACKs of the
+    /// guest; a session destroyed earlier is no longer there to answer them and the guest sees a RST,
+    /// truncating a response it had not fully read. A longer grace holds the sockets and buffers of
+    /// sessions whose guest is slow to close longer. UDP sessions whose upstream socket failed linger
+    /// as long.
+    pub tcp_close_grace: Duration,
+    /// How long the addresses of a destroyed TCP session stay quarantined. Meanwhile a late
+    /// retransmission of the SYN that opened the session is dropped and any other segment but a new
+    /// SYN is answered with a RST, instead of connecting to the server again for a connection the
+    /// guest already gave up. A new SYN, with another initial sequence number, creates a new session
+    /// at once, so apps reconnecting from the same port are not held back. Zero turns it off.
+    pub tcp_quarantine: Duration,
+    /// smoltcp sockets of destroyed sessions kept, per protocol, for the sessions created next. Each
+    /// holds its buffers of a few megabytes, reusing them saves allocating them for every short
+    /// lived session, e.g. of web browsing or DNS, at the cost of keeping that memory while idle.
+    /// Zero allocates the sockets of every session anew.
+    pub socket_pool_size: usize,
+    /// TTL (IPv4) or hop limit (IPv6) of the packets written to the guest.
+    pub hop_limit: u8,
+    /// MTU of the tun, the largest IP packet written to the guest. The TCP segments of the sessions,
+    /// and the MSS announced to the guest, are sized to fit. It also sizes the buffer packets are
+    /// read from the tun into, a larger packet is cut and then dropped as malformed, so it must not
+    /// be below the MTU of the tun. Can be changed at runtime with `tun::set_mtu`.
+    pub mtu: usize,
+    /// Refuses sessions whose upstream socket the socket created callback failed to prepare, with
+    /// a TCP reset or an ICMP port unreachable to the guest. Otherwise such a socket is used as is
+    /// and may leave through the default route instead of the intended egress interface.
+    pub strict_socket_setup: bool,
+    /// Kernel receive buffer (`SO_RCVBUF`) of the upstream sockets, the system default when unset.
+    /// Larger buffers lift the throughput cap of links with a long round trip time. The kernel
+    /// clamps the size to its limits, e.g. `net.core.rmem_max` on Linux.
+    pub upstream_recv_buffer_size: Option<usize>,
+    /// Kernel send buffer (`SO_SNDBUF`) of the upstream sockets, the system default when unset.
+    /// Clamped like `upstream_recv_buffer_size`, e.g. to `net.core.wmem_max` on Linux.
+    pub upstream_send_buffer_size: Option<usize>,
+    /// DSCP (0 to 63) marking the packets of the upstream TCP sockets, in IP_TOS or IPV6_TCLASS,
+    /// for egress networks prioritizing by it. Left to the system when unset. The socket created
+    /// callback runs afterwards, so it can still mark single flows differently.
+    pub tcp_dscp: Option<u8>,
+    /// DSCP marking the packets of the upstream UDP sockets, like `tcp_dscp`.
+    pub udp_dscp: Option<u8>,
+    /// Priority of the sessions by destination port, e.g. to service SSH or VoIP before bulk
+    /// downloads. When several sessions have data to move in the same iteration of the processor,
+    /// the ones with the higher priority go first: their events, timers and pending writes. It only
+    /// orders the work, it reserves no bandwidth. Ports without an entry take the priority of
+    /// `dscp_priority`, or 0. Empty by default, the sessions are then served in no particular order.
+    pub port_priorities: BTreeMap<u16, u8>,
+    /// Takes the DSCP (0 to 63) of the packet of the guest opening a session as its priority, see
+    /// `port_priorities`. Off by default, most guests mark no packet.
+    pub dscp_priority: bool,
+    /// Binds the upstream TCP sockets to the source port of the guest, for servers telling clients
+    /// apart by it. When the port cannot be bound, e.g. because it is taken or privileged, the
+    /// socket falls back to an ephemeral port. The port is bound before the socket created callback
+    /// runs, so the callback must not bind the socket itself, e.g. to a source address.
+    pub preserve_tcp_source_port: bool,
+    /// Binds the upstream TCP and UDP sockets to a local port of this range, e.g. one the firewall
+    /// of the egress network allows, or one wider than the ephemeral ports of the system for
+    /// gateways with many short connections. The ports are taken in turn, skipping the ones in use;
+    /// when none of the ports tried is free, the socket falls back to an ephemeral port. The TCP
+    /// sockets set SO_REUSEADDR, so a port is only in use for the destinations it is connected to,
+    /// including in TIME_WAIT; a UDP port is in use for any destination, so the range limits the
+    /// concurrent UDP sessions. On Windows SO_REUSEADDR lets other sockets take over the port, so
+    /// it is not set there. Shortening TIME_WAIT itself, e.g. `net.ipv4.tcp_tw_reuse` on Linux, is
+    /// a setting of the system. `preserve_tcp_source_port` takes precedence for TCP, and the socket
+    /// created callback must not bind the socket itself. The ephemeral ports when unset.
+    pub upstream_port_range: Option<std::ops::RangeInclusive<u16>>,
+    /// Sends TCP keepalive probes on idle upstream connections, so NAT and firewalls on the egress
+    /// path keep their state for long lived connections that are mostly quiet, e.g. of push
+    /// notifications. It only keeps the connection to the server alive; the sessions still expire
+    /// on their own timeouts. The interval and retries are left to the system where they cannot be
+    /// set. Off when unset.
+    pub upstream_tcp_keepalive: Option<TcpKeepaliveConfig>,
+    /// Where sessions are forwarded to.
+    pub upstream: Upstream,
+    /// Where the DNS sessions, UDP and TCP to port 53, are forwarded to instead of `upstream`, e.g.
+    /// a resolver of its own while the rest of the traffic goes to a proxy. The rules apply first,
+    /// a DNS session they refuse reaches neither. Must be unset along with `doh`, which answers the
+    /// DNS sessions itself. Follows `upstream` when unset.
+    pub dns_upstream: Option<Upstream>,
+    /// Sends a PROXY protocol header with the addresses of the guest as the first bytes of every
+    /// upstream TCP connection, for servers behind the tunnel that have to see the real client,
+    /// e.g. a load balancer accepting the protocol. Servers not expecting the header fail to parse
+    /// the stream, so it is meant for tunnels whose destinations all accept it. Off when unset.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// What happens to the packets of protocols other than TCP and UDP. Their sessions expire
+    /// after `udp_timeout` without traffic.
+    pub unsupported_protocols: UnsupportedProtocols,
+    /// Most TCP sessions whose upstream connect is still in progress. SYNs of further sessions are
+    /// dropped, without creating an upstream socket, until connects complete. The guest retransmits
+    /// them, so a flaky network cannot pile up half-open connects. Unlimited when unset.
+    pub max_half_open_connections: Option<usize>,
+    /// Most sessions to the same destination address at once, e.g. to stay below the rate limits
+    /// of a server. Packets opening further sessions to it are dropped, without creating an
+    /// upstream socket, until some of its sessions end. Unlimited when unset.
+    pub max_sessions_per_destination: Option<usize>,
+    /// Most bytes held in the session buffers of the whole engine, read from the guest or the server
+    /// and not yet written to the other side. Over it the sessions holding the most stop reading
+    /// until the buffers are back under it, the guest is held back by the TCP window and the servers
+    /// by their socket buffers. It is checked after every packet of the guest, so a single read can
+    /// exceed it by up to the receive buffer of one session. A memory ceiling for constrained
+    /// devices, unlimited when unset.
+    pub max_buffered_bytes: Option<usize>,
+    /// Most packets read from the tun per iteration of the processor loop, before the events of the
+    /// existing sessions are served; the rest is read in the next iteration. Bounds the latency a
+    /// burst of the guest adds to the transfers in progress. Unlimited when unset.
+    pub tun_read_budget: Option<usize>,
+    /// Most sessions created per iteration of the processor loop, like `tun_read_budget` but only
+    /// counting the packets that create a session, which cost sockets and syscalls of their own, so
+    /// a connection storm of one app does not stall the transfers of the others. Unlimited when
+    /// unset.
+    pub new_session_budget: Option<usize>,
+    /// Silently drops the IPv6 housekeeping traffic of the guest, like neighbor discovery and
+    /// multicast listener reports, from or to link-local, multicast or unspecified addresses.
+    /// Otherwise each such packet is reported as a failed session.
+    pub drop_ipv6_housekeeping: bool,
+    /// Relays the network, host and port unreachable errors of upstream UDP sockets to the guest as
+    /// ICMP destination unreachable messages, instead of silently ending the session.
+    pub forward_icmp_errors: bool,
+    /// Relays the UDP datagrams of the guest to multicast groups and to the limited broadcast
+    /// address 255.255.255.255 onto the network of the host instead of dropping them, for the LAN
+    /// discovery of mDNS or SSDP in a bridged setup. Each source, group and port gets an
+    /// unconnected socket of its own that joins the group and always goes to the network directly,
+    /// whatever `upstream`. The answers are written to the guest from the address of each
+    /// responder. Only answers sent to the port of the query arrive; announcements and answers
+    /// sent to the port of the group are not received. Directed broadcasts like 192.168.1.255 look
+    /// like unicast addresses and are not relayed this way. IPv6 link-local groups go out the
+    /// interface of `link_local_scope_id`. The multicast networks of `DEFAULT_BLOCKED_DESTINATIONS`
+    /// do not apply to these datagrams; the other blocked destinations still do. It opens the local
+    /// network of the host to the guest, so it is off by default.
+    pub forward_multicast: bool,
+    /// Retries the writes of data the upstream sockets refused on every loop iteration, waking up
+    /// at least every millisecond while any is queued, instead of waiting for the sockets to report
+    /// writable again. Trades CPU and syscalls for latency, e.g. for latency measurements. Without
+    /// it the queued data is still retried whenever the processor goes idle, at least every 50ms.
+    pub immediate_forward: bool,
+    /// Scope id, the index of the egress interface, of the upstream connects to IPv6 link-local
+    /// destinations (fe80::/10). Such an address is ambiguous without it, the connect fails with
+    /// EINVAL when unset. Packets to link-local destinations only get this far with
+    /// `drop_ipv6_housekeeping` off.
+    pub link_local_scope_id: Option<u32>,
+    /// Fails closed when the processor stops on its own, because the tun kept failing, on an error
+    /// or a panic: the packets of the guest are then read from the tun and dropped until `tun::stop`,
+    /// see `tun::state`. The app is told through the tun failed callback either way, and should
+    /// keep the tun up rather than let the traffic fall back to the default route. On by default
+    /// with the `kill-switch` feature.
+    pub kill_switch: bool,
+}
+
+impl Default for TunConfig {
+    fn default() -> Self {
+        Self {
+            interface_address: Ipv4Addr::new(0, 0, 0, 1),
+            interface_prefix_len: 0,
+            gateway: Ipv4Addr::new(0, 0, 0, 1),
+            block_quic: false,
+            quic_port: 443,
+            quic_port_unreachable: false,
+            blocked_destinations: DEFAULT_BLOCKED_DESTINATIONS.to_vec(),
+            any_ip_excluded: Vec::new(),
+            ip_version_policy: IpVersionPolicy::Dual,
+            udp_max_datagram_size: crate::MAX_PACKET_SIZE,
+            tee: None,
+            reaper_interval: Duration::from_secs(1),
+            #[cfg(feature = "doh")]
+            doh: None,
+            allowed_sources: Vec::new(),
+            packet_log: PacketLogConfig::default(),
+            #[cfg(feature = "connection-log")]
+            connection_log: None,
+            #[cfg(feature = "capture-compression")]
+            capture_compression: None,
+            udp_timeout: Duration::from_secs(crate::UDP_TIMEOUT),
+            udp_port_timeouts: BTreeMap::from([(53, Duration::from_secs(2)), (443, Duration::from_secs(30))]),
+            udp_unanswered_timeout: None,
+            udp_send_deadline: Duration::from_secs(1),
+            tcp_close_grace: Duration::from_secs(crate::TCP_CLOSE_GRACE),
+            tcp_quarantine: Duration::from_secs(crate::TCP_QUARANTINE),
+            socket_pool_size: crate::SOCKET_POOL_SIZE,
+            hop_limit: 64,
+            mtu: crate::MAX_PACKET_SIZE,
+            strict_socket_setup: false,
+            upstream_recv_buffer_size: None,
+            upstream_send_buffer_size: None,
+            tcp_dscp: None,
+            udp_dscp: None,
+            port_priorities: BTreeMap::new(),
+            dscp_priority: false,
+            preserve_tcp_source_port: false,
+            upstream_port_range: None,
+            upstream_tcp_keepalive: None,
+            upstream: Upstream::Direct,
+            dns_upstream: None,
+            proxy_protocol: None,
+            unsupported_protocols: UnsupportedProtocols::Drop,
+            max_half_open_connections: None,
+            max_sessions_per_destination: None,
+            max_buffered_bytes: None,
+            tun_read_budget: None,
+            new_session_budget: None,
+            drop_ipv6_housekeeping: true,
+            forward_icmp_errors: false,
+            forward_multicast: false,
+            immediate_forward: false,
+            link_local_scope_id: None,
+            kill_switch: cfg!(feature = "kill-switch"),
+        }
+    }
+}
+
+impl TunConfig {
+    /// Starts a `TunConfigBuilder` from the default configuration.
+    pub fn builder() -> TunConfigBuilder {
+        TunConfigBuilder::default()
+    }
+
+    /// The rules currently deciding which sessions are refused.
+    pub fn rules(&self) -> Rules {
+        Rules {
+            blocked_destinations: self.blocked_destinations.clone(),
+            allowed_sources: self.allowed_sources.clone(),
+            block_quic: self.block_quic,
+            quic_port: self.quic_port,
+            quic_port_unreachable: self.quic_port_unreachable,
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.blocked_destinations = rules.blocked_destinations;
+        self.allowed_sources = rules.allowed_sources;
+        self.block_quic = rules.block_quic;
+        self.quic_port = rules.quic_port;
+        self.quic_port_unreachable = rules.quic_port_unreachable;
+    }
+
+    /// Where the session is forwarded to, `dns_upstream` for DNS sessions and `upstream` otherwise.
+    pub(crate) fn upstream_for(&self, session_info: &SessionInfo) -> &Upstream {
+        match &self.dns_upstream {
+            Some(dns_upstream) if session_info.destination.port() == crate::DNS_PORT => dns_upstream,
+            _ => &self.upstream,
+        }
+    }
+
+    /// Idle timeout of UDP sessions to `port`.
+    pub fn udp_timeout_for_port(&self, port: u16) -> Duration {
+        self.udp_port_timeouts.get(&port).copied().unwrap_or(self.udp_timeout)
+    }
+
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.interface_prefix_len > 32 {
+            return Err(crate::Error::InvalidConfig(format!("invalid prefix length {}", self.interface_prefix_len)));
+        }
+        for (name, address) in [("interface address", self.interface_address), ("gateway", self.gateway)] {
+            if address.is_unspecified() || address.is_broadcast() || address.is_multicast() || address.is_loopback() {
+                return Err(crate::Error::InvalidConfig(format!("{} {} is not a host address", name, address)));
+            }
+        }
+        if self.udp_max_datagram_size == 0 || self.udp_max_datagram_size > crate::MAX_PACKET_SIZE {
+            let err = format!(
+                "udp max datagram size {} is not within 1..={}",
+                self.udp_max_datagram_size,
+                crate::MAX_PACKET_SIZE
+            );
+            return Err(crate::Error::InvalidConfig(err));
+        }
+        if self.reaper_interval.is_zero() {
+            return Err(crate::Error::InvalidConfig("reaper interval must not be zero".to_string()));
+        }
+        if self.udp_timeout.is_zero()
+            || self.udp_port_timeouts.values().any(Duration::is_zero)
+            || self.udp_unanswered_timeout.is_some_and(|timeout| timeout.is_zero())
+        {
+            return Err(crate::Error::InvalidConfig("udp timeouts must not be zero".to_string()));
+        }
+        if self.hop_limit == 0 {
+            return Err(crate::Error::InvalidConfig("hop limit must not be zero".to_string()));
+        }
+        if self.mtu < MIN_MTU || self.mtu > crate::MAX_PACKET_SIZE {
+            let err = format!("mtu {} is not within {}..={}", self.mtu, MIN_MTU, crate::MAX_PACKET_SIZE);
+            return Err(crate::Error::InvalidConfig(err));
+        }
+        if self.upstream_recv_buffer_size == Some(0) || self.upstream_send_buffer_size == Some(0) {
+            return Err(crate::Error::InvalidConfig("upstream buffer sizes must not be zero".to_string()));
+        }
+        if self.max_half_open_connections == Some(0) {
+            return Err(crate::Error::InvalidConfig("max half-open connections must not be zero".to_string()));
+        }
+        if self.tcp_dscp.is_some_and(|dscp| dscp > MAX_DSCP) || self.udp_dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+            return Err(crate::Error::InvalidConfig(format!("dscp must not exceed {}", MAX_DSCP)));
+        }
+        if self
+            .upstream_tcp_keepalive
+            .is_some_and(|keepalive| keepalive.idle.as_secs() == 0 || keepalive.interval.as_secs() == 0 || keepalive.retries == 0)
+        {
+            return Err(crate::Error::InvalidConfig("tcp keepalive times and retries must be at least 1".to_string()));
+        }
+        if self.upstream_port_range.as_ref().is_some_and(|range| range.is_empty() || *range.start() == 0) {
+            return Err(crate::Error::InvalidConfig("invalid upstream port range".to_string()));
+        }
+        if self.max_sessions_per_destination == Some(0) {
+            return Err(crate::Error::InvalidConfig("max sessions per destination must not be zero".to_string()));
+        }
+        if self.max_buffered_bytes == Some(0) {
+            return Err(crate::Error::InvalidConfig("max buffered bytes must not be zero".to_string()));
+        }
+        if self.tun_read_budget == Some(0) || self.new_session_budget == Some(0) {
+            return Err(crate::Error::InvalidConfig("tun read budgets must not be zero".to_string()));
+        }
+        if self.packet_log.sample_rate == 0 {
+            return Err(crate::Error::InvalidConfig("packet log sample rate must not be zero".to_string()));
+        }
+        #[cfg(feature = "connection-log")]
+        if self.connection_log.as_ref().is_some_and(|connection_log| connection_log.max_bytes == 0) {
+            return Err(crate::Error::InvalidConfig("connection log size must not be zero".to_string()));
+        }
+        #[cfg(feature = "doh")]
+        if let Some(doh) = &self.doh {
+            crate::vpn::DohEndpoint::parse(&doh.url)?;
+            if self.dns_upstream.is_some() {
+                return Err(crate::Error::InvalidConfig("dns upstream is taken by doh".to_string()));
+            }
+        }
+        let mask = u32::MAX.checked_shl(32 - self.interface_prefix_len as u32).unwrap_or(0);
+        if u32::from(self.interface_address) & mask != u32::from(self.gateway) & mask {
+            let err = format!(
+                "gateway {} is outside of {}/{}",
+                self.gateway, self.interface_address, self.interface_prefix_len
+            );
+            return Err(crate::Error::InvalidConfig(err));
+        }
+        Ok(())
+    }
+}
+
+/// Chainable construction of a `TunConfig`, checked by `TunConfig::validate` on `build`.
+///
+/// Fields that are not set keep their `TunConfig::default` value.
+#[derive(Debug, Clone, Default)]
+pub struct TunConfigBuilder {
+    config: TunConfig,
+}
+
+impl TunConfigBuilder {
+    pub fn interface_address(mut self, interface_address: Ipv4Addr) -> Self {
+        self.config.interface_address = interface_address;
+        self
+    }
+
+    pub fn interface_prefix_len(mut self, interface_prefix_len: u8) -> Self {
+        self.config.interface_prefix_len = interface_prefix_len;
+        self
+    }
+
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> Self {
+        self.config.gateway = gateway;
+        self
+    }
+
+    pub fn block_quic(mut self, block_quic: bool) -> Self {
+        self.config.block_quic = block_quic;
+        self
+    }
+
+    pub fn quic_port(mut self, quic_port: u16) -> Self {
+        self.config.quic_port = quic_port;
+        self
+    }
+
+    pub fn quic_port_unreachable(mut self, quic_port_unreachable: bool) -> Self {
+        self.config.quic_port_unreachable = quic_port_unreachable;
+        self
+    }
+
+    pub fn blocked_destinations(mut self, blocked_destinations: Vec<IpNetwork>) -> Self {
+        self.config.blocked_destinations = blocked_destinations;
+        self
+    }
+
+    pub fn any_ip_excluded(mut self, any_ip_excluded: Vec<IpNetwork>) -> Self {
+        self.config.any_ip_excluded = any_ip_excluded;
+        self
+    }
+
+    pub fn ip_version_policy(mut self, ip_version_policy: IpVersionPolicy) -> Self {
+        self.config.ip_version_policy = ip_version_policy;
+        self
+    }
+
+    pub fn udp_max_datagram_size(mut self, udp_max_datagram_size: usize) -> Self {
+        self.config.udp_max_datagram_size = udp_max_datagram_size;
+        self
+    }
+
+    pub fn tee(mut self, tee: TeeTarget) -> Self {
+        self.config.tee = Some(tee);
+        self
+    }
+
+    pub fn reaper_interval(mut self, reaper_interval: Duration) -> Self {
+        self.config.reaper_interval = reaper_interval;
+        self
+    }
+
+    #[cfg(feature = "doh")]
+    pub fn doh(mut self, doh: DohConfig) -> Self {
+        self.config.doh = Some(doh);
+        self
+    }
+
+    pub fn allowed_sources(mut self, allowed_sources: Vec<IpNetwork>) -> Self {
+        self.config.allowed_sources = allowed_sources;
+        self
+    }
+
+    pub fn packet_log(mut self, packet_log: PacketLogConfig) -> Self {
+        self.config.packet_log = packet_log;
+        self
+    }
+
+    #[cfg(feature = "connection-log")]
+    pub fn connection_log(mut self, connection_log: ConnectionLogConfig) -> Self {
+        self.config.connection_log = Some(connection_log);
+        self
+    }
+
+    #[cfg(feature = "capture-compression")]
+    pub fn capture_compression(mut self, capture_compression: CaptureCompression) -> Self {
+        self.config.capture_compression = Some(capture_compression);
+        self
+    }
+
+    pub fn udp_timeout(mut self, udp_timeout: Duration) -> Self {
+        self.config.udp_timeout = udp_timeout;
+        self
+    }
+
+    pub fn udp_port_timeouts(mut self, udp_port_timeouts: BTreeMap<u16, Duration>) -> Self {
+        self.config.udp_port_timeouts = udp_port_timeouts;
+        self
+    }
+
+    pub fn udp_unanswered_timeout(mut self, udp_unanswered_timeout: Duration) -> Self {
+        self.config.udp_unanswered_timeout = Some(udp_unanswered_timeout);
+        self
+    }
+
+    pub fn udp_send_deadline(mut self, udp_send_deadline: Duration) -> Self {
+        self.config.udp_send_deadline = udp_send_deadline;
+        self
+    }
+
+    pub fn tcp_close_grace(mut self, tcp_close_grace: Duration) -> Self {
+        self.config.tcp_close_grace = tcp_close_grace;
+        self
+    }
+
+    pub fn tcp_quarantine(mut self, tcp_quarantine: Duration) -> Self {
+        self.config.tcp_quarantine = tcp_quarantine;
+        self
+    }
+
+    pub fn socket_pool_size(mut self, socket_pool_size: usize) -> Self {
+        self.config.socket_pool_size = socket_pool_size;
+        self
+    }
+
+    pub fn hop_limit(mut self, hop_limit: u8) -> Self {
+        self.config.hop_limit = hop_limit;
+        self
+    }
+
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.config.mtu = mtu;
+        self
+    }
+
+    pub fn strict_socket_setup(mut self, strict_socket_setup: bool) -> Self {
+        self.config.strict_socket_setup = strict_socket_setup;
+        self
+    }
+
+    pub fn upstream_recv_buffer_size(mut self, upstream_recv_buffer_size: usize) -> Self {
+        self.config.upstream_recv_buffer_size = Some(upstream_recv_buffer_size);
+        self
+    }
+
+    pub fn upstream_send_buffer_size(mut self, upstream_send_buffer_size: usize) -> Self {
+        self.config.upstream_send_buffer_size = Some(upstream_send_buffer_size);
+        self
+    }
+
+    pub fn tcp_dscp(mut self, tcp_dscp: u8) -> Self {
+        self.config.tcp_dscp = Some(tcp_dscp);
+        self
+    }
+
+    pub fn port_priorities(mut self, port_priorities: BTreeMap<u16, u8>) -> Self {
+        self.config.port_priorities = port_priorities;
+        self
+    }
+
+    pub fn dscp_priority(mut self, dscp_priority: bool) -> Self {
+        self.config.dscp_priority = dscp_priority;
+        self
+    }
+
+    pub fn udp_dscp(mut self, udp_dscp: u8) -> Self {
+        self.config.udp_dscp = Some(udp_dscp);
+        self
+    }
+
+    pub fn preserve_tcp_source_port(mut self, preserve_tcp_source_port: bool) -> Self {
+        self.config.preserve_tcp_source_port = preserve_tcp_source_port;
+        self
+    }
+
+    pub fn upstream_port_range(mut self, upstream_port_range: std::ops::RangeInclusive<u16>) -> Self {
+        self.config.upstream_port_range = Some(upstream_port_range);
+        self
+    }
+
+    pub fn upstream_tcp_keepalive(mut self, upstream_tcp_keepalive: TcpKeepaliveConfig) -> Self {
+        self.config.upstream_tcp_keepalive = Some(upstream_tcp_keepalive);
+        self
+    }
+
+    pub fn upstream(mut self, upstream: Upstream) -> Self {
+        self.config.upstream = upstream;
+        self
+    }
+
+    pub fn dns_upstream(mut self, dns_upstream: Upstream) -> Self {
+        self.config.dns_upstream = Some(dns_upstream);
+        self
+    }
+
+    pub fn proxy_protocol(mut self, proxy_protocol: ProxyProtocol) -> Self {
+        self.config.proxy_protocol = Some(proxy_protocol);
+        self
+    }
+
+    pub fn unsupported_protocols(mut self, unsupported_protocols: UnsupportedProtocols) -> Self {
+        self.config.unsupported_protocols = unsupported_protocols;
+        self
+    }
+
+    pub fn max_half_open_connections(mut self, max_half_open_connections: usize) -> Self {
+        self.config.max_half_open_connections = Some(max_half_open_connections);
+        self
+    }
+
+    pub fn max_sessions_per_destination(mut self, max_sessions_per_destination: usize) -> Self {
+        self.config.max_sessions_per_destination = Some(max_sessions_per_destination);
+        self
+    }
+
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.config.max_buffered_bytes = Some(max_buffered_bytes);
+        self
+    }
+
+    pub fn tun_read_budget(mut self, tun_read_budget: usize) -> Self {
+        self.config.tun_read_budget = Some(tun_read_budget);
+        self
+    }
+
+    pub fn new_session_budget(mut self, new_session_budget: usize) -> Self {
+        self.config.new_session_budget = Some(new_session_budget);
+        self
+    }
+
+    pub fn drop_ipv6_housekeeping(mut self, drop_ipv6_housekeeping: bool) -> Self {
+        self.config.drop_ipv6_housekeeping = drop_ipv6_housekeeping;
+        self
+    }
+
+    pub fn forward_icmp_errors(mut self, forward_icmp_errors: bool) -> Self {
+        self.config.forward_icmp_errors = forward_icmp_errors;
+        self
+    }
+
+    pub fn forward_multicast(mut self, forward_multicast: bool) -> Self {
+        self.config.forward_multicast = forward_multicast;
+        self
+    }
+
+    pub fn immediate_forward(mut self, immediate_forward: bool) -> Self {
+        self.config.immediate_forward = immediate_forward;
+        self
+    }
+
+    pub fn link_local_scope_id(mut self, link_local_scope_id: u32) -> Self {
+        self.config.link_local_scope_id = Some(link_local_scope_id);
+        self
+    }
+
+    pub fn kill_switch(mut self, kill_switch: bool) -> Self {
+        self.config.kill_switch = kill_switch;
+        self
+    }
+
+    pub fn build(self) -> crate::Result<TunConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
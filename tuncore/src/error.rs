@@ -1,3 +1,6 @@
+use alloc::string::{String, ToString};
+
+#[cfg(feature = "std")]
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -30,6 +33,42 @@ pub enum Error {
     #[error("smoltcp::wire::IpProtocol {0}")]
     UnsupportedProtocol(smoltcp::wire::IpProtocol),
 
+    #[error("{0} packet shorter than its header, len={1}")]
+    ShortPacket(smoltcp::wire::IpProtocol, usize),
+
+    #[error("session blocked: {0}")]
+    Blocked(crate::SessionInfo),
+
+    #[error("destination not accepted by the interface: {0}")]
+    NotAccepted(crate::SessionInfo),
+
+    #[error("too many half-open connections: {0}")]
+    TooManyHalfOpen(crate::SessionInfo),
+
+    #[error("too many sessions to the destination: {0}")]
+    TooManyToDestination(crate::SessionInfo),
+
+    #[error("unexpected source address: {0}")]
+    UnexpectedSource(crate::SessionInfo),
+
+    #[error("unspecified destination address or port: {0}")]
+    InvalidDestination(crate::SessionInfo),
+
+    #[error("session recently destroyed: {0}")]
+    Quarantined(crate::SessionInfo),
+
+    #[error("failed to set up the upstream socket to {0}")]
+    SocketSetup(std::net::SocketAddr),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("the packet engine is not running")]
+    NotRunning,
+
+    #[error("the packet engine did not reply in time")]
+    CommandTimeout,
+
     #[error("TryFromSliceError {0:?}")]
     TryFromSlice(#[from] std::array::TryFromSliceError),
 
@@ -55,13 +94,31 @@ impl From<&String> for Error {
     }
 }
 
+/// The errors of a build without `std`, those of the `packet` module.
+#[cfg(not(feature = "std"))]
+#[derive(Debug)]
+pub enum Error {
+    UnsupportedProtocol(smoltcp::wire::IpProtocol),
+    ShortPacket(smoltcp::wire::IpProtocol, usize),
+    TryFromSlice(core::array::TryFromSliceError),
+    String(String),
+}
+
+#[cfg(not(feature = "std"))]
+impl From<core::array::TryFromSliceError> for Error {
+    fn from(err: core::array::TryFromSliceError) -> Self {
+        Self::TryFromSlice(err)
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<Error> for std::io::Error {
     fn from(err: Error) -> Self {
         match err {
             Error::Io(err) => err,
-            _ => std::io::Error::new(std::io::ErrorKind::Other, err),
+            _ => std::io::Error::other(err),
         }
     }
 }
 
-pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Result<T, E = Error> = core::result::Result<T, E>;
@@ -1,23 +1,77 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod build_info;
+#[cfg(feature = "std")]
+mod config;
 mod error;
+#[cfg(feature = "std")]
+mod ip_network;
+pub mod packet;
+#[cfg(feature = "std")]
 mod vpn;
+#[cfg(feature = "std")]
+pub use build_info::BuildInfo;
+#[cfg(feature = "capture-compression")]
+pub use config::CaptureCompression;
+#[cfg(feature = "connection-log")]
+pub use config::ConnectionLogConfig;
+#[cfg(feature = "doh")]
+pub use config::DohConfig;
+#[cfg(feature = "std")]
+pub use config::{
+    IpVersionPolicy, PacketLogConfig, ProxyProtocol, Rules, TcpKeepaliveConfig, TeeTarget, TunConfig, TunConfigBuilder, UnsupportedProtocols,
+    Upstream,
+    DEFAULT_BLOCKED_DESTINATIONS, PRIVATE_NETWORKS,
+};
 pub use error::{Error, Result};
+#[cfg(feature = "std")]
+pub use ip_network::IpNetwork;
+pub use packet::{SegmentFlags, SessionInfo};
+#[cfg(feature = "std")]
+pub use vpn::{
+    CaptureFilter, ConnectLatencyHistogram, ProcessorHealth, SessionCounters, SessionDump, SessionError, SessionErrorCause, ShutdownReason,
+    StatsSnapshot, TunState, UpstreamConnector, UpstreamTransport, CONNECT_LATENCY_BOUNDS,
+};
 
+#[cfg(feature = "std")]
 pub(crate) const MAX_PACKET_SIZE: usize = 0xffff;
+#[cfg(feature = "std")]
 pub(crate) const UDP_TIMEOUT: u64 = 10; // seconds
-pub(crate) const TCP_TIMEOUT: u64 = 1; // seconds
+#[cfg(feature = "std")]
+pub(crate) const TCP_CLOSE_GRACE: u64 = 1; // seconds
+#[cfg(feature = "std")]
+pub(crate) const TCP_QUARANTINE: u64 = 2; // seconds
+#[cfg(feature = "std")]
+pub(crate) const SOCKET_POOL_SIZE: usize = 4;
+#[cfg(feature = "std")]
+pub(crate) const DNS_PORT: u16 = 53;
+#[cfg(feature = "std")]
+pub(crate) const COMMAND_TIMEOUT: u64 = 5; // seconds
 
-#[cfg(not(debug_assertions))]
+#[cfg(all(feature = "std", not(debug_assertions)))]
 pub(crate) const TCP_MAX_LIFETIME: u64 = 7200; // seconds (2 hours)
-#[cfg(debug_assertions)]
+#[cfg(all(feature = "std", debug_assertions))]
 pub(crate) const TCP_MAX_LIFETIME: u64 = 600; // seconds (10 minutes)
 
 // #[cfg(not(debug_assertions))]
 // pub(crate) const POLL_TIMEOUT: u64 = 60; // seconds
 // #[cfg(debug_assertions)]
+#[cfg(feature = "std")]
 pub(crate) const POLL_TIMEOUT: u64 = 5; // seconds
 
+/// Controls the packet engine. The functions asking the processor, like `sessions()` or
+/// `health()`, fail with `Error::NotRunning` when it was not started, exited or only drops the
+/// packets behind the kill switch, and with `Error::CommandTimeout` when it does not reply in time.
+#[cfg(feature = "std")]
 pub mod tun {
-    use crate::vpn::Vpn;
+    use crate::{
+        build_info::BUILD_INFO,
+        vpn::{CaptureFilter, CommandSender, ProcessorHealth, SessionDump, SessionInfo, ShutdownReason, StatsSnapshot, TunState, Vpn},
+        BuildInfo, Rules, TunConfig,
+    };
     use std::process;
     use std::sync::Mutex;
 
@@ -25,12 +79,6 @@ pub mod tun {
         static ref VPN: Mutex<Option<Vpn>> = Mutex::new(None);
     }
 
-    macro_rules! vpn {
-        () => {
-            VPN.lock().unwrap().as_mut().unwrap()
-        };
-    }
-
     pub fn create() {
         log::trace!("create, pid={}", process::id());
     }
@@ -39,36 +87,168 @@ pub mod tun {
         log::trace!("destroy, pid={}", process::id());
     }
 
-    pub fn start(file_descriptor: i32) {
+    pub fn start(file_descriptor: i32) -> crate::Result<()> {
+        start_with_config(file_descriptor, TunConfig::default())
+    }
+
+    /// Starts the processor on the tun `file_descriptor`, which it owns from then on.
+    ///
+    /// Fails with `Error::InvalidConfig` when `config` does not validate, and with the error of the
+    /// processor setup otherwise, e.g. without file descriptors left. The file descriptor is then
+    /// left to the caller.
+    pub fn start_with_config(file_descriptor: i32, config: TunConfig) -> crate::Result<()> {
         log::trace!("start, pid={}, fd={}", process::id(), file_descriptor);
-        update_vpn(file_descriptor);
-        vpn!().start().unwrap();
+        let mut vpn = Vpn::new(file_descriptor, config);
+        vpn.start()?;
+        *VPN.lock().unwrap() = Some(vpn);
         log::trace!("started, pid={}, fd={}", process::id(), file_descriptor);
+        Ok(())
     }
 
-    pub fn stop() {
+    /// Stops the processor and waits for its thread. Fails with `Error::NotRunning` when it was not
+    /// started or is already stopped.
+    ///
+    /// The thread is joined without holding the vpn, so the tun failed callback running on it can
+    /// still read `state()` meanwhile. Called on that thread, the stop is only requested: the thread
+    /// ends once the callback returned.
+    pub fn stop() -> crate::Result<()> {
         log::trace!("stop, pid={}", process::id());
-        vpn!().stop().unwrap();
+        let thread = VPN.lock().unwrap().as_mut().ok_or(crate::Error::NotRunning)?.stop()?;
+        if thread.thread().id() == std::thread::current().id() {
+            log::warn!("stop requested from the processor thread, not waiting for it");
+            return Ok(());
+        }
+        if let Err(error) = thread.join() {
+            log::error!("failed to join processor thread, error={:?}", error);
+        }
         log::trace!("stopped, pid={}", process::id());
+        Ok(())
+    }
+
+    /// Closes every session matching `predicate` and returns how many were closed. Fails with
+    /// `Error::NotRunning` when the processor was not started or exited.
+    pub fn close_sessions<F>(predicate: F) -> crate::Result<usize>
+    where
+        F: Fn(&SessionInfo) -> bool + Send + 'static,
+    {
+        log::trace!("close sessions, pid={}", process::id());
+        command_sender()?.close_sessions(predicate)
+    }
+
+    /// Returns the sessions currently handled by the processor.
+    pub fn sessions() -> crate::Result<Vec<SessionInfo>> {
+        log::trace!("sessions, pid={}", process::id());
+        command_sender()?.sessions()
+    }
+
+    /// Replaces the rules deciding which new sessions are refused, without restarting.
+    ///
+    /// With `enforce` the existing sessions the new rules refuse are closed as well, their count is
+    /// returned. The rules are kept for the next start only once the processor took them; after a
+    /// `CommandTimeout` it may still take them late.
+    pub fn reload_rules(rules: Rules, enforce: bool) -> crate::Result<usize> {
+        log::trace!("reload rules, pid={}", process::id());
+        let count = command_sender()?.reload_rules(rules.clone(), enforce)?;
+        if let Some(vpn) = VPN.lock().unwrap().as_mut() {
+            vpn.set_rules(rules);
+        }
+        Ok(count)
+    }
+
+    /// Whether packets are forwarded, or dropped by the kill switch after the processor failed.
+    pub fn state() -> TunState {
+        VPN.lock().unwrap().as_ref().map_or(TunState::Stopped, |vpn| vpn.state())
+    }
+
+    /// Why the processor stopped, after `stop()` or once it failed on its own; unset while it runs.
+    pub fn shutdown_reason() -> Option<ShutdownReason> {
+        VPN.lock().unwrap().as_ref().and_then(|vpn| vpn.shutdown_reason())
+    }
+
+    /// Returns the internal state of the TCP or UDP session, unset when there is no such session.
+    pub fn dump_session(session_info: SessionInfo) -> crate::Result<Option<SessionDump>> {
+        log::trace!("dump session, pid={}", process::id());
+        command_sender()?.dump_session(session_info)
+    }
+
+    /// Changes the MTU without restarting, e.g. after a handover to a network with a smaller one.
+    ///
+    /// New sessions and the existing ones send segments fitting the new MTU, the count of existing
+    /// sessions updated is returned. Fails with `Error::InvalidConfig` when `mtu` is out of range.
+    pub fn set_mtu(mtu: usize) -> crate::Result<usize> {
+        log::trace!("set mtu, pid={}, mtu={}", process::id(), mtu);
+        let command_sender = {
+            let mut vpn = VPN.lock().unwrap();
+            let vpn = vpn.as_mut().ok_or(crate::Error::NotRunning)?;
+            vpn.set_mtu(mtu)?;
+            vpn.command_sender()?
+        };
+        command_sender.set_mtu(mtu)
+    }
+
+    /// Records the packets of the sessions matching `filter` to a pcap file per session in
+    /// `directory`, the existing sessions from now on and the new ones from their first packet.
+    ///
+    /// Starting a capture with the filter of one in progress replaces it. A session matching several
+    /// captures is recorded by the one started first. Returns the count of existing sessions
+    /// recorded.
+    pub fn start_capture(filter: CaptureFilter, directory: std::path::PathBuf) -> crate::Result<usize> {
+        log::trace!("start capture, pid={}, filter={:?}", process::id(), filter);
+        command_sender()?.start_capture(filter, directory)
+    }
+
+    /// Stops the capture started with `filter` and returns the count of sessions it was recording.
+    pub fn stop_capture(filter: CaptureFilter) -> crate::Result<usize> {
+        log::trace!("stop capture, pid={}, filter={:?}", process::id(), filter);
+        command_sender()?.stop_capture(filter)
+    }
+
+    /// Returns a snapshot of the processor state, like the upstream connect latencies.
+    pub fn health() -> crate::Result<ProcessorHealth> {
+        log::trace!("health, pid={}", process::id());
+        command_sender()?.health()
     }
 
-    fn update_vpn(file_descriptor: i32) {
-        let mut vpn = VPN.lock().unwrap();
-        *vpn = Some(Vpn::new(file_descriptor));
+    /// Zeroes the cumulative counters of `health()`, like the connect latency histograms, and with
+    /// `sessions` the traffic counters of every session. The counters as they were right before are
+    /// returned, so usage can be reported in intervals without losing any.
+    pub fn reset_stats(sessions: bool) -> crate::Result<StatsSnapshot> {
+        log::trace!("reset stats, pid={}, sessions={}", process::id(), sessions);
+        command_sender()?.reset_stats(sessions)
     }
+
+    /// Returns the versions this library was built from, for bug reports.
+    pub fn version() -> BuildInfo {
+        BUILD_INFO
+    }
+
+    // cloned out of the lock, the reply is waited for without holding the vpn, so `stop()` and
+    // `state()` are not blocked by the processor.
+    fn command_sender() -> crate::Result<CommandSender> {
+        VPN.lock().unwrap().as_ref().ok_or(crate::Error::NotRunning)?.command_sender()
+    }
+
+    #[cfg(all(test, target_family = "unix"))]
+    mod tests;
 }
 
-#[cfg(target_family = "unix")]
+#[cfg(all(feature = "std", target_family = "unix"))]
 pub mod tun_callbacks {
 
+    use crate::{SessionError, SessionInfo, ShutdownReason, TunState};
     use std::os::unix::io::RawFd;
     use std::sync::RwLock;
 
     lazy_static::lazy_static! {
-        static ref CALLBACK: RwLock<fn(i32)> = RwLock::new(on_socket_created_stub);
+        static ref CALLBACK: RwLock<fn(i32) -> bool> = RwLock::new(on_socket_created_stub);
+        static ref TUN_FAILED_CALLBACK: RwLock<fn(ShutdownReason, TunState)> = RwLock::new(on_tun_failed_stub);
+        static ref SESSION_ERROR_CALLBACK: RwLock<fn(SessionError)> = RwLock::new(on_session_error_stub);
+        static ref UPSTREAM_FD_CALLBACK: RwLock<fn(&SessionInfo) -> Option<RawFd>> = RwLock::new(on_upstream_fd_stub);
     }
 
-    pub fn set_socket_created_callback(callback: Option<fn(i32)>) {
+    /// Sets the callback preparing every upstream socket before it connects, e.g. binding it to the
+    /// egress interface. It returns whether the socket was prepared, see `TunConfig::strict_socket_setup`.
+    pub fn set_socket_created_callback(callback: Option<fn(i32) -> bool>) {
         let mut current_callback = CALLBACK.write().unwrap();
         match callback {
             Some(callback) => *current_callback = callback,
@@ -76,10 +256,82 @@ pub mod tun_callbacks {
         }
     }
 
-    pub fn on_socket_created(socket: RawFd) {
+    pub fn on_socket_created(socket: RawFd) -> bool {
         let callback = CALLBACK.read().unwrap();
-        callback(socket);
+        callback(socket)
+    }
+
+    fn on_socket_created_stub(_socket: RawFd) -> bool {
+        true
+    }
+
+    /// Sets the callback told that the processor stopped on its own, because the tun kept failing,
+    /// e.g. once the system revoked the VPN, on an error or a panic. With `TunConfig::kill_switch`
+    /// the packets are dropped from then on, until `tun::stop`, and the state passed along is
+    /// `TunState::Blocking`.
+    ///
+    /// It runs on the processor thread before the packets are dropped, while a `tun::stop` on
+    /// another thread may be waiting for that thread. It gets the reason and the state instead of
+    /// asking for them, and must not call into `tun::`: the processor serves no commands meanwhile.
+    pub fn set_tun_failed_callback(callback: Option<fn(ShutdownReason, TunState)>) {
+        let mut current_callback = TUN_FAILED_CALLBACK.write().unwrap();
+        match callback {
+            Some(callback) => *current_callback = callback,
+            None => *current_callback = on_tun_failed_stub,
+        }
     }
 
-    fn on_socket_created_stub(_socket: RawFd) {}
+    pub fn on_tun_failed(reason: ShutdownReason, state: TunState) {
+        let callback = TUN_FAILED_CALLBACK.read().unwrap();
+        callback(reason, state)
+    }
+
+    fn on_tun_failed_stub(_reason: ShutdownReason, _state: TunState) {}
+
+    /// Sets the callback told about the failures the processor recovers from, like failed upstream
+    /// connects or malformed packets of the guest, which are otherwise only logged. It runs on the
+    /// processor thread, so it has to return quickly, e.g. by counting the errors.
+    pub fn set_session_error_callback(callback: Option<fn(SessionError)>) {
+        let mut current_callback = SESSION_ERROR_CALLBACK.write().unwrap();
+        match callback {
+            Some(callback) => *current_callback = callback,
+            None => *current_callback = on_session_error_stub,
+        }
+    }
+
+    pub fn on_session_error(error: SessionError) {
+        let callback = SESSION_ERROR_CALLBACK.read().unwrap();
+        callback(error)
+    }
+
+    fn on_session_error_stub(_error: SessionError) {}
+
+    /// Sets the callback supplying the upstream socket of a TCP or UDP session itself, e.g. one the
+    /// app already protected and connected, or the socket of a transport of its own. When it returns
+    /// a file descriptor, that is used instead of a socket of the crate, and the socket created
+    /// callback does not run for it; `None` leaves the session to `TunConfig::upstream`. It only
+    /// applies with `Upstream::Direct`.
+    ///
+    /// The processor takes ownership of the file descriptor and closes it with the session, the
+    /// caller must neither use nor close it afterwards. It has to be a stream socket for TCP
+    /// sessions and a datagram socket for UDP ones; any other is closed and the session refused. It
+    /// is switched to non-blocking mode. A socket that is not connected yet is connected to the
+    /// destination of the session, one already connected or connecting is used as is. It runs on the
+    /// processor thread, so it must not block, e.g. on a connect.
+    pub fn set_upstream_fd_callback(callback: Option<fn(&SessionInfo) -> Option<RawFd>>) {
+        let mut current_callback = UPSTREAM_FD_CALLBACK.write().unwrap();
+        match callback {
+            Some(callback) => *current_callback = callback,
+            None => *current_callback = on_upstream_fd_stub,
+        }
+    }
+
+    pub fn on_upstream_fd(session_info: &SessionInfo) -> Option<RawFd> {
+        let callback = UPSTREAM_FD_CALLBACK.read().unwrap();
+        callback(session_info)
+    }
+
+    fn on_upstream_fd_stub(_session_info: &SessionInfo) -> Option<RawFd> {
+        None
+    }
 }
@@ -0,0 +1,382 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::time::Duration;
+
+/// When a datagram was queued, for the deadlines of `Buffers::drop_stale`. The engine uses
+/// `std::time::Instant`, a build without `std` brings a clock of its own.
+pub trait Timestamp: Copy {
+    fn saturating_duration_since(&self, earlier: Self) -> Duration;
+}
+
+#[cfg(feature = "std")]
+impl Timestamp for std::time::Instant {
+    fn saturating_duration_since(&self, earlier: Self) -> Duration {
+        std::time::Instant::saturating_duration_since(self, earlier)
+    }
+}
+
+/// The data of a session waiting to be written to either side.
+pub enum Buffers<T: Timestamp> {
+    Tcp(TcpBuffers),
+    Udp(UdpBuffers<T>),
+}
+
+impl<T: Timestamp> Buffers<T> {
+    /// Queues the data of `event`, received at `now`.
+    pub fn store_data(&mut self, event: IncomingDataEvent<'_>, now: T) {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.store_data(event),
+            Buffers::Udp(udp_buf) => udp_buf.store_data(event, now),
+        }
+    }
+
+    /// Drops the UDP datagrams queued for longer than `deadline`, returning how many. The bytes of
+    /// a TCP stream are never dropped.
+    pub fn drop_stale(&mut self, direction: OutgoingDirection, deadline: Duration, now: T) -> usize {
+        match self {
+            Buffers::Tcp(_) => 0,
+            Buffers::Udp(udp_buf) => udp_buf.drop_stale(direction, deadline, now),
+        }
+    }
+
+    pub fn is_empty(&self, direction: OutgoingDirection) -> bool {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.is_empty(direction),
+            Buffers::Udp(udp_buf) => udp_buf.is_empty(direction),
+        }
+    }
+
+    /// Pending bytes for TCP, pending datagrams for UDP.
+    pub fn len(&self, direction: OutgoingDirection) -> usize {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.len(direction),
+            Buffers::Udp(udp_buf) => udp_buf.len(direction),
+        }
+    }
+
+    /// Bytes pending in both directions, the payload of the datagrams for UDP.
+    pub fn bytes(&self) -> usize {
+        match self {
+            Buffers::Tcp(tcp_buf) => tcp_buf.len(OutgoingDirection::ToServer) + tcp_buf.len(OutgoingDirection::ToClient),
+            Buffers::Udp(udp_buf) => udp_buf.bytes(),
+        }
+    }
+
+    pub fn consume_data_with_fn<F>(&mut self, direction: OutgoingDirection, mut consume_fn: F) -> crate::Result<()>
+    where
+        F: FnMut(&[u8]) -> crate::Result<usize>,
+    {
+        let mut result = Ok(());
+        match self {
+            Buffers::Tcp(tcp_buf) => {
+                // keep writing until the consumer is full, a partial write alone does not
+                // guarantee another writable event for the rest of the data.
+                loop {
+                    let buffer = tcp_buf.peek_data(direction);
+                    if buffer.is_empty() {
+                        break;
+                    }
+                    match consume_fn(buffer) {
+                        Ok(0) => break,
+                        Ok(consumed) => {
+                            tcp_buf.consume_data(direction, consumed);
+                        }
+                        Err(error) => {
+                            if !is_would_block(&error) {
+                                result = Err(error);
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+            Buffers::Udp(udp_buf) => {
+                let all_datagrams = udp_buf.peek_data(direction);
+                let mut consumed: usize = 0;
+                // write udp packets one by one
+                // empty datagrams are valid, e.g. as keepalives, and forwarded as well.
+                for datagram in all_datagrams {
+                    if let Err(error) = consume_fn(&datagram.data[..]) {
+                        if !is_would_block(&error) {
+                            result = Err(error);
+                        }
+                        break;
+                    }
+                    consumed += 1;
+                }
+                udp_buf.consume_data(direction, consumed);
+            }
+        }
+        result
+    }
+}
+
+pub struct TcpBuffers {
+    client_buf: VecDeque<u8>,
+    server_buf: VecDeque<u8>,
+}
+
+impl Default for TcpBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpBuffers {
+    pub fn new() -> TcpBuffers {
+        TcpBuffers {
+            client_buf: VecDeque::default(),
+            server_buf: VecDeque::default(),
+        }
+    }
+
+    pub fn is_empty(&self, direction: OutgoingDirection) -> bool {
+        match direction {
+            OutgoingDirection::ToServer => self.server_buf.is_empty(),
+            OutgoingDirection::ToClient => self.client_buf.is_empty(),
+        }
+    }
+
+    pub fn len(&self, direction: OutgoingDirection) -> usize {
+        match direction {
+            OutgoingDirection::ToServer => self.server_buf.len(),
+            OutgoingDirection::ToClient => self.client_buf.len(),
+        }
+    }
+
+    pub fn peek_data(&mut self, direction: OutgoingDirection) -> &[u8] {
+        let buffer = match direction {
+            OutgoingDirection::ToServer => &mut self.server_buf,
+            OutgoingDirection::ToClient => &mut self.client_buf,
+        };
+        buffer.make_contiguous()
+    }
+
+    pub fn consume_data(&mut self, direction: OutgoingDirection, size: usize) {
+        let buffer = match direction {
+            OutgoingDirection::ToServer => &mut self.server_buf,
+            OutgoingDirection::ToClient => &mut self.client_buf,
+        };
+        buffer.drain(0..size);
+    }
+
+    pub fn store_data(&mut self, event: IncomingDataEvent<'_>) {
+        match event.direction {
+            IncomingDirection::FromServer => {
+                self.client_buf.extend(event.buffer.iter());
+            }
+            IncomingDirection::FromClient => {
+                self.server_buf.extend(event.buffer.iter());
+            }
+        }
+    }
+}
+
+pub struct Datagram<T> {
+    pub data: Vec<u8>,
+    enqueued: T,
+}
+
+pub struct UdpBuffers<T> {
+    client_buf: VecDeque<Datagram<T>>,
+    server_buf: VecDeque<Datagram<T>>,
+}
+
+impl<T: Timestamp> Default for UdpBuffers<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Timestamp> UdpBuffers<T> {
+    pub fn new() -> UdpBuffers<T> {
+        UdpBuffers {
+            client_buf: VecDeque::default(),
+            server_buf: VecDeque::default(),
+        }
+    }
+
+    pub fn is_empty(&self, direction: OutgoingDirection) -> bool {
+        match direction {
+            OutgoingDirection::ToServer => self.server_buf.is_empty(),
+            OutgoingDirection::ToClient => self.client_buf.is_empty(),
+        }
+    }
+
+    pub fn len(&self, direction: OutgoingDirection) -> usize {
+        match direction {
+            OutgoingDirection::ToServer => self.server_buf.len(),
+            OutgoingDirection::ToClient => self.client_buf.len(),
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.server_buf.iter().chain(self.client_buf.iter()).map(|datagram| datagram.data.len()).sum()
+    }
+
+    pub fn peek_data(&mut self, direction: OutgoingDirection) -> &[Datagram<T>] {
+        let buffer = match direction {
+            OutgoingDirection::ToServer => &mut self.server_buf,
+            OutgoingDirection::ToClient => &mut self.client_buf,
+        };
+        buffer.make_contiguous()
+    }
+
+    pub fn consume_data(&mut self, direction: OutgoingDirection, size: usize) {
+        let buffer = match direction {
+            OutgoingDirection::ToServer => &mut self.server_buf,
+            OutgoingDirection::ToClient => &mut self.client_buf,
+        };
+        buffer.drain(0..size);
+    }
+
+    pub fn store_data(&mut self, event: IncomingDataEvent<'_>, now: T) {
+        let datagram = Datagram {
+            data: event.buffer.to_vec(),
+            enqueued: now,
+        };
+        match event.direction {
+            IncomingDirection::FromServer => self.client_buf.push_back(datagram),
+            IncomingDirection::FromClient => self.server_buf.push_back(datagram),
+        }
+    }
+
+    pub fn drop_stale(&mut self, direction: OutgoingDirection, deadline: Duration, now: T) -> usize {
+        let buffer = match direction {
+            OutgoingDirection::ToServer => &mut self.server_buf,
+            OutgoingDirection::ToClient => &mut self.client_buf,
+        };
+        // datagrams are queued in order, the stale ones are at the front.
+        let stale = buffer
+            .iter()
+            .take_while(|datagram| now.saturating_duration_since(datagram.enqueued) > deadline)
+            .count();
+        buffer.drain(0..stale);
+        stale
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, PartialOrd, Ord, Hash)]
+pub enum IncomingDirection {
+    FromServer,
+    FromClient,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, PartialOrd, Ord, Hash)]
+pub enum OutgoingDirection {
+    ToServer,
+    ToClient,
+}
+
+pub struct DataEvent<'a, T> {
+    pub direction: T,
+    pub buffer: &'a [u8],
+}
+
+pub type IncomingDataEvent<'a> = DataEvent<'a, IncomingDirection>;
+
+// a consumer that cannot take more for now, the rest is consumed on a later call.
+fn is_would_block(error: &crate::Error) -> bool {
+    #[cfg(feature = "std")]
+    if let crate::Error::Io(error) = error {
+        return error.kind() == std::io::ErrorKind::WouldBlock;
+    }
+    #[cfg(not(feature = "std"))]
+    let _ = error;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const DEADLINE: Duration = Duration::from_millis(100);
+
+    // milliseconds since the start of a test, moved by hand.
+    #[derive(Clone, Copy)]
+    struct Millis(u64);
+
+    impl Timestamp for Millis {
+        fn saturating_duration_since(&self, earlier: Self) -> Duration {
+            Duration::from_millis(self.0.saturating_sub(earlier.0))
+        }
+    }
+
+    fn store(buffers: &mut Buffers<Millis>, direction: IncomingDirection, data: &[u8], now: u64) {
+        let event = IncomingDataEvent { direction, buffer: data };
+        buffers.store_data(event, Millis(now));
+    }
+
+    fn datagrams(buffers: &mut Buffers<Millis>, direction: OutgoingDirection) -> Vec<Vec<u8>> {
+        match buffers {
+            Buffers::Udp(udp_buf) => udp_buf.peek_data(direction).iter().map(|datagram| datagram.data.clone()).collect(),
+            Buffers::Tcp(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn drop_stale_drops_datagrams_past_the_deadline() {
+        let mut buffers = Buffers::Udp(UdpBuffers::new());
+        store(&mut buffers, IncomingDirection::FromClient, b"first", 0);
+        store(&mut buffers, IncomingDirection::FromClient, b"second", 50);
+        store(&mut buffers, IncomingDirection::FromClient, b"third", 100);
+
+        // a datagram exactly as old as the deadline is still in time.
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(100)), 0);
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(151)), 2);
+        assert_eq!(datagrams(&mut buffers, OutgoingDirection::ToServer), vec![b"third".to_vec()]);
+        assert_eq!(buffers.bytes(), 5);
+
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(201)), 1);
+        assert!(buffers.is_empty(OutgoingDirection::ToServer));
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(1000)), 0);
+    }
+
+    #[test]
+    fn drop_stale_keeps_the_other_direction() {
+        let mut buffers = Buffers::Udp(UdpBuffers::new());
+        store(&mut buffers, IncomingDirection::FromClient, b"query", 0);
+        store(&mut buffers, IncomingDirection::FromServer, b"answer", 0);
+
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(500)), 1);
+        assert_eq!(datagrams(&mut buffers, OutgoingDirection::ToClient), vec![b"answer".to_vec()]);
+    }
+
+    #[test]
+    fn drop_stale_never_drops_tcp_data() {
+        let mut buffers = Buffers::<Millis>::Tcp(TcpBuffers::new());
+        store(&mut buffers, IncomingDirection::FromClient, b"stream", 0);
+
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(10_000)), 0);
+        assert_eq!(buffers.len(OutgoingDirection::ToServer), 6);
+    }
+
+    // a socket refusing every write drops what it held for too long, the datagrams queued since are
+    // still sent, in order, once it takes writes again.
+    #[cfg(feature = "std")]
+    #[test]
+    fn sustained_would_block_drops_old_datagrams_and_retries_new_ones() {
+        let mut buffers = Buffers::Udp(UdpBuffers::new());
+        let mut sent = Vec::new();
+        let mut write = |blocked: bool, buffers: &mut Buffers<Millis>| {
+            buffers.consume_data_with_fn(OutgoingDirection::ToServer, |datagram| {
+                if blocked {
+                    return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+                }
+                sent.push(datagram.to_vec());
+                Ok(datagram.len())
+            })
+        };
+        for (now, data) in [(0, b"one"), (20, b"two"), (80, b"six")] {
+            store(&mut buffers, IncomingDirection::FromClient, data, now);
+            write(true, &mut buffers).unwrap();
+        }
+        assert_eq!(buffers.len(OutgoingDirection::ToServer), 3, "refused datagrams not kept");
+
+        store(&mut buffers, IncomingDirection::FromClient, b"ten", 130);
+        assert_eq!(buffers.drop_stale(OutgoingDirection::ToServer, DEADLINE, Millis(130)), 2);
+        write(false, &mut buffers).unwrap();
+        assert!(buffers.is_empty(OutgoingDirection::ToServer));
+        assert_eq!(sent, vec![b"six".to_vec(), b"ten".to_vec()]);
+    }
+}
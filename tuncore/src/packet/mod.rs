@@ -0,0 +1,11 @@
+//! The packet-processing core of the engine: parsing the packets of the guest into sessions and
+//! buffering the data of the sessions. It does no I/O and needs `alloc` only, so it is also built
+//! without the `std` feature, for reuse where the engine itself cannot run.
+//!
+//! The rest of the crate is the I/O shell around it: the processor with its poll loop, the tun,
+//! the upstream sockets, the smoltcp interfaces, files and threads.
+
+pub mod buffers;
+pub mod session_info;
+
+pub use session_info::{SegmentFlags, SessionInfo};
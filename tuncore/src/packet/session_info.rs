@@ -0,0 +1,306 @@
+use alloc::format;
+use core::{fmt, hash::Hash, net::SocketAddr};
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket, IPV4_HEADER_LEN};
+
+/// Identity of a session, the key of the session tables of the processor.
+///
+/// The protocol is part of the identity: a TCP and a UDP session between the same addresses and
+/// ports, e.g. HTTPS and QUIC to the same server, are separate sessions with sockets of their own.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SessionInfo {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_display"))]
+    pub ip_version: IpVersion,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_display"))]
+    pub ip_protocol: IpProtocol,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Control flags of the TCP segment a session was parsed from, all unset for UDP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentFlags {
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+impl SessionInfo {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(bytes: &[u8], is_closed: &mut bool) -> crate::Result<SessionInfo> {
+        let (session_info, flags) = Self::parse_with_flags(bytes)?;
+        *is_closed = flags.fin || flags.rst;
+        Ok(session_info)
+    }
+
+    /// Parses a raw IP packet from the guest into the session the processor would handle it in.
+    pub fn parse(bytes: &[u8]) -> crate::Result<SessionInfo> {
+        Self::parse_with_flags(bytes).map(|(session_info, _)| session_info)
+    }
+
+    /// Like `parse`, along with the flags telling whether the packet opens or ends a TCP session.
+    pub fn parse_with_flags(bytes: &[u8]) -> crate::Result<(SessionInfo, SegmentFlags)> {
+        Self::new_ipv4(bytes).or_else(|e| {
+            if let crate::Error::UnsupportedProtocol(_) | crate::Error::ShortPacket(..) = e {
+                Err(e)
+            } else {
+                Self::new_ipv6(bytes)
+            }
+        })
+    }
+
+    /// Creates the session identity from its explicit parts, the IP version follows `source`.
+    pub fn from_addresses(ip_protocol: IpProtocol, source: SocketAddr, destination: SocketAddr) -> SessionInfo {
+        let ip_version = match source {
+            SocketAddr::V4(_) => IpVersion::Ipv4,
+            SocketAddr::V6(_) => IpVersion::Ipv6,
+        };
+        SessionInfo {
+            ip_version,
+            ip_protocol,
+            source,
+            destination,
+        }
+    }
+
+    /// Returns the same session seen from the other end, with source and destination swapped.
+    pub fn reverse(&self) -> SessionInfo {
+        SessionInfo {
+            source: self.destination,
+            destination: self.source,
+            ..*self
+        }
+    }
+
+    /// Creates the session of a packet of a protocol without ports, e.g. GRE, identified by its
+    /// addresses and protocol alone. The ports are left 0.
+    pub fn new_raw(bytes: &[u8]) -> crate::Result<SessionInfo> {
+        if let Some(ip_packet) = Self::ipv4_packet(bytes) {
+            let source_ip: [u8; 4] = ip_packet.src_addr().as_bytes().try_into()?;
+            let destination_ip: [u8; 4] = ip_packet.dst_addr().as_bytes().try_into()?;
+            return Ok(SessionInfo::from_addresses(
+                ip_packet.next_header(),
+                SocketAddr::from((source_ip, 0)),
+                SocketAddr::from((destination_ip, 0)),
+            ));
+        }
+        if let Some(ip_packet) = Self::ipv6_packet(bytes) {
+            let source_ip: [u8; 16] = ip_packet.src_addr().as_bytes().try_into()?;
+            let destination_ip: [u8; 16] = ip_packet.dst_addr().as_bytes().try_into()?;
+            return Ok(SessionInfo::from_addresses(
+                ip_packet.next_header(),
+                SocketAddr::from((source_ip, 0)),
+                SocketAddr::from((destination_ip, 0)),
+            ));
+        }
+        let err = format!("neither ipv4 nor ipv6 packet len={:?}", bytes.len());
+        Err(crate::Error::from(err))
+    }
+
+    // smoltcp checks neither the version nor the minimum header length: an IPv6 packet passes as
+    // IPv4, and a header length below 20 bytes would start the payload inside the addresses.
+    fn ipv4_packet(bytes: &[u8]) -> Option<Ipv4Packet<&[u8]>> {
+        Ipv4Packet::new_checked(bytes)
+            .ok()
+            .filter(|packet| packet.version() == 4 && usize::from(packet.header_len()) >= IPV4_HEADER_LEN)
+    }
+
+    fn ipv6_packet(bytes: &[u8]) -> Option<Ipv6Packet<&[u8]>> {
+        Ipv6Packet::new_checked(bytes).ok().filter(|packet| packet.version() == 6)
+    }
+
+    fn new_ipv4(bytes: &[u8]) -> crate::Result<(SessionInfo, SegmentFlags)> {
+        if let Some(ip_packet) = Self::ipv4_packet(bytes) {
+            let protocol = ip_packet.next_header();
+            // the payload starts at the header length, after any options like router alert.
+            let (source_port, destination_port, flags) = Self::parse_ports(protocol, ip_packet.payload())?;
+            let source_ip: [u8; 4] = ip_packet.src_addr().as_bytes().try_into()?;
+            let destination_ip: [u8; 4] = ip_packet.dst_addr().as_bytes().try_into()?;
+            let session_info = SessionInfo::from_addresses(
+                protocol,
+                SocketAddr::from((source_ip, source_port)),
+                SocketAddr::from((destination_ip, destination_port)),
+            );
+            return Ok((session_info, flags));
+        }
+        let err = format!("neither ipv4 nor ipv6 packet len={:?}", bytes.len());
+        Err(crate::Error::from(err))
+    }
+
+    fn new_ipv6(bytes: &[u8]) -> crate::Result<(SessionInfo, SegmentFlags)> {
+        if let Some(ip_packet) = Self::ipv6_packet(bytes) {
+            let protocol = ip_packet.next_header();
+            let (source_port, destination_port, flags) = Self::parse_ports(protocol, ip_packet.payload())?;
+            let source_ip: [u8; 16] = ip_packet.src_addr().as_bytes().try_into()?;
+            let destination_ip: [u8; 16] = ip_packet.dst_addr().as_bytes().try_into()?;
+            let session_info = SessionInfo::from_addresses(
+                protocol,
+                SocketAddr::from((source_ip, source_port)),
+                SocketAddr::from((destination_ip, destination_port)),
+            );
+            return Ok((session_info, flags));
+        }
+        let err = format!("neither ipv4 nor ipv6 packet len={:?}", bytes.len());
+        Err(crate::Error::from(err))
+    }
+
+    // some tun drivers deliver partial frames, whose IP header is complete but not the transport one.
+    fn parse_ports(protocol: IpProtocol, payload: &[u8]) -> crate::Result<(u16, u16, SegmentFlags)> {
+        let short_packet = |_| crate::Error::ShortPacket(protocol, payload.len());
+        match protocol {
+            IpProtocol::Tcp => {
+                let packet = TcpPacket::new_checked(payload).map_err(short_packet)?;
+                let flags = SegmentFlags {
+                    syn: packet.syn(),
+                    fin: packet.fin(),
+                    rst: packet.rst(),
+                };
+                Ok((packet.src_port(), packet.dst_port(), flags))
+            }
+            IpProtocol::Udp => {
+                let packet = UdpPacket::new_checked(payload).map_err(short_packet)?;
+                Ok((packet.src_port(), packet.dst_port(), SegmentFlags::default()))
+            }
+            _ => Err(crate::Error::UnsupportedProtocol(protocol)),
+        }
+    }
+}
+
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "[{:?}][{:?}]{}->{}",
+            self.ip_version, self.ip_protocol, self.source, self.destination
+        )
+    }
+}
+
+// the smoltcp wire types have no serde support, they are serialized by their names.
+#[cfg(feature = "serde")]
+fn serialize_display<T: fmt::Display, S: serde::Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{collections::BTreeMap, vec, vec::Vec};
+    use smoltcp::wire::{Ipv4Address, Ipv6Address};
+
+    const GUEST_PORT: u16 = 40000;
+    const SERVER_PORT: u16 = 443;
+
+    // an IPv4 packet from 10.0.0.2 to 10.0.0.4, with `options` after the 20 bytes of the header.
+    fn ipv4(protocol: IpProtocol, options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let header_len = IPV4_HEADER_LEN + options.len();
+        let mut bytes = vec![0; header_len + payload.len()];
+        let mut packet = Ipv4Packet::new_unchecked(&mut bytes);
+        packet.set_version(4);
+        packet.set_header_len(header_len as u8);
+        packet.set_total_len((header_len + payload.len()) as u16);
+        packet.set_hop_limit(64);
+        packet.set_next_header(protocol);
+        packet.set_src_addr(Ipv4Address::new(10, 0, 0, 2));
+        packet.set_dst_addr(Ipv4Address::new(10, 0, 0, 4));
+        bytes[IPV4_HEADER_LEN..header_len].copy_from_slice(options);
+        bytes[header_len..].copy_from_slice(payload);
+        bytes
+    }
+
+    fn ipv6(protocol: IpProtocol, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; 40 + payload.len()];
+        let mut packet = Ipv6Packet::new_unchecked(&mut bytes);
+        packet.set_version(6);
+        packet.set_payload_len(payload.len() as u16);
+        packet.set_next_header(protocol);
+        packet.set_hop_limit(64);
+        packet.set_src_addr(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2));
+        packet.set_dst_addr(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 4));
+        bytes[40..].copy_from_slice(payload);
+        bytes
+    }
+
+    fn udp() -> Vec<u8> {
+        let mut bytes = vec![0; 8];
+        bytes[..2].copy_from_slice(&GUEST_PORT.to_be_bytes());
+        bytes[2..4].copy_from_slice(&SERVER_PORT.to_be_bytes());
+        bytes[4..6].copy_from_slice(&8u16.to_be_bytes());
+        bytes
+    }
+
+    // a SYN without options.
+    fn tcp() -> Vec<u8> {
+        let mut bytes = vec![0; 20];
+        bytes[..2].copy_from_slice(&GUEST_PORT.to_be_bytes());
+        bytes[2..4].copy_from_slice(&SERVER_PORT.to_be_bytes());
+        bytes[12] = 5 << 4;
+        bytes[13] = 0x02;
+        bytes
+    }
+
+    #[test]
+    fn protocol_is_part_of_the_key() {
+        let (tcp, _) = SessionInfo::parse_with_flags(&ipv4(IpProtocol::Tcp, &[], &tcp())).unwrap();
+        let (udp, flags) = SessionInfo::parse_with_flags(&ipv4(IpProtocol::Udp, &[], &udp())).unwrap();
+        assert_eq!((tcp.source, tcp.destination), (udp.source, udp.destination));
+        assert_eq!(flags, SegmentFlags::default());
+        assert_ne!(tcp, udp);
+
+        let sessions = BTreeMap::from([(tcp, "tcp"), (udp, "udp")]);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!((sessions[&tcp], sessions[&udp]), ("tcp", "udp"));
+    }
+
+    #[test]
+    fn ipv4_options_are_skipped() {
+        // IHL 6, the router alert option of IGMP and MLD.
+        let packet = ipv4(IpProtocol::Udp, &[0x94, 0x04, 0x00, 0x00], &udp());
+        assert_eq!(Ipv4Packet::new_checked(&packet[..]).unwrap().header_len(), 24);
+        let session_info = SessionInfo::parse(&packet).unwrap();
+        assert_eq!(session_info.source, SocketAddr::from(([10, 0, 0, 2], GUEST_PORT)));
+        assert_eq!(session_info.destination, SocketAddr::from(([10, 0, 0, 4], SERVER_PORT)));
+    }
+
+    #[test]
+    fn ipv4_header_shorter_than_minimum_is_rejected() {
+        for header_len in [0, 16] {
+            let mut packet = ipv4(IpProtocol::Udp, &[], &udp());
+            Ipv4Packet::new_unchecked(&mut packet).set_header_len(header_len);
+            assert!(SessionInfo::parse(&packet).is_err(), "header length {}", header_len);
+            assert!(SessionInfo::new_raw(&packet).is_err(), "header length {}", header_len);
+        }
+    }
+
+    #[test]
+    fn ipv6_packet_is_not_taken_for_ipv4() {
+        let packet = ipv6(IpProtocol::Tcp, &tcp());
+        assert!(SessionInfo::new_ipv4(&packet).is_err());
+        let (session_info, flags) = SessionInfo::parse_with_flags(&packet).unwrap();
+        assert_eq!(session_info.ip_version, IpVersion::Ipv6);
+        assert_eq!(session_info.destination, SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 4], SERVER_PORT)));
+        assert!(flags.syn);
+        assert_eq!(SessionInfo::new_raw(&packet).unwrap().ip_version, IpVersion::Ipv6);
+    }
+
+    #[test]
+    fn reverse_swaps_the_endpoints() {
+        let session_info = SessionInfo::parse(&ipv4(IpProtocol::Tcp, &[], &tcp())).unwrap();
+        let reversed = session_info.reverse();
+        assert_eq!((reversed.source, reversed.destination), (session_info.destination, session_info.source));
+        assert_eq!((reversed.ip_version, reversed.ip_protocol), (session_info.ip_version, session_info.ip_protocol));
+        assert_ne!(reversed, session_info);
+        assert_eq!(reversed.reverse(), session_info);
+    }
+
+    #[test]
+    fn from_addresses_takes_the_ip_version_of_the_source() {
+        let ipv4 = SessionInfo::parse(&ipv4(IpProtocol::Udp, &[], &udp())).unwrap();
+        assert_eq!(SessionInfo::from_addresses(IpProtocol::Udp, ipv4.source, ipv4.destination), ipv4);
+
+        let ipv6 = SessionInfo::parse(&ipv6(IpProtocol::Tcp, &tcp())).unwrap();
+        let session_info = SessionInfo::from_addresses(IpProtocol::Tcp, ipv6.source, ipv6.destination);
+        assert_eq!(session_info.ip_version, IpVersion::Ipv6);
+        assert_eq!(session_info, ipv6);
+    }
+}
@@ -0,0 +1,54 @@
+use super::*;
+use crate::tun_callbacks;
+use std::{
+    os::unix::io::IntoRawFd,
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+// the failures reported, and the release the callback waits for.
+type Failures = (Sender<(ShutdownReason, TunState)>, Receiver<()>);
+
+// the callback is a plain function, it finds the channels of the test here.
+lazy_static::lazy_static! {
+    static ref FAILURES: Mutex<Option<Failures>> = Mutex::new(None);
+}
+
+// reports the failure, then holds the processor thread until the test releases it.
+fn on_tun_failed(reason: ShutdownReason, state: TunState) {
+    let failures = FAILURES.lock().unwrap();
+    let (sender, release) = failures.as_ref().unwrap();
+    sender.send((reason, state)).unwrap();
+    release.recv_timeout(Duration::from_secs(5)).unwrap();
+}
+
+#[test]
+fn stop_does_not_wait_for_failed_callback_under_lock() {
+    let (sender, failures) = channel();
+    let (release, receiver) = channel();
+    *FAILURES.lock().unwrap() = Some((sender, receiver));
+    tun_callbacks::set_tun_failed_callback(Some(on_tun_failed));
+
+    // epoll refuses a regular file, so the processor fails right away.
+    let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+    let config = TunConfig {
+        kill_switch: true,
+        ..TunConfig::default()
+    };
+    start_with_config(file.into_raw_fd(), config).unwrap();
+    let failure = failures.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(failure, (ShutdownReason::TunError, TunState::Blocking));
+
+    // the stop waits for the processor thread, still in the callback, without holding the vpn.
+    let stopper = std::thread::spawn(stop);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(state(), TunState::Blocking);
+    assert_eq!(shutdown_reason(), Some(ShutdownReason::TunError));
+    assert!(matches!(sessions(), Err(crate::Error::NotRunning)));
+
+    release.send(()).unwrap();
+    stopper.join().unwrap().unwrap();
+    assert_eq!(state(), TunState::Stopped);
+    assert!(matches!(stop(), Err(crate::Error::NotRunning)));
+    tun_callbacks::set_tun_failed_callback(None);
+}
@@ -0,0 +1,24 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Bytes held in the session buffers of the whole engine, shared by every session and the
+/// processor enforcing `TunConfig::max_buffered_bytes`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BufferUsage(Arc<AtomicUsize>);
+
+impl BufferUsage {
+    /// Accounts for buffers holding `current` bytes after holding `previous` bytes.
+    pub(crate) fn update(&self, previous: usize, current: usize) {
+        if current > previous {
+            self.0.fetch_add(current - previous, Ordering::Relaxed);
+        } else {
+            self.0.fetch_sub(previous - current, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
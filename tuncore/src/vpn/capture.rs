@@ -0,0 +1,161 @@
+#[cfg(feature = "capture-compression")]
+use crate::CaptureCompression;
+use crate::{vpn::session_info::SessionInfo, IpNetwork, TunConfig};
+#[cfg(feature = "capture-compression")]
+use flate2::{write::GzEncoder, Compression};
+use smoltcp::wire::IpProtocol;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// the packets are recorded as they cross the tun, raw IP without a link layer header.
+const LINKTYPE_RAW: u32 = 101;
+
+const SNAPLEN: u32 = 65535;
+
+/// Which sessions a capture started with `tun::start_capture` records, every criterion set has to
+/// match. The default filter matches every session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureFilter {
+    /// Networks the destination of the session is in, empty matches any destination.
+    pub destinations: Vec<IpNetwork>,
+    /// Destination port of the session.
+    pub port: Option<u16>,
+    pub protocol: Option<IpProtocol>,
+}
+
+impl CaptureFilter {
+    pub fn matches(&self, session_info: &SessionInfo) -> bool {
+        let destination = session_info.destination;
+        (self.destinations.is_empty() || self.destinations.iter().any(|network| network.contains(&destination.ip())))
+            && self.port.is_none_or(|port| port == destination.port())
+            && self.protocol.is_none_or(|protocol| protocol == session_info.ip_protocol)
+    }
+}
+
+/// The captures in progress, in the order they were started. A session is recorded by the first
+/// capture matching it, to a pcap file of its own in the directory of the capture.
+#[derive(Debug)]
+pub(crate) struct CaptureRegistry {
+    captures: Vec<(CaptureFilter, PathBuf)>,
+    #[cfg(feature = "capture-compression")]
+    compression: Option<CaptureCompression>,
+}
+
+impl CaptureRegistry {
+    #[cfg_attr(not(feature = "capture-compression"), allow(unused_variables))]
+    pub(crate) fn new(config: &TunConfig) -> CaptureRegistry {
+        CaptureRegistry {
+            captures: Vec::new(),
+            #[cfg(feature = "capture-compression")]
+            compression: config.capture_compression,
+        }
+    }
+
+    pub(crate) fn start(&mut self, filter: CaptureFilter, directory: PathBuf) {
+        self.captures.push((filter, directory));
+    }
+
+    /// Returns whether a capture with `filter` was in progress.
+    pub(crate) fn stop(&mut self, filter: &CaptureFilter) -> bool {
+        let count = self.captures.len();
+        self.captures.retain(|(capture_filter, _)| capture_filter != filter);
+        self.captures.len() != count
+    }
+
+    /// Opens the pcap file of the session when a capture matches it.
+    pub(crate) fn open(&self, session_info: &SessionInfo) -> Option<Capture> {
+        let (filter, directory) = self.captures.iter().find(|(filter, _)| filter.matches(session_info))?;
+        Some(Capture::new(filter, directory, session_info, self))
+    }
+
+    fn extension(&self) -> &'static str {
+        #[cfg(feature = "capture-compression")]
+        match self.compression {
+            Some(CaptureCompression::Gzip) => return "pcap.gz",
+            Some(CaptureCompression::Zstd) => return "pcap.zst",
+            None => {}
+        }
+        "pcap"
+    }
+
+    // the encoders write their last block, and gzip its trailer, when dropped with the capture.
+    fn writer(&self, file: File) -> std::io::Result<Box<dyn Write + Send>> {
+        #[cfg(feature = "capture-compression")]
+        match self.compression {
+            Some(CaptureCompression::Gzip) => return Ok(Box::new(GzEncoder::new(file, Compression::fast()))),
+            Some(CaptureCompression::Zstd) => return Ok(Box::new(zstd::Encoder::new(file, 1)?.auto_finish())),
+            None => {}
+        }
+        Ok(Box::new(file))
+    }
+}
+
+/// The pcap file one session is recorded to.
+///
+/// Like the tee the capture is best effort: a failing file is dropped after logging the error, the
+/// forwarded traffic is never held back by it.
+pub(crate) struct Capture {
+    filter: CaptureFilter,
+    file: Option<Box<dyn Write + Send>>,
+}
+
+impl Capture {
+    fn new(filter: &CaptureFilter, directory: &Path, info: &SessionInfo, registry: &CaptureRegistry) -> Capture {
+        let file = Self::create(directory, info, registry)
+            .map_err(|error| log::error!("failed to open capture, {:?} error={:?}", info, error))
+            .ok();
+        Capture { filter: filter.clone(), file }
+    }
+
+    /// Whether the session is recorded by the capture started with `filter`.
+    pub(crate) fn is_of(&self, filter: &CaptureFilter) -> bool {
+        self.filter == *filter
+    }
+
+    pub(crate) fn write_packet(&mut self, bytes: &[u8]) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured = &bytes[..bytes.len().min(SNAPLEN as usize)];
+        let mut record = Vec::with_capacity(16 + captured.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(captured);
+        // a single write per packet, a reader of a growing uncompressed file never sees half a
+        // record header.
+        if let Err(error) = file.write_all(&record) {
+            log::error!("failed to write to capture, error={:?}", error);
+            self.file = None;
+        }
+    }
+
+    fn create(directory: &Path, info: &SessionInfo, registry: &CaptureRegistry) -> std::io::Result<Box<dyn Write + Send>> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let name = format!(
+            "{}-{}-{}-{}.{}",
+            timestamp,
+            info.ip_protocol,
+            info.source.to_string().replace(':', "_"),
+            info.destination.to_string().replace(':', "_"),
+            registry.extension()
+        );
+        let mut file = registry.writer(File::create(directory.join(name))?)?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4_u32.to_le_bytes());
+        header.extend_from_slice(&2_u16.to_le_bytes());
+        header.extend_from_slice(&4_u16.to_le_bytes());
+        // timezone offset and timestamp accuracy, both always zero.
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(file)
+    }
+}
@@ -0,0 +1,99 @@
+use crate::vpn::{
+    capture::CaptureFilter,
+    health::{ProcessorHealth, SessionDump, StatsSnapshot},
+    session_info::SessionInfo,
+};
+use crate::Error;
+use mio::Waker;
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub(crate) type SessionPredicate = Box<dyn Fn(&SessionInfo) -> bool + Send>;
+
+/// Requests executed on the processor thread, drained at the top of every poll loop.
+pub(crate) enum Command {
+    CloseSessions(SessionPredicate, Sender<usize>),
+    ListSessions(Sender<Vec<SessionInfo>>),
+    Health(Sender<ProcessorHealth>),
+    ResetStats(bool, Sender<StatsSnapshot>),
+    ReloadRules(crate::Rules, bool, Sender<usize>),
+    SetMtu(usize, Sender<usize>),
+    DumpSession(SessionInfo, Sender<Option<SessionDump>>),
+    StartCapture(CaptureFilter, PathBuf, Sender<usize>),
+    StopCapture(CaptureFilter, Sender<usize>),
+}
+
+#[derive(Clone)]
+pub(crate) struct CommandSender {
+    sender: Sender<Command>,
+    waker: Arc<Waker>,
+}
+
+impl CommandSender {
+    pub(crate) fn new(sender: Sender<Command>, waker: Arc<Waker>) -> CommandSender {
+        CommandSender { sender, waker }
+    }
+
+    /// Sends the command built by `build_command` and blocks until the processor replies. Fails with
+    /// `Error::NotRunning` when the processor exited, or drops the commands after it failed, and with
+    /// `Error::CommandTimeout` when it does not reply in time; the command may still be carried out.
+    pub(crate) fn request<T, F>(&self, build_command: F) -> crate::Result<T>
+    where
+        F: FnOnce(Sender<T>) -> Command,
+    {
+        let (reply_sender, reply_receiver) = channel();
+        self.sender.send(build_command(reply_sender)).map_err(|_| Error::NotRunning)?;
+        self.waker.wake()?;
+        reply_receiver
+            .recv_timeout(Duration::from_secs(crate::COMMAND_TIMEOUT))
+            .map_err(|error| match error {
+                RecvTimeoutError::Timeout => Error::CommandTimeout,
+                RecvTimeoutError::Disconnected => Error::NotRunning,
+            })
+    }
+
+    pub(crate) fn close_sessions<F>(&self, predicate: F) -> crate::Result<usize>
+    where
+        F: Fn(&SessionInfo) -> bool + Send + 'static,
+    {
+        self.request(|reply_sender| Command::CloseSessions(Box::new(predicate), reply_sender))
+    }
+
+    pub(crate) fn sessions(&self) -> crate::Result<Vec<SessionInfo>> {
+        self.request(Command::ListSessions)
+    }
+
+    pub(crate) fn reload_rules(&self, rules: crate::Rules, enforce: bool) -> crate::Result<usize> {
+        self.request(|reply_sender| Command::ReloadRules(rules, enforce, reply_sender))
+    }
+
+    pub(crate) fn dump_session(&self, session_info: SessionInfo) -> crate::Result<Option<SessionDump>> {
+        self.request(|reply_sender| Command::DumpSession(session_info, reply_sender))
+    }
+
+    pub(crate) fn set_mtu(&self, mtu: usize) -> crate::Result<usize> {
+        self.request(|reply_sender| Command::SetMtu(mtu, reply_sender))
+    }
+
+    pub(crate) fn start_capture(&self, filter: CaptureFilter, directory: PathBuf) -> crate::Result<usize> {
+        self.request(|reply_sender| Command::StartCapture(filter, directory, reply_sender))
+    }
+
+    pub(crate) fn stop_capture(&self, filter: CaptureFilter) -> crate::Result<usize> {
+        self.request(|reply_sender| Command::StopCapture(filter, reply_sender))
+    }
+
+    pub(crate) fn health(&self) -> crate::Result<ProcessorHealth> {
+        self.request(Command::Health)
+    }
+
+    pub(crate) fn reset_stats(&self, sessions: bool) -> crate::Result<StatsSnapshot> {
+        self.request(|reply_sender| Command::ResetStats(sessions, reply_sender))
+    }
+}
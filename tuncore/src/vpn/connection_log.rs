@@ -0,0 +1,110 @@
+use crate::{
+    vpn::{health::SessionCounters, session::DestroyReason},
+    ConnectionLogConfig,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::mpsc::{channel, Sender},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Access log of the ended sessions, see `TunConfig::connection_log`.
+///
+/// The lines are formatted on the processor thread and appended by a writer thread, which stops
+/// once the log is dropped with the processor. Like the tee the log is best effort, a line that
+/// cannot be written is dropped after logging the error.
+pub(crate) struct ConnectionLog {
+    lines: Sender<String>,
+}
+
+impl ConnectionLog {
+    pub(crate) fn new(config: &ConnectionLogConfig) -> ConnectionLog {
+        let (lines, line_receiver) = channel::<String>();
+        let mut appender = Appender {
+            config: config.clone(),
+            file: None,
+            size: 0,
+        };
+        std::thread::spawn(move || {
+            while let Ok(line) = line_receiver.recv() {
+                appender.append(&line);
+            }
+            log::trace!("connection log stopped");
+        });
+        ConnectionLog { lines }
+    }
+
+    /// Logs the session that ended with `reason`, `age` after its creation.
+    pub(crate) fn record(&self, counters: &SessionCounters, age: Duration, reason: DestroyReason) {
+        let start = SystemTime::now().checked_sub(age).unwrap_or(UNIX_EPOCH);
+        let line = format!(
+            "{} {} duration_ms={} bytes_from_guest={} bytes_to_guest={} reason={:?}\n",
+            start.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis(),
+            counters.session_info,
+            age.as_millis(),
+            counters.bytes_from_guest,
+            counters.bytes_to_guest,
+            reason
+        );
+        if let Err(error) = self.lines.send(line) {
+            log::error!("failed to queue connection log line, error={:?}", error);
+        }
+    }
+}
+
+struct Appender {
+    config: ConnectionLogConfig,
+    file: Option<File>,
+    size: u64,
+}
+
+impl Appender {
+    fn append(&mut self, line: &str) {
+        if let Err(error) = self.write(line) {
+            log::error!("failed to write to connection log, path={:?} error={:?}", self.config.path, error);
+            // opened again for the next line, e.g. once the directory is back.
+            self.file = None;
+        }
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_bytes {
+            self.file = None;
+            self.rotate()?;
+            self.file = Some(File::create(&self.config.path)?);
+            self.size = 0;
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    // renaming onto the oldest file replaces it.
+    fn rotate(&self) -> std::io::Result<()> {
+        if self.config.max_files == 0 {
+            return Ok(());
+        }
+        for index in (1..self.config.max_files).rev() {
+            match std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        std::fs::rename(&self.config.path, self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+const HEADER_LEN: usize = 12;
+const RECORD_TYPE_OPT: u16 = 41;
+const MAX_POINTERS: usize = 16;
+const CACHE_CAPACITY: usize = 1024;
+
+/// Name and type of the single question of a DNS message, the key of cached answers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Question {
+    pub(crate) name: String,
+    pub(crate) record_type: u16,
+}
+
+pub(crate) fn id(message: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(message.get(..2)?.try_into().ok()?))
+}
+
+pub(crate) fn set_id(message: &mut [u8], id: u16) {
+    if message.len() >= 2 {
+        message[..2].copy_from_slice(&id.to_be_bytes());
+    }
+}
+
+/// Builds a SERVFAIL response to `query`, telling the guest right away that resolving failed.
+pub(crate) fn server_failure(query: &[u8]) -> Vec<u8> {
+    let mut response = query.to_vec();
+    if response.len() >= HEADER_LEN {
+        // QR and the original opcode and RD bits, then RA and RCODE 2.
+        response[2] |= 0x80;
+        response[3] = 0x82;
+    }
+    response
+}
+
+pub(crate) fn question(message: &[u8]) -> Option<Question> {
+    if message.len() < HEADER_LEN || read_u16(message, 4)? != 1 {
+        return None;
+    }
+    let (name, offset) = read_name(message, HEADER_LEN)?;
+    let record_type = read_u16(message, offset)?;
+    Some(Question { name, record_type })
+}
+
+/// Offsets of the TTL fields of all resource records, the EDNS pseudo record excluded.
+fn ttl_offsets(message: &[u8]) -> Option<Vec<usize>> {
+    let questions = read_u16(message, 4)?;
+    let records = read_u16(message, 6)? as usize + read_u16(message, 8)? as usize + read_u16(message, 10)? as usize;
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    let mut offsets = Vec::with_capacity(records);
+    for _ in 0..records {
+        offset = skip_name(message, offset)?;
+        let record_type = read_u16(message, offset)?;
+        let data_len = read_u16(message, offset + 8)? as usize;
+        if record_type != RECORD_TYPE_OPT {
+            offsets.push(offset + 4);
+        }
+        offset += 10 + data_len;
+        if offset > message.len() {
+            return None;
+        }
+    }
+    Some(offsets)
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(message.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(message: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(message.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += 1 + len;
+    }
+}
+
+// returns the lower case name and the offset following it in the message.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(offset)? as usize;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            end.get_or_insert(offset + 2);
+            offset = (read_u16(message, offset)? & 0x3fff) as usize;
+            continue;
+        }
+        let label = message.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        offset += 1 + len;
+    }
+    Some((labels.join("."), end.unwrap_or(offset + 1)))
+}
+
+struct CacheEntry {
+    response: Vec<u8>,
+    stored: Instant,
+    expiry: Instant,
+}
+
+/// DNS responses keyed by question, kept for the smallest TTL of their records.
+pub(crate) struct Cache {
+    entries: HashMap<Question, CacheEntry>,
+}
+
+impl Cache {
+    pub(crate) fn new() -> Cache {
+        Cache { entries: HashMap::new() }
+    }
+
+    /// Returns the cached response with its TTLs reduced by the time spent in the cache.
+    pub(crate) fn get(&mut self, question: &Question) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let entry = self.entries.get(question)?;
+        if entry.expiry <= now {
+            self.entries.remove(question);
+            return None;
+        }
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut response = entry.response.clone();
+        for offset in ttl_offsets(&response)? {
+            let ttl = read_u32(&response, offset)?.saturating_sub(elapsed);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    pub(crate) fn insert(&mut self, question: Question, response: Vec<u8>) {
+        let Some(offsets) = ttl_offsets(&response) else {
+            return;
+        };
+        let ttl = offsets.iter().filter_map(|offset| read_u32(&response, *offset)).min().unwrap_or(0);
+        if ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= CACHE_CAPACITY {
+            self.entries.retain(|_, entry| entry.expiry > now);
+            if self.entries.len() >= CACHE_CAPACITY {
+                self.entries.clear();
+            }
+        }
+        let entry = CacheEntry {
+            response,
+            stored: now,
+            expiry: now + Duration::from_secs(ttl as u64),
+        };
+        self.entries.insert(question, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a query for www.example.com, the question name starting at offset 12.
+    fn query() -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message
+    }
+
+    // the response to `query()` with an A record of `ttl` seconds, its name compressed, and an
+    // EDNS record.
+    fn response(ttl: u32) -> Vec<u8> {
+        let mut message = query();
+        message[2] = 0x81;
+        message[3] = 0x80;
+        message[7] = 1;
+        message[11] = 1;
+        message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&[0, 4, 93, 184, 216, 34]);
+        message.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        message
+    }
+
+    #[test]
+    fn question_of_query() {
+        let question = question(&query()).unwrap();
+        assert_eq!(question.name, "www.example.com");
+        assert_eq!(question.record_type, 1);
+    }
+
+    #[test]
+    fn name_behind_pointer() {
+        let mut message = query();
+        let offset = message.len();
+        // "mail" followed by a pointer to "example.com" of the question.
+        message.extend_from_slice(&[4, b'm', b'a', b'i', b'l', 0xc0, 16]);
+        assert_eq!(read_name(&message, offset), Some(("mail.example.com".to_string(), offset + 7)));
+        assert_eq!(skip_name(&message, offset), Some(offset + 7));
+    }
+
+    #[test]
+    fn pointer_loop_is_rejected() {
+        let mut message = query();
+        message.truncate(HEADER_LEN);
+        // the name points at itself.
+        message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert_eq!(read_name(&message, HEADER_LEN), None);
+        assert_eq!(question(&message), None);
+
+        // two labels pointing at each other.
+        message.truncate(HEADER_LEN);
+        message.extend_from_slice(&[1, b'a', 0xc0, 16, 1, b'b', 0xc0, 12]);
+        assert_eq!(read_name(&message, HEADER_LEN), None);
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        let message = query();
+        assert_eq!(id(&message[..1]), None);
+        assert_eq!(question(&message[..HEADER_LEN - 1]), None);
+        // cut inside the label "example" and before the record type.
+        assert_eq!(question(&message[..HEADER_LEN + 6]), None);
+        assert_eq!(question(&message[..message.len() - 3]), None);
+        // the record data runs past the end.
+        let response = response(60);
+        assert_eq!(ttl_offsets(&response[..response.len() - 13]), None);
+        // a pointer cut in half.
+        let mut message = message[..HEADER_LEN].to_vec();
+        message.push(0xc0);
+        assert_eq!(read_name(&message, HEADER_LEN), None);
+    }
+
+    #[test]
+    fn server_failure_keeps_id_and_question() {
+        let failure = server_failure(&query());
+        assert_eq!(id(&failure), Some(0x1234));
+        assert_eq!(failure[2..4], [0x81, 0x82]);
+        assert_eq!(failure[HEADER_LEN..], query()[HEADER_LEN..]);
+        // too short to carry the flags, returned as is.
+        assert_eq!(server_failure(&[1, 2, 3]), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn ttl_offsets_skip_edns_record() {
+        let response = response(60);
+        let offsets = ttl_offsets(&response).unwrap();
+        assert_eq!(offsets, vec![query().len() + 6]);
+        assert_eq!(read_u32(&response, offsets[0]), Some(60));
+    }
+
+    #[test]
+    fn cache_reduces_ttl() {
+        let mut cache = Cache::new();
+        let question = question(&query()).unwrap();
+        cache.insert(question.clone(), response(60));
+        assert_eq!(cache.get(&question), Some(response(60)));
+
+        let entry = cache.entries.get_mut(&question).unwrap();
+        entry.stored -= Duration::from_secs(25);
+        assert_eq!(cache.get(&question), Some(response(35)));
+
+        cache.entries.get_mut(&question).unwrap().expiry = Instant::now();
+        assert_eq!(cache.get(&question), None);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn cache_skips_zero_ttl() {
+        let mut cache = Cache::new();
+        let question = question(&query()).unwrap();
+        cache.insert(question.clone(), response(0));
+        assert_eq!(cache.get(&question), None);
+    }
+}
@@ -0,0 +1,613 @@
+#[cfg(target_family = "unix")]
+use crate::tun_callbacks::on_socket_created;
+use crate::vpn::{
+    dns::{self, Cache, Question},
+    session_info::SessionInfo,
+    upstream::{UpstreamConnector, UpstreamTransport},
+};
+use mio::{Poll, Token, Waker};
+use smoltcp::wire::IpProtocol;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_SIZE: usize = 0xffff;
+// queries waiting for the worker, further ones are answered with a server failure right away.
+const MAX_QUEUED_QUERIES: usize = 32;
+
+/// Location of a DNS-over-HTTPS server, parsed from its URL.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    tls: bool,
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    pub(crate) fn parse(url: &str) -> crate::Result<Endpoint> {
+        let invalid = || crate::Error::InvalidConfig(format!("invalid doh url {}", url));
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/dns-query"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = if let Some(literal) = authority.strip_prefix('[') {
+            let (host, port) = literal.split_once(']').ok_or_else(invalid)?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Endpoint {
+            tls,
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+struct Query {
+    session_info: SessionInfo,
+    question: Option<Question>,
+    message: Vec<u8>,
+    queued: Instant,
+    reply: Reply,
+}
+
+/// Where the response to a query goes: a UDP packet to the guest, or the stream of a DNS over TCP
+/// session.
+enum Reply {
+    Datagram,
+    Stream(Arc<Mutex<StreamState>>),
+}
+
+struct Answer {
+    query: Query,
+    response: std::io::Result<Vec<u8>>,
+}
+
+/// Answers DNS queries of the guest through a DNS-over-HTTPS server.
+///
+/// Cached answers are returned right away, other queries are sent by a worker thread which wakes
+/// up the processor once the answer is ready. DNS over TCP sessions are answered as well, through
+/// the transports of `stream_connector`, so no query of the guest leaves in plaintext. The worker sends one query at a time, so the queue
+/// is bounded: once it is full, or a query waited longer than the guest waits for it, the query is
+/// answered with a server failure instead of keeping the following ones waiting.
+pub(crate) struct Resolver {
+    queries: SyncSender<Query>,
+    answers: Receiver<Answer>,
+    cache: Cache,
+}
+
+impl Resolver {
+    pub(crate) fn new(config: &crate::DohConfig, strict_socket_setup: bool, waker: Arc<Waker>) -> crate::Result<Resolver> {
+        let client = Client::new(Endpoint::parse(&config.url)?, config.address, strict_socket_setup);
+        let (queries, query_receiver) = sync_channel::<Query>(MAX_QUEUED_QUERIES);
+        let (answer_sender, answers) = channel();
+        std::thread::spawn(move || {
+            let mut client = client;
+            while let Ok(query) = query_receiver.recv() {
+                let response = if query.queued.elapsed() < TIMEOUT {
+                    client.query(&query.message)
+                } else {
+                    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "dns query waited too long"))
+                };
+                if answer_sender.send(Answer { query, response }).is_err() {
+                    break;
+                }
+                if let Err(error) = waker.wake() {
+                    log::error!("failed to wake processor, error={:?}", error);
+                }
+            }
+            log::trace!("doh resolver stopped");
+        });
+        Ok(Resolver {
+            queries,
+            answers,
+            cache: Cache::new(),
+        })
+    }
+
+    /// Returns the cached response to `message`, or queues it for the server and returns `None`.
+    /// A server failure is returned when the queue is full.
+    pub(crate) fn resolve(&mut self, session_info: SessionInfo, message: &[u8]) -> Option<Vec<u8>> {
+        let question = dns::question(message);
+        if let Some(mut response) = question.as_ref().and_then(|question| self.cache.get(question)) {
+            dns::set_id(&mut response, dns::id(message)?);
+            return Some(response);
+        }
+        queue(&self.queries, session_info, question, message, Reply::Datagram)
+    }
+
+    /// Creates the transports of the DNS over TCP sessions, for `TunConfig::dns_upstream`: the
+    /// queries written to them are resolved like the UDP ones and their responses read back.
+    pub(crate) fn stream_connector(&self) -> UpstreamConnector {
+        let queries = self.queries.clone();
+        UpstreamConnector::new(move |session_info| {
+            if session_info.ip_protocol != IpProtocol::Tcp {
+                return Err(std::io::Error::other("doh takes udp dns queries without a session"));
+            }
+            let transport = StreamTransport {
+                session_info: *session_info,
+                queries: queries.clone(),
+                received: Vec::new(),
+                state: Arc::new(Mutex::new(StreamState::default())),
+            };
+            Ok(Box::new(transport) as Box<dyn UpstreamTransport>)
+        })
+    }
+
+    /// Returns the responses received since the last call, paired with the session they answer,
+    /// and the DNS over TCP sessions that have responses to read.
+    pub(crate) fn answers(&mut self) -> (Vec<(SessionInfo, Vec<u8>)>, Vec<SessionInfo>) {
+        let (mut answers, mut streams) = (Vec::new(), Vec::new());
+        while let Ok(Answer { query, response }) = self.answers.try_recv() {
+            let response = match response {
+                Ok(mut response) => {
+                    if let Some(question) = query.question {
+                        self.cache.insert(question, response.clone());
+                    }
+                    dns::set_id(&mut response, dns::id(&query.message).unwrap_or_default());
+                    response
+                }
+                Err(error) => {
+                    log::debug!("failed to resolve through doh, {:?} error={:?}", query.session_info, error);
+                    dns::server_failure(&query.message)
+                }
+            };
+            match query.reply {
+                Reply::Datagram => answers.push((query.session_info, response)),
+                Reply::Stream(state) => {
+                    let mut state = state.lock().unwrap();
+                    state.outstanding -= 1;
+                    if !state.closed {
+                        state.push_response(&response);
+                        streams.push(query.session_info);
+                    }
+                }
+            }
+        }
+        (answers, streams)
+    }
+}
+
+// queues `message` for the worker, a full queue is answered with a server failure right away.
+fn queue(queries: &SyncSender<Query>, session_info: SessionInfo, question: Option<Question>, message: &[u8], reply: Reply) -> Option<Vec<u8>> {
+    let query = Query {
+        session_info,
+        question,
+        message: message.to_vec(),
+        queued: Instant::now(),
+        reply,
+    };
+    match queries.try_send(query) {
+        Ok(()) => None,
+        Err(TrySendError::Full(query)) => {
+            log::debug!("too many dns queries waiting, {:?}", query.session_info);
+            Some(dns::server_failure(message))
+        }
+        Err(TrySendError::Disconnected(query)) => {
+            log::error!("doh resolver stopped, {:?}", query.session_info);
+            Some(dns::server_failure(message))
+        }
+    }
+}
+
+/// The responses of a DNS over TCP session, shared by its transport and the resolver.
+#[derive(Default)]
+struct StreamState {
+    responses: VecDeque<Vec<u8>>,
+    // queries sent to the worker and not answered yet.
+    outstanding: usize,
+    write_shutdown: bool,
+    closed: bool,
+}
+
+impl StreamState {
+    // frames `response` with its length, as DNS over TCP does.
+    fn push_response(&mut self, response: &[u8]) {
+        let Ok(len) = u16::try_from(response.len()) else {
+            log::debug!("dns response too large for tcp, len={}", response.len());
+            return;
+        };
+        let mut framed = Vec::with_capacity(2 + response.len());
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(response);
+        self.responses.push_back(framed);
+    }
+
+    // the guest sent its last query and every response was read.
+    fn is_finished(&self) -> bool {
+        self.write_shutdown && self.outstanding == 0 && self.responses.is_empty()
+    }
+}
+
+/// The upstream of a DNS over TCP session: splits the length prefixed queries of the guest out of
+/// the stream and resolves them through the worker, in place of a connection to the DNS server.
+/// The responses come without a poll event, the processor reads them when the resolver returns the
+/// session from `Resolver::answers`. Ends once the guest finished sending and all are answered.
+struct StreamTransport {
+    session_info: SessionInfo,
+    queries: SyncSender<Query>,
+    // bytes of the guest not yet making a whole query.
+    received: Vec<u8>,
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl UpstreamTransport for StreamTransport {
+    fn register_poll(&mut self, _poll: &mut Poll, _token: Token) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister_poll(&mut self, _poll: &mut Poll) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.received.extend_from_slice(bytes);
+        let mut start = 0;
+        while let Some(prefix) = self.received.get(start..start + 2) {
+            let end = start + 2 + u16::from_be_bytes([prefix[0], prefix[1]]) as usize;
+            let Some(message) = self.received.get(start + 2..end) else {
+                break;
+            };
+            let mut state = self.state.lock().unwrap();
+            let reply = Reply::Stream(self.state.clone());
+            match queue(&self.queries, self.session_info, dns::question(message), message, reply) {
+                Some(failure) => state.push_response(&failure),
+                None => state.outstanding += 1,
+            }
+            start = end;
+        }
+        self.received.drain(..start);
+        Ok(bytes.len())
+    }
+
+    fn read(&mut self, is_closed: &mut bool, callback: &mut dyn FnMut(&mut [u8]) -> std::io::Result<()>) -> std::io::Result<()> {
+        loop {
+            let (response, is_finished) = {
+                let mut state = self.state.lock().unwrap();
+                (state.responses.pop_front(), state.is_finished())
+            };
+            match response {
+                Some(mut response) => callback(&mut response)?,
+                None => {
+                    *is_closed = is_finished;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.responses.clear();
+    }
+
+    fn shutdown_write(&self) {
+        self.state.lock().unwrap().write_shutdown = true;
+    }
+
+    fn has_unannounced_data(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.responses.is_empty() || state.is_finished()
+    }
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+struct Client {
+    endpoint: Endpoint,
+    address: Option<IpAddr>,
+    strict_socket_setup: bool,
+    tls_config: Arc<rustls::ClientConfig>,
+    connection: Option<BufReader<Box<dyn Stream>>>,
+}
+
+impl Client {
+    fn new(endpoint: Endpoint, address: Option<IpAddr>, strict_socket_setup: bool) -> Client {
+        let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions are supported")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Client {
+            endpoint,
+            address,
+            strict_socket_setup,
+            tls_config: Arc::new(tls_config),
+            connection: None,
+        }
+    }
+
+    fn query(&mut self, message: &[u8]) -> std::io::Result<Vec<u8>> {
+        // DoH asks for a zero id so that equal queries are cacheable by HTTP caches.
+        let mut message = message.to_vec();
+        dns::set_id(&mut message, 0);
+
+        if self.connection.is_some() {
+            match self.post(&message) {
+                Ok(response) => return Ok(response),
+                // the server may have closed the kept alive connection meanwhile.
+                Err(error) => log::trace!("failed to reuse doh connection, error={:?}", error),
+            }
+        }
+        self.post(&message)
+    }
+
+    fn post(&mut self, message: &[u8]) -> std::io::Result<Vec<u8>> {
+        if self.connection.is_none() {
+            self.connection = Some(BufReader::new(self.connect()?));
+        }
+        let connection = self.connection.as_mut().ok_or_else(|| std::io::Error::other("no doh connection"))?;
+        let result = Self::exchange(connection, &self.endpoint, message);
+        match result {
+            Ok((response, true)) => Ok(response),
+            Ok((response, false)) => {
+                self.connection = None;
+                Ok(response)
+            }
+            Err(error) => {
+                self.connection = None;
+                Err(error)
+            }
+        }
+    }
+
+    fn connect(&self) -> std::io::Result<Box<dyn Stream>> {
+        let address = match self.address {
+            Some(address) => SocketAddr::new(address, self.endpoint.port),
+            None => (self.endpoint.host.as_str(), self.endpoint.port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| std::io::Error::other(format!("failed to resolve {}", self.endpoint.host)))?,
+        };
+
+        let socket = socket2::Socket::new(socket2::Domain::for_address(address), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+
+        #[cfg(target_family = "unix")]
+        if !on_socket_created(socket.as_raw_fd()) && self.strict_socket_setup {
+            return Err(std::io::Error::other(crate::Error::SocketSetup(address)));
+        }
+
+        log::trace!("connecting to doh server, address={:?}", address);
+        socket.connect_timeout(&address.into(), TIMEOUT)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+        let stream = TcpStream::from(socket);
+
+        if !self.endpoint.tls {
+            return Ok(Box::new(stream));
+        }
+        let server_name = rustls::pki_types::ServerName::try_from(self.endpoint.host.clone()).map_err(std::io::Error::other)?;
+        let connection = rustls::ClientConnection::new(self.tls_config.clone(), server_name).map_err(std::io::Error::other)?;
+        Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+    }
+
+    // returns the response body and whether the connection can be kept alive.
+    fn exchange(connection: &mut BufReader<Box<dyn Stream>>, endpoint: &Endpoint, message: &[u8]) -> std::io::Result<(Vec<u8>, bool)> {
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            endpoint.path,
+            endpoint.authority,
+            message.len()
+        );
+        let stream = connection.get_mut();
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(message)?;
+        stream.flush()?;
+
+        let status_line = Self::read_line(connection)?;
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut keep_alive = status_line.starts_with("HTTP/1.1");
+        loop {
+            let line = Self::read_line(connection)?;
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse::<usize>().ok(),
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                _ => {}
+            }
+        }
+
+        let body = if chunked {
+            Self::read_chunked(connection)?
+        } else if let Some(content_length) = content_length.filter(|len| *len <= MAX_RESPONSE_SIZE) {
+            let mut body = vec![0; content_length];
+            connection.read_exact(&mut body)?;
+            body
+        } else {
+            return Err(std::io::Error::other("doh response without valid length"));
+        };
+        if status != "200" {
+            return Err(std::io::Error::other(format!("doh server replied {}", status_line)));
+        }
+        Ok((body, keep_alive))
+    }
+
+    fn read_chunked(connection: &mut BufReader<Box<dyn Stream>>) -> std::io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = Self::read_line(connection)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(std::io::Error::other)?;
+            if body.len() + size > MAX_RESPONSE_SIZE {
+                return Err(std::io::Error::other("doh response is too large"));
+            }
+            if size == 0 {
+                // skip trailers up to the final empty line.
+                while !Self::read_line(connection)?.is_empty() {}
+                return Ok(body);
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            connection.read_exact(&mut body[start..])?;
+            Self::read_line(connection)?;
+        }
+    }
+
+    fn read_line(connection: &mut BufReader<Box<dyn Stream>>) -> std::io::Result<String> {
+        let mut line = String::new();
+        if connection.read_line(&mut line)? == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "doh connection closed"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn endpoint_defaults() {
+        let endpoint = Endpoint::parse("https://dns.example").unwrap();
+        assert!(endpoint.tls);
+        assert_eq!(
+            (endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()),
+            ("dns.example", 443, "/dns-query")
+        );
+        assert_eq!(endpoint.authority, "dns.example");
+
+        let endpoint = Endpoint::parse("http://10.0.0.3:8053/resolve?ct").unwrap();
+        assert!(!endpoint.tls);
+        assert_eq!(
+            (endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()),
+            ("10.0.0.3", 8053, "/resolve?ct")
+        );
+        assert_eq!(Endpoint::parse("http://10.0.0.3/").unwrap().port, 80);
+    }
+
+    #[test]
+    fn endpoint_ipv6_literal() {
+        let endpoint = Endpoint::parse("https://[2606:4700:4700::1111]/dns-query").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("2606:4700:4700::1111", 443));
+        // the Host header keeps the brackets.
+        assert_eq!(endpoint.authority, "[2606:4700:4700::1111]");
+
+        let endpoint = Endpoint::parse("https://[::1]:8443").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()), ("::1", 8443, "/dns-query"));
+    }
+
+    #[test]
+    fn endpoint_invalid() {
+        for url in [
+            "dns.example/dns-query",
+            "ftp://dns.example",
+            "https://",
+            "https://:443/",
+            "https://[::1/",
+            "https://dns.example:dns/",
+            "https://dns.example:65536/",
+        ] {
+            assert!(Endpoint::parse(url).is_err(), "{}", url);
+        }
+    }
+
+    fn stream(capacity: usize) -> (StreamTransport, Receiver<Query>) {
+        let (queries, receiver) = sync_channel(capacity);
+        let session_info = SessionInfo {
+            source: "10.0.0.2:40000".parse::<SocketAddr>().unwrap(),
+            destination: "10.0.0.4:53".parse::<SocketAddr>().unwrap(),
+            ip_protocol: IpProtocol::Tcp,
+            ip_version: smoltcp::wire::IpVersion::Ipv4,
+        };
+        let transport = StreamTransport {
+            session_info,
+            queries,
+            received: Vec::new(),
+            state: Arc::new(Mutex::new(StreamState::default())),
+        };
+        (transport, receiver)
+    }
+
+    fn framed(message: &[u8]) -> Vec<u8> {
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(message);
+        framed
+    }
+
+    #[test]
+    fn stream_splits_queries() {
+        let (mut transport, receiver) = stream(4);
+        let first = [0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let second = [0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut bytes = framed(&first);
+        bytes.extend_from_slice(&framed(&second));
+        // the first query and the length of the second in one write, the rest in another.
+        assert_eq!(transport.write(&bytes[..16]).unwrap(), 16);
+        assert_eq!(receiver.try_recv().unwrap().message, first);
+        assert!(receiver.try_recv().is_err());
+        transport.write(&bytes[16..]).unwrap();
+        assert_eq!(receiver.try_recv().unwrap().message, second);
+        assert!(transport.received.is_empty());
+        assert_eq!(transport.state.lock().unwrap().outstanding, 2);
+    }
+
+    #[test]
+    fn stream_fails_queries_beyond_queue() {
+        let (mut transport, _receiver) = stream(1);
+        let query = [0, 7, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut bytes = framed(&query);
+        bytes.extend_from_slice(&framed(&query));
+        transport.write(&bytes).unwrap();
+        transport.shutdown_write();
+        assert!(transport.has_unannounced_data());
+
+        let (mut responses, mut is_closed) = (Vec::new(), false);
+        transport
+            .read(&mut is_closed, &mut |bytes| {
+                responses.push(bytes.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(responses, vec![framed(&dns::server_failure(&query))]);
+        // the queued query is still to be answered.
+        assert!(!is_closed);
+        assert!(!transport.has_unannounced_data());
+    }
+}
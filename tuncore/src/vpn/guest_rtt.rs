@@ -0,0 +1,53 @@
+use smoltcp::wire::TcpSeqNumber;
+use std::time::{Duration, Instant};
+
+/// Round trip time toward the guest, measured from the segments sent to it to its ACKs, as smoltcp
+/// keeps its own estimate private.
+///
+/// Like the estimator of RFC 6298 a single segment is timed at a time, and the sample is discarded
+/// when that segment is retransmitted, since its ACK could answer either copy (Karn's algorithm).
+/// A guest delaying its ACKs adds the delay to the samples.
+#[derive(Debug, Default)]
+pub(crate) struct GuestRtt {
+    // end of the timed segment in sequence space, and when it was sent.
+    timed: Option<(TcpSeqNumber, Instant)>,
+    // end of the highest segment sent, a segment starting below it is a retransmission.
+    sent: Option<TcpSeqNumber>,
+    smoothed: Option<Duration>,
+}
+
+impl GuestRtt {
+    pub(crate) fn on_sent(&mut self, seq_number: TcpSeqNumber, segment_len: usize, now: Instant) {
+        if segment_len == 0 {
+            // a bare ACK is not acknowledged itself.
+            return;
+        }
+        let end = seq_number + segment_len;
+        if self.sent.is_some_and(|sent| seq_number < sent) {
+            if self.timed.is_some_and(|(timed_end, _)| seq_number < timed_end) {
+                self.timed = None;
+            }
+        } else if self.timed.is_none() {
+            self.timed = Some((end, now));
+        }
+        if self.sent.is_none_or(|sent| end > sent) {
+            self.sent = Some(end);
+        }
+    }
+
+    pub(crate) fn on_ack(&mut self, ack_number: TcpSeqNumber, now: Instant) {
+        let Some((end, sent_at)) = self.timed else {
+            return;
+        };
+        if ack_number >= end {
+            let sample = now.saturating_duration_since(sent_at);
+            // RFC 6298 2.3, SRTT <- 7/8 SRTT + 1/8 R'.
+            self.smoothed = Some(self.smoothed.map_or(sample, |smoothed| (smoothed * 7 + sample) / 8));
+            self.timed = None;
+        }
+    }
+
+    pub(crate) fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+}
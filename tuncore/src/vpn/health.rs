@@ -0,0 +1,132 @@
+use crate::vpn::session_info::SessionInfo;
+use std::time::Duration;
+
+/// Upper bounds of the connect latency buckets, slower connects fall into a last open bucket.
+pub const CONNECT_LATENCY_BOUNDS: [Duration; 7] = [
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// Counts of upstream TCP connects by the time they took to be established.
+///
+/// `counts[i]` holds the connects faster than `CONNECT_LATENCY_BOUNDS[i]` but not faster than the
+/// previous bound, the last count holds the connects slower than every bound.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectLatencyHistogram {
+    pub counts: [u64; CONNECT_LATENCY_BOUNDS.len() + 1],
+}
+
+impl ConnectLatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let bucket = CONNECT_LATENCY_BOUNDS
+            .iter()
+            .position(|bound| latency < *bound)
+            .unwrap_or(CONNECT_LATENCY_BOUNDS.len());
+        self.counts[bucket] += 1;
+    }
+
+    /// Number of connects recorded in all buckets.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// Snapshot of the processor state, as returned by `tun::health()`.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProcessorHealth {
+    pub sessions: usize,
+    pub connect_latency: ConnectLatencyHistogram,
+    /// Sessions by the time from their creation to the first byte from the server, in the buckets
+    /// of `connect_latency`. Compared with it, it tells a slow server from a slow connect.
+    pub first_byte_latency: ConnectLatencyHistogram,
+    /// Packets from the guest dropped because they were cut within their TCP or UDP header.
+    pub short_packets: u64,
+    /// TCP sessions whose upstream connect is still in progress.
+    pub half_open_connections: usize,
+    /// Sessions not created because their destination had `TunConfig::max_sessions_per_destination`
+    /// sessions already.
+    pub destination_limited: u64,
+    /// Sessions not created because the upstream connect found no local address and port left for
+    /// the destination (EADDRNOTAVAIL), e.g. with the ephemeral ports held in TIME_WAIT. See
+    /// `TunConfig::upstream_port_range`.
+    pub address_not_available: u64,
+    /// Bytes held in the session buffers, see `TunConfig::max_buffered_bytes`.
+    pub buffered_bytes: usize,
+    /// Sessions not reading from the guest and the server while the buffers are over budget.
+    pub throttled_sessions: usize,
+    /// Moving average of the sessions created per second.
+    pub sessions_per_second: f64,
+    /// Moving average of the bytes of IP packets exchanged with the guest per second, both ways.
+    pub bytes_per_second: f64,
+}
+
+/// Traffic counters of a single TCP or UDP session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SessionCounters {
+    pub session_info: SessionInfo,
+    /// Bytes of the IP packets from the guest.
+    pub bytes_from_guest: u64,
+    /// Bytes of the IP packets to the guest.
+    pub bytes_to_guest: u64,
+}
+
+/// The counters as they were right before `tun::reset_stats()` zeroed them, so nothing counted
+/// between an earlier read and the reset is lost.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StatsSnapshot {
+    pub health: ProcessorHealth,
+    /// Counters of the TCP and UDP sessions, empty unless those were reset as well.
+    pub sessions: Vec<SessionCounters>,
+}
+
+/// Internal state of a single TCP or UDP session, as returned by `tun::dump_session()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SessionDump {
+    pub session_info: SessionInfo,
+    /// Poll token of the upstream socket, as in the trace logs.
+    pub token: usize,
+    /// State of the smoltcp socket facing the guest, e.g. `ESTABLISHED`, unset for UDP.
+    pub tcp_state: Option<String>,
+    /// Bytes (TCP) or datagrams (UDP) read from the guest and not yet written to the server.
+    pub pending_to_server: usize,
+    /// Bytes (TCP) or datagrams (UDP) read from the server and not yet handed to smoltcp.
+    pub pending_to_client: usize,
+    /// Bytes in the send queue of the smoltcp socket, sent but not yet acknowledged by the guest.
+    pub smoltcp_send_queue: usize,
+    /// Bytes in the receive queue of the smoltcp socket, not yet read by the session.
+    pub smoltcp_recv_queue: usize,
+    /// Whether the upstream connect completed, successfully or not.
+    pub connected: bool,
+    pub connect_failed: bool,
+    pub upstream_closed: bool,
+    /// Whether the FIN of the guest was passed on to the server, which may still answer.
+    pub upstream_write_shutdown: bool,
+    /// Whether server data is left unread for lack of room towards the guest.
+    pub continue_read: bool,
+    /// Time since the session was created.
+    pub age: Duration,
+    /// Time since the last traffic of the session.
+    pub idle: Duration,
+    /// Time until the session expires, unset for TCP sessions before their close.
+    pub expires_in: Option<Duration>,
+    /// Bytes of the IP packets from the guest.
+    pub bytes_from_guest: u64,
+    /// Bytes of the IP packets to the guest.
+    pub bytes_to_guest: u64,
+    /// Smoothed round trip time toward the guest, from the segments sent to it to its ACKs. Unset
+    /// for UDP and before the first sample. Compared with the connect latency of the server it
+    /// tells which side of the tunnel is slow.
+    pub guest_rtt: Option<Duration>,
+    /// See `TunConfig::port_priorities`.
+    pub priority: u8,
+}
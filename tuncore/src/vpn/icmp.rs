@@ -0,0 +1,162 @@
+use crate::vpn::session_info::SessionInfo;
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        Icmpv4DstUnreachable, Icmpv4Message, Icmpv4Packet, Icmpv4Repr, Icmpv6DstUnreachable, Icmpv6Message, Icmpv6Packet, Icmpv6Repr, IpAddress, IpProtocol,
+        IpVersion, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr,
+    },
+};
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+};
+
+const IPV4_MIN_MTU: usize = 576;
+const IPV6_MIN_MTU: usize = 1280;
+const ICMP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// What an ICMP destination unreachable message reports as unreachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Unreachable {
+    Network,
+    Host,
+    Port,
+}
+
+impl Unreachable {
+    /// The unreachable reported by an upstream socket error, which the kernel derives from the
+    /// ICMP error it received.
+    pub(crate) fn from_error(error: &std::io::Error) -> Option<Unreachable> {
+        match error.kind() {
+            ErrorKind::NetworkUnreachable => Some(Unreachable::Network),
+            ErrorKind::HostUnreachable => Some(Unreachable::Host),
+            ErrorKind::ConnectionRefused => Some(Unreachable::Port),
+            _ => None,
+        }
+    }
+}
+
+/// Builds an ICMP port unreachable message answering the raw IP packet `bytes`.
+pub(crate) fn port_unreachable(bytes: &[u8], hop_limit: u8) -> crate::Result<Vec<u8>> {
+    unreachable(bytes, Unreachable::Port, hop_limit)
+}
+
+/// Builds an ICMP destination unreachable message for `reason` answering the raw IP packet `bytes`.
+pub(crate) fn unreachable(bytes: &[u8], reason: Unreachable, hop_limit: u8) -> crate::Result<Vec<u8>> {
+    match IpVersion::of_packet(bytes)? {
+        IpVersion::Ipv4 => ipv4_unreachable(bytes, reason, hop_limit),
+        IpVersion::Ipv6 => ipv6_unreachable(bytes, reason, hop_limit),
+    }
+}
+
+fn ipv4_unreachable(bytes: &[u8], reason: Unreachable, hop_limit: u8) -> crate::Result<Vec<u8>> {
+    let checksum = ChecksumCapabilities::default();
+    let packet = Ipv4Packet::new_checked(bytes)?;
+    let header = Ipv4Repr::parse(&packet, &checksum)?;
+
+    // quote as much of the offending packet as fits in the minimum MTU.
+    let max_data_len = IPV4_MIN_MTU - header.buffer_len() * 2 - ICMP_HEADER_LEN;
+    let data = &packet.payload()[..packet.payload().len().min(max_data_len)];
+    let icmp_repr = Icmpv4Repr::DstUnreachable {
+        reason: match reason {
+            Unreachable::Network => Icmpv4DstUnreachable::NetUnreachable,
+            Unreachable::Host => Icmpv4DstUnreachable::HostUnreachable,
+            Unreachable::Port => Icmpv4DstUnreachable::PortUnreachable,
+        },
+        header,
+        data,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr: header.dst_addr,
+        dst_addr: header.src_addr,
+        next_header: IpProtocol::Icmp,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit,
+    };
+
+    let mut buffer = vec![0; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    ip_repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer[..]), &checksum);
+    icmp_repr.emit(&mut Icmpv4Packet::new_unchecked(&mut buffer[ip_repr.buffer_len()..]), &checksum);
+    Ok(buffer)
+}
+
+fn ipv6_unreachable(bytes: &[u8], reason: Unreachable, hop_limit: u8) -> crate::Result<Vec<u8>> {
+    let checksum = ChecksumCapabilities::default();
+    let packet = Ipv6Packet::new_checked(bytes)?;
+    let header = Ipv6Repr::parse(&packet)?;
+
+    let max_data_len = IPV6_MIN_MTU - header.buffer_len() * 2 - ICMP_HEADER_LEN;
+    let data = &packet.payload()[..packet.payload().len().min(max_data_len)];
+    let icmp_repr = Icmpv6Repr::DstUnreachable {
+        reason: match reason {
+            Unreachable::Network => Icmpv6DstUnreachable::NoRoute,
+            Unreachable::Host => Icmpv6DstUnreachable::AddrUnreachable,
+            Unreachable::Port => Icmpv6DstUnreachable::PortUnreachable,
+        },
+        header,
+        data,
+    };
+    let ip_repr = Ipv6Repr {
+        src_addr: header.dst_addr,
+        dst_addr: header.src_addr,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit,
+    };
+
+    let mut buffer = vec![0; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    ip_repr.emit(&mut Ipv6Packet::new_unchecked(&mut buffer[..]));
+    icmp_repr.emit(
+        &IpAddress::Ipv6(ip_repr.src_addr),
+        &IpAddress::Ipv6(ip_repr.dst_addr),
+        &mut Icmpv6Packet::new_unchecked(&mut buffer[ip_repr.buffer_len()..]),
+        &checksum,
+    );
+    Ok(buffer)
+}
+
+/// The UDP session whose port the guest reports unreachable with the ICMP error `bytes`, e.g. as its
+/// kernel answers a datagram of the server once the app that opened the session is gone.
+///
+/// The quoted datagram is the one sent to the guest, so the session is the quote seen from the other
+/// end. Only its addresses and ports are read, the quote may be cut anywhere after them.
+pub(crate) fn closed_udp_session(bytes: &[u8]) -> Option<SessionInfo> {
+    let (protocol, source, destination, udp) = match IpVersion::of_packet(bytes).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(bytes).ok()?;
+            let icmp = packet.payload();
+            let port_unreachable = (u8::from(Icmpv4Message::DstUnreachable), u8::from(Icmpv4DstUnreachable::PortUnreachable));
+            if packet.next_header() != IpProtocol::Icmp || icmp.len() < ICMP_HEADER_LEN || (icmp[0], icmp[1]) != port_unreachable {
+                return None;
+            }
+            let quote = &icmp[ICMP_HEADER_LEN..];
+            let header_len = usize::from(quote.first()? & 0x0f) * 4;
+            if header_len < IPV4_HEADER_LEN || quote.len() < header_len {
+                return None;
+            }
+            let source: [u8; 4] = quote[12..16].try_into().ok()?;
+            let destination: [u8; 4] = quote[16..20].try_into().ok()?;
+            (quote[9], IpAddr::from(source), IpAddr::from(destination), &quote[header_len..])
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(bytes).ok()?;
+            let icmp = packet.payload();
+            let port_unreachable = (u8::from(Icmpv6Message::DstUnreachable), u8::from(Icmpv6DstUnreachable::PortUnreachable));
+            if packet.next_header() != IpProtocol::Icmpv6 || icmp.len() < ICMP_HEADER_LEN + IPV6_HEADER_LEN || (icmp[0], icmp[1]) != port_unreachable {
+                return None;
+            }
+            let quote = &icmp[ICMP_HEADER_LEN..];
+            let source: [u8; 16] = quote[8..24].try_into().ok()?;
+            let destination: [u8; 16] = quote[24..40].try_into().ok()?;
+            (quote[6], IpAddr::from(source), IpAddr::from(destination), &quote[IPV6_HEADER_LEN..])
+        }
+    };
+    if IpProtocol::from(protocol) != IpProtocol::Udp || udp.len() < 4 {
+        return None;
+    }
+    let source = SocketAddr::new(source, u16::from_be_bytes([udp[0], udp[1]]));
+    let destination = SocketAddr::new(destination, u16::from_be_bytes([udp[2], udp[3]]));
+    Some(SessionInfo::from_addresses(IpProtocol::Udp, destination, source))
+}
@@ -0,0 +1,47 @@
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr},
+};
+use std::net::IpAddr;
+
+/// DSCP of the raw IP packet `bytes`, the upper six bits of its type of service or traffic class.
+pub(crate) fn dscp(bytes: &[u8]) -> Option<u8> {
+    match IpVersion::of_packet(bytes).ok()? {
+        IpVersion::Ipv4 => Ipv4Packet::new_checked(bytes).ok().map(|packet| packet.dscp()),
+        IpVersion::Ipv6 => Ipv6Packet::new_checked(bytes).ok().map(|packet| packet.traffic_class() >> 2),
+    }
+}
+
+/// Builds a raw IP packet carrying `payload` of `ip_protocol` as is from `source` to `destination`.
+pub(crate) fn build(ip_protocol: IpProtocol, source: IpAddr, destination: IpAddr, payload: &[u8], hop_limit: u8) -> crate::Result<Vec<u8>> {
+    let checksum = ChecksumCapabilities::default();
+    let (mut buffer, header_len) = match (source, destination) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            let ip_repr = Ipv4Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                next_header: ip_protocol,
+                payload_len: payload.len(),
+                hop_limit,
+            };
+            let mut buffer = vec![0; ip_repr.buffer_len() + payload.len()];
+            ip_repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer[..]), &checksum);
+            (buffer, ip_repr.buffer_len())
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            let ip_repr = Ipv6Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                next_header: ip_protocol,
+                payload_len: payload.len(),
+                hop_limit,
+            };
+            let mut buffer = vec![0; ip_repr.buffer_len() + payload.len()];
+            ip_repr.emit(&mut Ipv6Packet::new_unchecked(&mut buffer[..]));
+            (buffer, ip_repr.buffer_len())
+        }
+        _ => return Err(crate::Error::from("mismatching ip versions of ip packet")),
+    };
+    buffer[header_len..].copy_from_slice(payload);
+    Ok(buffer)
+}
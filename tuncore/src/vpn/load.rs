@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+// the averages follow a lasting change of load within a few time constants, while a single busy
+// loop iteration barely moves them.
+const TIME_CONSTANT: Duration = Duration::from_secs(10);
+
+/// Exponentially weighted moving averages of the rate of new sessions and of the bytes exchanged
+/// with the guest, a smoothed sense of load rather than the instantaneous one.
+#[derive(Debug)]
+pub(crate) struct Load {
+    sessions_per_second: f64,
+    bytes_per_second: f64,
+    sessions: u64,
+    bytes: u64,
+    updated: Instant,
+}
+
+impl Load {
+    pub(crate) fn new(now: Instant) -> Load {
+        Load {
+            sessions_per_second: 0.0,
+            bytes_per_second: 0.0,
+            sessions: 0,
+            bytes: 0,
+            updated: now,
+        }
+    }
+
+    pub(crate) fn record_session(&mut self) {
+        self.sessions += 1;
+    }
+
+    pub(crate) fn record_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    /// Folds the sessions and bytes recorded since the previous update into the averages.
+    pub(crate) fn update(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        if elapsed == 0.0 {
+            // keep the counts for the next update rather than divide by zero.
+            return;
+        }
+        // the weight of the new sample grows with the time it covers, so the averages do not
+        // depend on how often the loop runs.
+        let weight = 1.0 - (-elapsed / TIME_CONSTANT.as_secs_f64()).exp();
+        self.sessions_per_second += weight * (self.sessions as f64 / elapsed - self.sessions_per_second);
+        self.bytes_per_second += weight * (self.bytes as f64 / elapsed - self.bytes_per_second);
+        self.sessions = 0;
+        self.bytes = 0;
+        self.updated = now;
+    }
+
+    pub(crate) fn sessions_per_second(&self) -> f64 {
+        self.sessions_per_second
+    }
+
+    pub(crate) fn bytes_per_second(&self) -> f64 {
+        self.bytes_per_second
+    }
+}
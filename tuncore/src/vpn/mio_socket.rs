@@ -1,48 +1,189 @@
 #[cfg(target_family = "unix")]
-use crate::tun_callbacks::on_socket_created;
+use crate::tun_callbacks::{on_socket_created, on_upstream_fd};
+use crate::vpn::{
+    session_error::{self, SessionErrorCause},
+    session_info::SessionInfo,
+};
+#[cfg(unix)]
+use mio::unix::SourceFd;
 use mio::{Interest, Poll, Token};
-use smoltcp::wire::{IpProtocol, IpVersion};
-use std::net::{Shutdown, SocketAddr};
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet};
 #[cfg(unix)]
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 #[cfg(windows)]
-use std::os::windows::io::{AsRawSocket, FromRawSocket};
+use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// the ports of `TunConfig::upstream_port_range` tried for a socket before falling back to an
+// ephemeral one.
+const PORT_RANGE_ATTEMPTS: usize = 16;
+
+// the offset into `TunConfig::upstream_port_range` of the port tried next, so consecutive sockets
+// do not contend for the same ports.
+static NEXT_RANGE_PORT: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug)]
 pub(crate) struct Socket {
-    _socket: ::socket2::Socket, // Need to retain so socket does not get closed.
     connection: Connection,
+    max_datagram_size: usize,
+    // reused by every datagram read, allocated by the first one. One spare byte tells an oversized
+    // datagram apart from one filling the buffer exactly.
+    datagram_buffer: Vec<u8>,
 }
 
 #[derive(Debug)]
 enum Connection {
     Tcp(::mio::net::TcpStream),
     Udp(::mio::net::UdpSocket),
+    /// A raw IP socket of a protocol other than TCP and UDP, see `UnsupportedProtocols::ForwardRaw`.
+    /// IPv4 raw sockets receive the IP header along with the payload, IPv6 ones the payload only.
+    Raw(::socket2::Socket, IpVersion),
+    /// An unconnected UDP socket sending to a multicast group or the broadcast address, and
+    /// receiving the answers of any responder, see `TunConfig::forward_multicast`.
+    Multicast(::mio::net::UdpSocket, SocketAddr),
+    /// The in-memory echo of `Upstream::Loopback`, holding what was written until it is read back.
+    Loopback(VecDeque<Vec<u8>>),
 }
 
 impl Socket {
-    pub(crate) fn new(ip_protocol: IpProtocol, ip_version: IpVersion, remote_address: SocketAddr) -> std::io::Result<Socket> {
-        let socket = Self::create_socket(&ip_protocol, &ip_version)?;
+    pub(crate) fn new(session_info: &SessionInfo, config: &crate::TunConfig) -> crate::Result<Socket> {
+        let (ip_protocol, ip_version) = (session_info.ip_protocol, session_info.ip_version);
+        let (source_port, remote_address) = (session_info.source.port(), session_info.destination);
+        if *config.upstream_for(session_info) == crate::Upstream::Loopback {
+            return Ok(Socket {
+                connection: Connection::Loopback(VecDeque::new()),
+                max_datagram_size: config.udp_max_datagram_size,
+                datagram_buffer: Vec::new(),
+            });
+        }
 
         #[cfg(target_family = "unix")]
-        on_socket_created(socket.as_raw_fd());
+        if matches!(ip_protocol, IpProtocol::Tcp | IpProtocol::Udp) {
+            if let Some(fd) = on_upstream_fd(session_info) {
+                return Self::adopt(fd, session_info, config);
+            }
+        }
+
+        // a port of `TunConfig::upstream_port_range` may be bound by a connection to the same
+        // destination already, the connect then fails and another port is tried on a new socket.
+        let mut attempts = PORT_RANGE_ATTEMPTS;
+        let socket = loop {
+            let socket = Self::create_socket(&ip_protocol, &ip_version)?;
+            Self::set_buffer_sizes(&socket, config);
+            Self::set_dscp(&socket, &ip_protocol, &ip_version, config);
+            Self::set_keepalive(&socket, &ip_protocol, config);
+            let range_bound = !Self::bind_source_port(&socket, &ip_protocol, &ip_version, source_port, config)
+                && Self::bind_port_range(&socket, &ip_protocol, &ip_version, config);
+
+            #[cfg(target_family = "unix")]
+            if !on_socket_created(socket.as_raw_fd()) {
+                session_error::report(Some(*session_info), SessionErrorCause::SocketSetupFailed);
+                if config.strict_socket_setup {
+                    return Err(crate::Error::SocketSetup(remote_address));
+                }
+            }
+
+            let socket_address = ::socket2::SockAddr::from(Self::scoped_address(remote_address, config));
+
+            log::trace!("connecting to host, address={:?}", remote_address);
 
-        let socket_address = ::socket2::SockAddr::from(remote_address);
+            match socket.connect(&socket_address) {
+                Ok(()) => break socket,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock || error.raw_os_error() == Some(libc::EINPROGRESS) => break socket,
+                Err(error) if error.kind() == std::io::ErrorKind::AddrNotAvailable && range_bound && attempts > 1 => {
+                    log::debug!("port of range taken for the destination, retrying, address={:?}", remote_address);
+                    attempts -= 1;
+                }
+                Err(error) => {
+                    log::error!("failed to connect to host, error={:?} address={:?}", error, remote_address);
+                    return Err(error.into());
+                }
+            }
+        };
+
+        let connection = Self::create_connection(&ip_protocol, &ip_version, socket)?;
 
-        log::trace!("connecting to host, address={:?}", remote_address);
+        Ok(Socket {
+            connection,
+            max_datagram_size: config.udp_max_datagram_size,
+            datagram_buffer: Vec::new(),
+        })
+    }
 
-        if let Err(error) = socket.connect(&socket_address) {
-            if error.kind() == std::io::ErrorKind::WouldBlock || error.raw_os_error() == Some(libc::EINPROGRESS) {
-                // do nothing.
-            } else {
-                log::error!("failed to connect to host, error={:?} address={:?}", error, remote_address);
-                return Err(error);
+    /// The socket of a multicast or broadcast UDP session, which always goes to the network
+    /// directly, whatever `TunConfig::upstream`.
+    pub(crate) fn new_multicast(session_info: &SessionInfo, config: &crate::TunConfig) -> crate::Result<Socket> {
+        let ip_version = session_info.ip_version;
+        let socket = Self::create_socket(&IpProtocol::Udp, &ip_version)?;
+        Self::set_buffer_sizes(&socket, config);
+        Self::set_dscp(&socket, &IpProtocol::Udp, &ip_version, config);
+        Self::join_group(&socket, session_info.destination.ip(), config)?;
+        // the answers come to the port of the query, from whichever responders there are.
+        socket.bind(&Self::unspecified_address(&ip_version, 0).into())?;
+
+        #[cfg(target_family = "unix")]
+        if !on_socket_created(socket.as_raw_fd()) {
+            session_error::report(Some(*session_info), SessionErrorCause::SocketSetupFailed);
+            if config.strict_socket_setup {
+                return Err(crate::Error::SocketSetup(session_info.destination));
             }
         }
 
-        let connection = Self::create_connection(&ip_protocol, &socket)?;
+        let group = Self::scoped_address(session_info.destination, config);
+        let udp_socket = ::mio::net::UdpSocket::from_std(std::net::UdpSocket::from(socket));
+        Ok(Socket {
+            connection: Connection::Multicast(udp_socket, group),
+            max_datagram_size: config.udp_max_datagram_size,
+            datagram_buffer: Vec::new(),
+        })
+    }
+
+    /// Takes over the file descriptor supplied by `tun_callbacks::set_upstream_fd_callback`.
+    #[cfg(target_family = "unix")]
+    fn adopt(fd: std::os::unix::io::RawFd, session_info: &SessionInfo, config: &crate::TunConfig) -> crate::Result<Socket> {
+        let (ip_protocol, ip_version, remote_address) = (session_info.ip_protocol, session_info.ip_version, session_info.destination);
+        // owned from here on, an error closes it along with the socket.
+        let socket = unsafe { ::socket2::Socket::from_raw_fd(fd) };
+        let expected_type = match ip_protocol {
+            IpProtocol::Tcp => ::socket2::Type::STREAM,
+            _ => ::socket2::Type::DGRAM,
+        };
+        if socket.r#type()? != expected_type {
+            log::error!("supplied upstream socket is of another type, fd={} {:?}", fd, session_info);
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+        }
+        socket.set_nonblocking(true)?;
+
+        // a socket connected to a proxy keeps its peer, only one without a peer is connected here.
+        if let Err(error) = socket.peer_addr() {
+            if error.kind() != std::io::ErrorKind::NotConnected {
+                return Err(error.into());
+            }
+            log::trace!("connecting supplied upstream socket, fd={} address={:?}", fd, remote_address);
+            let socket_address = ::socket2::SockAddr::from(Self::scoped_address(remote_address, config));
+            match socket.connect(&socket_address) {
+                Ok(()) => {}
+                // a TCP connect still in progress has no peer either, it goes on with EALREADY.
+                Err(error)
+                    if error.kind() == std::io::ErrorKind::WouldBlock
+                        || error.raw_os_error() == Some(libc::EINPROGRESS)
+                        || error.raw_os_error() == Some(libc::EALREADY) => {}
+                Err(error) => {
+                    log::error!("failed to connect to host, error={:?} address={:?}", error, remote_address);
+                    return Err(error.into());
+                }
+            }
+        }
 
-        Ok(Socket { _socket: socket, connection })
+        Ok(Socket {
+            connection: Self::create_connection(&ip_protocol, &ip_version, socket)?,
+            max_datagram_size: config.udp_max_datagram_size,
+            datagram_buffer: Vec::new(),
+        })
     }
 
     pub(crate) fn register_poll(&mut self, poll: &mut Poll, token: Token) -> std::io::Result<()> {
@@ -52,23 +193,41 @@ impl Socket {
                 poll.registry().register(connection, token, interests)
             }
             Connection::Udp(connection) => {
-                let interests = Interest::READABLE;
+                // a send may fail with WouldBlock as well, the writable event retries the queued datagrams.
+                let interests = Interest::READABLE | Interest::WRITABLE;
                 poll.registry().register(connection, token, interests)
             }
+            Connection::Multicast(connection, _) => poll.registry().register(connection, token, Interest::READABLE),
+            #[cfg(unix)]
+            Connection::Raw(socket, _) => poll.registry().register(&mut SourceFd(&socket.as_raw_fd()), token, Interest::READABLE),
+            #[cfg(windows)]
+            Connection::Raw(_, _) => Err(std::io::Error::from(std::io::ErrorKind::Unsupported)),
+            Connection::Loopback(_) => Ok(()),
         }
     }
 
     pub(crate) fn deregister_poll(&mut self, poll: &mut Poll) -> std::io::Result<()> {
         match &mut self.connection {
             Connection::Tcp(connection) => poll.registry().deregister(connection),
-            Connection::Udp(connection) => poll.registry().deregister(connection),
+            Connection::Udp(connection) | Connection::Multicast(connection, _) => poll.registry().deregister(connection),
+            #[cfg(unix)]
+            Connection::Raw(socket, _) => poll.registry().deregister(&mut SourceFd(&socket.as_raw_fd())),
+            #[cfg(windows)]
+            Connection::Raw(_, _) => Ok(()),
+            Connection::Loopback(_) => Ok(()),
         }
     }
 
     pub(crate) fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
         match &mut self.connection {
-            Connection::Tcp(connection) => connection.write(bytes),
+            Connection::Tcp(connection) => Self::write_stream(connection, bytes),
             Connection::Udp(connection) => connection.write(bytes),
+            Connection::Multicast(connection, group) => connection.send_to(bytes, *group),
+            Connection::Raw(socket, _) => socket.send(bytes),
+            Connection::Loopback(queue) => {
+                queue.push_back(bytes.to_vec());
+                Ok(bytes.len())
+            }
         }
     }
 
@@ -78,7 +237,58 @@ impl Socket {
     {
         match &mut self.connection {
             Connection::Tcp(connection) => Self::read_all(connection, is_closed, callback),
-            Connection::Udp(connection) => Self::read_all(connection, is_closed, callback),
+            Connection::Udp(connection) | Connection::Multicast(connection, _) => {
+                let buffer = Self::datagram_buffer(&mut self.datagram_buffer, self.max_datagram_size);
+                Self::read_datagrams(connection, buffer, is_closed, callback)
+            }
+            Connection::Raw(socket, ip_version) => Self::read_raw(socket, *ip_version, is_closed, callback),
+            Connection::Loopback(queue) => Self::read_queue(queue, callback),
+        }
+    }
+
+    /// Reads the datagrams of a multicast socket along with the address of the responder sending
+    /// each, which `read` leaves out.
+    pub(crate) fn read_from<F>(&mut self, mut callback: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut [u8], SocketAddr) -> std::io::Result<()>,
+    {
+        let Connection::Multicast(connection, _) = &mut self.connection else {
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported));
+        };
+        let buffer = Self::datagram_buffer(&mut self.datagram_buffer, self.max_datagram_size);
+        loop {
+            match connection.recv_from(&mut buffer[..]) {
+                Ok((count, _)) if count >= buffer.len() => {
+                    log::info!("dropped datagram larger than {} bytes", buffer.len() - 1);
+                }
+                Ok((count, source)) => callback(&mut buffer[..count], source)?,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the non-blocking connect completed, a failed connect is reported by its SO_ERROR.
+    pub(crate) fn is_connected(&self) -> std::io::Result<bool> {
+        match &self.connection {
+            Connection::Tcp(connection) => {
+                if let Some(error) = connection.take_error()? {
+                    return Err(error);
+                }
+                Ok(connection.peer_addr().is_ok())
+            }
+            Connection::Udp(_) | Connection::Multicast(_, _) | Connection::Raw(_, _) | Connection::Loopback(_) => Ok(true),
+        }
+    }
+
+    /// Takes the pending error of the socket (SO_ERROR), e.g. left by an ICMP error.
+    pub(crate) fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        match &self.connection {
+            Connection::Tcp(connection) => connection.take_error(),
+            Connection::Udp(connection) | Connection::Multicast(connection, _) => connection.take_error(),
+            Connection::Raw(socket, _) => socket.take_error(),
+            Connection::Loopback(_) => Ok(None),
         }
     }
 
@@ -89,66 +299,221 @@ impl Socket {
                     log::debug!("failed to shutdown tcp stream, error={:?}", error);
                 }
             }
-            Connection::Udp(_) => {
-                // UDP connections do not require to be closed.
+            Connection::Udp(_) | Connection::Multicast(_, _) | Connection::Raw(_, _) | Connection::Loopback(_) => {
+                // UDP, raw and loopback connections do not require to be closed.
+            }
+        }
+    }
+
+    /// Ends the sending half of a TCP connection with a FIN, the server can still answer.
+    pub(crate) fn shutdown_write(&self) {
+        match &self.connection {
+            Connection::Tcp(connection) => {
+                if let Err(error) = connection.shutdown(Shutdown::Write) {
+                    log::debug!("failed to shutdown write half of tcp stream, error={:?}", error);
+                }
+            }
+            Connection::Udp(_) | Connection::Multicast(_, _) | Connection::Raw(_, _) | Connection::Loopback(_) => {
+                // there is no stream to end, the data just stops.
             }
         }
     }
 
+    /// Whether data is waiting to be read that no poll event will announce.
+    pub(crate) fn has_unannounced_data(&self) -> bool {
+        match &self.connection {
+            Connection::Loopback(queue) => !queue.is_empty(),
+            _ => false,
+        }
+    }
+
     fn create_socket(ip_protocol: &IpProtocol, ip_version: &IpVersion) -> std::io::Result<::socket2::Socket> {
         let domain = match ip_version {
             IpVersion::Ipv4 => ::socket2::Domain::IPV4,
             IpVersion::Ipv6 => ::socket2::Domain::IPV6,
         };
 
-        let err = format!("unsupported transport protocol: {:?}", ip_protocol);
-        let protocol = match ip_protocol {
-            IpProtocol::Tcp => ::socket2::Protocol::TCP,
-            IpProtocol::Udp => ::socket2::Protocol::UDP,
-            _ => {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, err));
+        let (socket_type, protocol) = match ip_protocol {
+            IpProtocol::Tcp => (::socket2::Type::STREAM, ::socket2::Protocol::TCP),
+            IpProtocol::Udp => (::socket2::Type::DGRAM, ::socket2::Protocol::UDP),
+            // any other protocol is relayed as is, the processor only asks for it when configured to.
+            _ => (::socket2::Type::RAW, ::socket2::Protocol::from(i32::from(u8::from(*ip_protocol)))),
+        };
+
+        let socket = ::socket2::Socket::new(domain, socket_type, Some(protocol))?;
+
+        socket.set_nonblocking(true)?;
+
+        // lets a port in TIME_WAIT be bound again, by `preserve_tcp_source_port` or
+        // `upstream_port_range`. On Windows it would let another socket take over the port instead.
+        #[cfg(unix)]
+        if *ip_protocol == IpProtocol::Tcp {
+            socket.set_reuse_address(true)?;
+        }
+
+        Ok(socket)
+    }
+
+    // failing to resize the buffers only costs throughput, the socket is still usable.
+    fn set_buffer_sizes(socket: &::socket2::Socket, config: &crate::TunConfig) {
+        if let Some(size) = config.upstream_recv_buffer_size {
+            match socket.set_recv_buffer_size(size).and_then(|_| socket.recv_buffer_size()) {
+                Ok(effective_size) => log::trace!("set receive buffer size, size={} effective={}", size, effective_size),
+                Err(error) => log::error!("failed to set receive buffer size, size={} error={:?}", size, error),
             }
+        }
+        if let Some(size) = config.upstream_send_buffer_size {
+            match socket.set_send_buffer_size(size).and_then(|_| socket.send_buffer_size()) {
+                Ok(effective_size) => log::trace!("set send buffer size, size={} effective={}", size, effective_size),
+                Err(error) => log::error!("failed to set send buffer size, size={} error={:?}", size, error),
+            }
+        }
+    }
+
+    // failing to mark the packets only costs their priority, the socket is still usable.
+    fn set_dscp(socket: &::socket2::Socket, ip_protocol: &IpProtocol, ip_version: &IpVersion, config: &crate::TunConfig) {
+        let dscp = match ip_protocol {
+            IpProtocol::Tcp => config.tcp_dscp,
+            IpProtocol::Udp => config.udp_dscp,
+            _ => None,
+        };
+        let Some(dscp) = dscp else {
+            return;
         };
+        // the lower two bits are ECN, left to the kernel.
+        let traffic_class = u32::from(dscp) << 2;
+        let result = match ip_version {
+            IpVersion::Ipv4 => socket.set_tos(traffic_class),
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+            IpVersion::Ipv6 => socket.set_tclass_v6(traffic_class),
+            #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+            IpVersion::Ipv6 => Err(std::io::Error::from(std::io::ErrorKind::Unsupported)),
+        };
+        match result {
+            Ok(()) => log::trace!("set dscp, dscp={}", dscp),
+            Err(error) => log::error!("failed to set dscp, dscp={} error={:?}", dscp, error),
+        }
+    }
 
-        let socket_type = match ip_protocol {
-            IpProtocol::Tcp => ::socket2::Type::STREAM,
-            IpProtocol::Udp => ::socket2::Type::DGRAM,
-            _ => {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, err));
+    // failing to enable the probes leaves the connection to the idle timeouts of the middleboxes,
+    // the socket is still usable.
+    fn set_keepalive(socket: &::socket2::Socket, ip_protocol: &IpProtocol, config: &crate::TunConfig) {
+        let Some(keepalive) = config.upstream_tcp_keepalive.filter(|_| *ip_protocol == IpProtocol::Tcp) else {
+            return;
+        };
+        let params = ::socket2::TcpKeepalive::new().with_time(keepalive.idle);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        let params = params.with_interval(keepalive.interval).with_retries(keepalive.retries);
+        match socket.set_tcp_keepalive(&params) {
+            Ok(()) => log::trace!("set tcp keepalive, keepalive={:?}", keepalive),
+            Err(error) => log::error!("failed to set tcp keepalive, keepalive={:?} error={:?}", keepalive, error),
+        }
+    }
+
+    // the sessions are keyed by the addresses of the guest packets, which carry no scope, so the scope
+    // is only added to the address connected to. Link-local multicast groups need it as well.
+    fn scoped_address(address: SocketAddr, config: &crate::TunConfig) -> SocketAddr {
+        match (address, config.link_local_scope_id) {
+            (SocketAddr::V6(mut address), Some(scope_id)) if address.ip().segments()[0] & 0xffc0 == 0xfe80 || address.ip().segments()[0] & 0xff0f == 0xff02 => {
+                address.set_scope_id(scope_id);
+                SocketAddr::V6(address)
             }
+            _ => address,
+        }
+    }
+
+    // the membership is announced to the local network, so snooping switches pass the traffic of the
+    // group; failing to join only costs that. A broadcast cannot be sent at all without SO_BROADCAST.
+    fn join_group(socket: &::socket2::Socket, group: IpAddr, config: &crate::TunConfig) -> std::io::Result<()> {
+        let joined = match group {
+            IpAddr::V4(address) if address.is_broadcast() => return socket.set_broadcast(true),
+            IpAddr::V4(address) => socket.join_multicast_v4(&address, &Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(address) => socket.join_multicast_v6(&address, config.link_local_scope_id.unwrap_or(0)),
         };
+        if let Err(error) = joined {
+            log::debug!("failed to join multicast group, group={} error={:?}", group, error);
+        }
+        Ok(())
+    }
 
-        let socket = ::socket2::Socket::new(domain, socket_type, Some(protocol))?;
+    // failing to bind the port of the guest leaves the socket unbound, the connect then picks an
+    // ephemeral port as usual.
+    fn bind_source_port(socket: &::socket2::Socket, ip_protocol: &IpProtocol, ip_version: &IpVersion, source_port: u16, config: &crate::TunConfig) -> bool {
+        if *ip_protocol != IpProtocol::Tcp || !config.preserve_tcp_source_port {
+            return false;
+        }
+        // the port may still be held by a previous connection in TIME_WAIT, or by one of the guest
+        // to another destination.
+        match socket.bind(&Self::unspecified_address(ip_version, source_port).into()) {
+            Ok(()) => {
+                log::trace!("bound source port, port={}", source_port);
+                true
+            }
+            Err(error) => {
+                log::debug!("failed to bind source port, port={} error={:?}", source_port, error);
+                false
+            }
+        }
+    }
 
-        socket.set_nonblocking(true)?;
+    // like `bind_source_port`, the socket is left unbound when no port of the range is free. With
+    // SO_REUSEADDR a port held by another connection binds as well, only its connect to the same
+    // destination fails.
+    fn bind_port_range(socket: &::socket2::Socket, ip_protocol: &IpProtocol, ip_version: &IpVersion, config: &crate::TunConfig) -> bool {
+        let Some(range) = config.upstream_port_range.as_ref() else {
+            return false;
+        };
+        if *ip_protocol != IpProtocol::Tcp && *ip_protocol != IpProtocol::Udp {
+            return false;
+        }
+        let (first, count) = (usize::from(*range.start()), range.len());
+        for _ in 0..count.min(PORT_RANGE_ATTEMPTS) {
+            let port = (first + NEXT_RANGE_PORT.fetch_add(1, Ordering::Relaxed) % count) as u16;
+            match socket.bind(&Self::unspecified_address(ip_version, port).into()) {
+                Ok(()) => {
+                    log::trace!("bound port of range, port={}", port);
+                    return true;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::AddrInUse => continue,
+                Err(error) => {
+                    log::debug!("failed to bind port of range, port={} error={:?}", port, error);
+                    return false;
+                }
+            }
+        }
+        log::debug!("no free port in range, using an ephemeral one, range={:?}", range);
+        false
+    }
 
-        Ok(socket)
+    fn unspecified_address(ip_version: &IpVersion, port: u16) -> SocketAddr {
+        match ip_version {
+            IpVersion::Ipv4 => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            IpVersion::Ipv6 => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        }
     }
 
-    fn create_connection(ip_protocol: &IpProtocol, socket: &::socket2::Socket) -> std::io::Result<Connection> {
+    // the connection takes over the ownership of the socket, so it is closed exactly once.
+    fn create_connection(ip_protocol: &IpProtocol, ip_version: &IpVersion, socket: ::socket2::Socket) -> std::io::Result<Connection> {
         match ip_protocol {
             IpProtocol::Tcp => {
                 #[cfg(unix)]
-                let tcp_stream = unsafe { ::mio::net::TcpStream::from_raw_fd(socket.as_raw_fd()) };
+                let tcp_stream = unsafe { ::mio::net::TcpStream::from_raw_fd(socket.into_raw_fd()) };
 
                 #[cfg(windows)]
-                let tcp_stream = unsafe { ::mio::net::TcpStream::from_raw_socket(socket.as_raw_socket()) };
+                let tcp_stream = unsafe { ::mio::net::TcpStream::from_raw_socket(socket.into_raw_socket()) };
 
                 Ok(Connection::Tcp(tcp_stream))
             }
             IpProtocol::Udp => {
                 #[cfg(unix)]
-                let udp_socket = unsafe { ::mio::net::UdpSocket::from_raw_fd(socket.as_raw_fd()) };
+                let udp_socket = unsafe { ::mio::net::UdpSocket::from_raw_fd(socket.into_raw_fd()) };
 
                 #[cfg(windows)]
-                let udp_socket = unsafe { ::mio::net::UdpSocket::from_raw_socket(socket.as_raw_socket()) };
+                let udp_socket = unsafe { ::mio::net::UdpSocket::from_raw_socket(socket.into_raw_socket()) };
 
                 Ok(Connection::Udp(udp_socket))
             }
-            _ => {
-                let err = format!("unsupported transport protocol: {:?}", ip_protocol);
-                Err(std::io::Error::new(std::io::ErrorKind::Other, err))
-            }
+            _ => Ok(Connection::Raw(socket, *ip_version)),
         }
     }
 
@@ -157,7 +522,7 @@ impl Socket {
         R: Reader,
         F: FnMut(&mut [u8]) -> std::io::Result<()>,
     {
-        let mut buffer = [0; crate::MAX_PACKET_SIZE]; // maximum UDP packet size
+        let mut buffer = [0; crate::MAX_PACKET_SIZE];
         loop {
             match reader.read(&mut buffer[..]) {
                 Ok(count) => {
@@ -179,6 +544,91 @@ impl Socket {
         }
         Ok(())
     }
+
+    fn write_stream<W: Writer>(writer: &mut W, bytes: &[u8]) -> std::io::Result<usize> {
+        let count = writer.write(bytes)?;
+        if count == 0 && !bytes.is_empty() {
+            // the stream does not accept data anymore, retrying would spin forever.
+            return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "tcp stream is closed"));
+        }
+        Ok(count)
+    }
+
+    // unlike a stream, an empty read is an empty datagram rather than the end of the connection.
+    fn read_datagrams<F>(socket: &mut ::mio::net::UdpSocket, buffer: &mut [u8], is_closed: &mut bool, mut callback: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut [u8]) -> std::io::Result<()>,
+    {
+        loop {
+            match socket.recv(&mut buffer[..]) {
+                Ok(count) => {
+                    if count >= buffer.len() {
+                        log::info!("dropped datagram larger than {} bytes", buffer.len() - 1);
+                        continue;
+                    }
+                    callback(&mut buffer[..count])?;
+                }
+                Err(err) => {
+                    if err.kind() == std::io::ErrorKind::WouldBlock {
+                        break;
+                    } else {
+                        *is_closed = true;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn datagram_buffer(buffer: &mut Vec<u8>, max_datagram_size: usize) -> &mut [u8] {
+        buffer.resize(max_datagram_size + 1, 0);
+        buffer
+    }
+
+    // hands over the IP payload of each packet, the header an IPv4 raw socket receives is stripped.
+    fn read_raw<F>(socket: &mut ::socket2::Socket, ip_version: IpVersion, is_closed: &mut bool, mut callback: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut [u8]) -> std::io::Result<()>,
+    {
+        let mut buffer = [0; crate::MAX_PACKET_SIZE];
+        loop {
+            match std::io::Read::read(socket, &mut buffer[..]) {
+                Ok(count) => {
+                    let payload = match ip_version {
+                        IpVersion::Ipv4 => match Ipv4Packet::new_checked(&buffer[..count]) {
+                            Ok(packet) => usize::from(packet.header_len())..usize::from(packet.total_len()),
+                            Err(error) => {
+                                log::debug!("dropped malformed raw packet, error={:?}", error);
+                                continue;
+                            }
+                        },
+                        IpVersion::Ipv6 => 0..count,
+                    };
+                    callback(&mut buffer[payload])?;
+                }
+                Err(err) => {
+                    if err.kind() == std::io::ErrorKind::WouldBlock {
+                        break;
+                    } else {
+                        *is_closed = true;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_queue<F>(queue: &mut VecDeque<Vec<u8>>, mut callback: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut [u8]) -> std::io::Result<()>,
+    {
+        while let Some(mut bytes) = queue.pop_front() {
+            callback(&mut bytes[..])?;
+        }
+        Ok(())
+    }
 }
 
 trait Reader {
@@ -212,3 +662,28 @@ impl Writer for ::mio::net::TcpStream {
         <::mio::net::TcpStream as std::io::Write>::write(self, buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a stream accepting at most `accepted` bytes per write.
+    struct Stream {
+        accepted: usize,
+    }
+
+    impl Writer for Stream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len().min(self.accepted))
+        }
+    }
+
+    #[test]
+    fn stream_accepting_nothing_is_closed() {
+        let error = Socket::write_stream(&mut Stream { accepted: 0 }, b"ping").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WriteZero);
+        // nothing to write is not a closed stream.
+        assert_eq!(Socket::write_stream(&mut Stream { accepted: 0 }, b"").unwrap(), 0);
+        assert_eq!(Socket::write_stream(&mut Stream { accepted: 2 }, b"ping").unwrap(), 2);
+    }
+}
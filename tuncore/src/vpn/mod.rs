@@ -1,43 +1,184 @@
-mod buffers;
+mod buffer_usage;
+mod capture;
+mod clock;
+mod command;
+#[cfg(feature = "connection-log")]
+mod connection_log;
+#[cfg(feature = "doh")]
+mod dns;
+#[cfg(feature = "doh")]
+mod doh;
+mod guest_rtt;
+mod health;
+mod icmp;
+mod ip_packet;
+mod load;
 mod mio_socket;
 mod processor;
+mod proxy_protocol;
+mod raw_session;
 mod session;
-mod session_info;
+mod session_error;
 mod smoltcp_socket;
+mod socket_pool;
+mod tcp_packet;
+mod tee;
+mod udp_packet;
+mod upstream;
 mod utils;
 mod vpn_device;
 
+// the packet core is built without std as well, the engine uses it as modules of its own.
+pub(crate) use crate::packet::{buffers, session_info};
+
+pub use capture::CaptureFilter;
+pub(crate) use command::CommandSender;
+#[cfg(feature = "doh")]
+pub(crate) use doh::Endpoint as DohEndpoint;
+pub use health::{ConnectLatencyHistogram, ProcessorHealth, SessionCounters, SessionDump, StatsSnapshot, CONNECT_LATENCY_BOUNDS};
+pub use session_error::{SessionError, SessionErrorCause};
+pub use session_info::SessionInfo;
+pub use upstream::{UpstreamConnector, UpstreamTransport};
+
+/// Whether packets of the guest are forwarded, as returned by `tun::state()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum TunState {
+    /// Not started, stopped, or failed without `TunConfig::kill_switch`.
+    Stopped,
+    Forwarding,
+    /// Failed with `TunConfig::kill_switch`, the packets of the guest are dropped until stopped.
+    Blocking,
+}
+
+/// Why the processor stopped, as returned by `tun::shutdown_reason()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ShutdownReason {
+    /// Stopped by `tun::stop()`.
+    UserRequested,
+    /// Reading or writing the tun failed, e.g. after the system revoked the vpn.
+    TunError,
+    /// Polling failed for another reason than the file descriptors.
+    PollError,
+    /// No file descriptor was left for the process or the system.
+    FdExhausted,
+    Panic,
+}
+
+impl ShutdownReason {
+    fn from_error(error: &std::io::Error, otherwise: Self) -> Self {
+        match error.raw_os_error() {
+            Some(libc::EMFILE) | Some(libc::ENFILE) => ShutdownReason::FdExhausted,
+            _ => otherwise,
+        }
+    }
+}
+
 pub(super) struct Vpn {
     file_descriptor: i32,
+    config: crate::TunConfig,
     stop_waker: Option<std::sync::Arc<::mio::Waker>>,
     exit_flag: Option<std::sync::Arc<std::sync::atomic::AtomicBool>>,
     thread_join_handle: Option<std::thread::JoinHandle<()>>,
+    command_sender: Option<command::CommandSender>,
+    state: std::sync::Arc<std::sync::Mutex<TunState>>,
+    shutdown_reason: std::sync::Arc<std::sync::Mutex<Option<ShutdownReason>>>,
 }
 
 impl Vpn {
-    pub fn new(file_descriptor: i32) -> Self {
+    pub fn new(file_descriptor: i32, config: crate::TunConfig) -> Self {
         Self {
             file_descriptor,
+            config,
             stop_waker: None,
             exit_flag: None,
             thread_join_handle: None,
+            command_sender: None,
+            state: std::sync::Arc::new(std::sync::Mutex::new(TunState::Stopped)),
+            shutdown_reason: std::sync::Arc::new(std::sync::Mutex::new(None)),
         }
     }
 
-    pub fn start(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let mut processor = processor::Processor::new(self.file_descriptor)?;
-        self.stop_waker = Some(processor.new_stop_waker()?);
+    pub fn start(&mut self) -> crate::Result<()> {
+        self.config.validate()?;
+        let mut processor = processor::Processor::new(self.file_descriptor, self.config.clone())?;
+        self.stop_waker = Some(processor.new_stop_waker());
         self.exit_flag = Some(processor.exit_flag());
-        self.thread_join_handle = Some(std::thread::spawn(move || processor.run().unwrap()));
+        self.command_sender = Some(processor.command_sender());
+        let state = self.state.clone();
+        let shutdown_reason = self.shutdown_reason.clone();
+        let kill_switch = self.config.kill_switch;
+        *state.lock().unwrap() = TunState::Forwarding;
+        *shutdown_reason.lock().unwrap() = None;
+        self.thread_join_handle = Some(std::thread::spawn(move || {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| processor.run()));
+            let reason = match result {
+                Ok(_) => processor.shutdown_reason(),
+                Err(_) => Some(ShutdownReason::Panic),
+            };
+            *shutdown_reason.lock().unwrap() = reason;
+            match result {
+                Ok(Ok(())) => {
+                    *state.lock().unwrap() = TunState::Stopped;
+                    return;
+                }
+                Ok(Err(error)) => log::error!("processor failed, error={:?}", error),
+                Err(_) => log::error!("processor panicked"),
+            }
+            let failed_state = if kill_switch { TunState::Blocking } else { TunState::Stopped };
+            *state.lock().unwrap() = failed_state;
+            // run sets a reason whenever it fails.
+            #[cfg(target_family = "unix")]
+            crate::tun_callbacks::on_tun_failed(reason.unwrap_or(ShutdownReason::TunError), failed_state);
+            if kill_switch {
+                processor.drop_packets();
+            }
+            *state.lock().unwrap() = TunState::Stopped;
+        }));
         Ok(())
     }
 
-    pub fn stop(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        self.exit_flag.as_ref().ok_or("no exit flag")?.store(true, std::sync::atomic::Ordering::Relaxed);
-        self.stop_waker.as_ref().ok_or("no waker")?.wake()?;
-        if let Err(e) = self.thread_join_handle.take().ok_or("no thread")?.join() {
-            log::error!("failed to join thread: {:?}", e);
+    pub fn state(&self) -> TunState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Unset while the processor runs, or when it was never started.
+    pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        *self.shutdown_reason.lock().unwrap()
+    }
+
+    /// Tells the processor to stop and returns its thread, for the caller to join without holding
+    /// the vpn. Fails with `Error::NotRunning` once stopped.
+    pub fn stop(&mut self) -> crate::Result<std::thread::JoinHandle<()>> {
+        let thread = self.thread_join_handle.take().ok_or(crate::Error::NotRunning)?;
+        self.command_sender = None;
+        if let Some(exit_flag) = &self.exit_flag {
+            exit_flag.store(true, std::sync::atomic::Ordering::Relaxed);
         }
+        if let Some(stop_waker) = &self.stop_waker {
+            stop_waker.wake()?;
+        }
+        Ok(thread)
+    }
+
+    /// The commands of the running processor, cloned so the caller can wait for the replies without
+    /// holding the vpn.
+    pub(crate) fn command_sender(&self) -> crate::Result<command::CommandSender> {
+        self.command_sender.clone().ok_or(crate::Error::NotRunning)
+    }
+
+    /// Keeps the rules in the config, for the processor of the next start.
+    pub fn set_rules(&mut self, rules: crate::Rules) {
+        self.config.set_rules(rules);
+    }
+
+    /// Validates the MTU and keeps it in the config, for the processor of the next start.
+    pub fn set_mtu(&mut self, mtu: usize) -> crate::Result<()> {
+        let mut config = self.config.clone();
+        config.mtu = mtu;
+        config.validate()?;
+        self.config = config;
         Ok(())
     }
 }
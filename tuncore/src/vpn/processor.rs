@@ -1,15 +1,43 @@
-use crate::vpn::{session::Session, session_info::SessionInfo};
+#[cfg(feature = "connection-log")]
+use crate::vpn::connection_log::ConnectionLog;
+#[cfg(feature = "doh")]
+use crate::vpn::doh;
+use crate::vpn::{
+    buffer_usage::BufferUsage,
+    capture::{CaptureFilter, CaptureRegistry},
+    clock::{Clock, SystemClock},
+    command::{Command, CommandSender, SessionPredicate},
+    health::{ProcessorHealth, SessionDump, StatsSnapshot},
+    icmp, ip_packet,
+    load::Load,
+    raw_session::RawSession,
+    session::{DestroyReason, Session},
+    session_error::{self, SessionErrorCause},
+    session_info::SessionInfo,
+    socket_pool::SocketPool,
+    tcp_packet, udp_packet,
+    utils::PacketLogger,
+    ShutdownReason,
+};
 #[cfg(target_family = "unix")]
 use mio::unix::SourceFd;
 use mio::{event::Event, Events, Interest, Token, Waker};
+use smoltcp::wire::{IpProtocol, Ipv6Packet, TcpSeqNumber};
 #[cfg(target_family = "unix")]
 use std::os::unix::io::FromRawFd;
 use std::{
     collections::HashMap,
-    io::{ErrorKind, Read},
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
 };
 
 type SessionHashMap<'a> = HashMap<SessionInfo, Session<'a>>;
+type RawSessionHashMap = HashMap<SessionInfo, RawSession>;
 
 const EVENTS_CAPACITY: usize = 1024;
 
@@ -17,6 +45,28 @@ const TOKEN_TUN: Token = Token(0);
 const TOKEN_WAKER: Token = Token(1);
 const TOKEN_START_ID: usize = 10;
 
+// link-local, multicast and unspecified, the addresses of neighbor discovery, multicast listener
+// reports and duplicate address detection.
+const IPV6_HOUSEKEEPING_NETWORKS: [crate::IpNetwork; 3] = [
+    crate::IpNetwork::v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+    crate::IpNetwork::v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+    crate::IpNetwork::v6([0, 0, 0, 0, 0, 0, 0, 0], 128),
+];
+
+// the multicast networks of `DEFAULT_BLOCKED_DESTINATIONS`, which `TunConfig::forward_multicast`
+// lets through.
+const MULTICAST_NETWORKS: [crate::IpNetwork; 2] = [crate::IpNetwork::v4(224, 0, 0, 0, 4), crate::IpNetwork::v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8)];
+
+// consecutive failed tun reads after which the tun is taken as gone, e.g. revoked by the system.
+const MAX_TUN_READ_ERRORS: u32 = 8;
+
+// lower bound of the wait for smoltcp timers, so a timer that stays due cannot spin the loop.
+const MIN_TIMER_DELAY: std::time::Duration = std::time::Duration::from_millis(1);
+
+// longest wait of data of the guest for the upstream socket before its write is retried without
+// any event, so a writable event that never comes cannot hold it until an unrelated wakeup.
+const IDLE_FLUSH_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
 pub(crate) struct Processor<'a> {
     #[cfg(target_family = "unix")]
     file_descriptor: i32,
@@ -24,49 +74,166 @@ pub(crate) struct Processor<'a> {
     file: std::fs::File,
     poll: mio::Poll,
     sessions: SessionHashMap<'a>,
+    raw_sessions: RawSessionHashMap,
+    // the session of each upstream token, so an event finds its session without a scan. Tokens are
+    // never reused, and the one of a destroyed session is removed with it, so a later event of the
+    // same batch finds no session rather than another one.
+    tokens: HashMap<Token, SessionInfo>,
     next_token_id: usize,
-    waker: Option<std::sync::Arc<::mio::Waker>>,
+    waker: Arc<Waker>,
     exit_flag: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    command_sender: Sender<Command>,
+    command_receiver: Receiver<Command>,
+    config: crate::TunConfig,
+    next_reap: std::time::Instant,
+    #[cfg(feature = "doh")]
+    doh_resolver: Option<doh::Resolver>,
+    #[cfg(feature = "connection-log")]
+    connection_log: Option<ConnectionLog>,
+    packet_logger: PacketLogger,
+    health: ProcessorHealth,
+    clock: Arc<dyn Clock>,
+    destination_sessions: HashMap<IpAddr, usize>,
+    load: Load,
+    tun_read_errors: u32,
+    // reused by every tun read, one packet of at most the mtu fits.
+    tun_buffer: Vec<u8>,
+    captures: CaptureRegistry,
+    buffer_usage: BufferUsage,
+    // whether sessions are throttled by `enforce_buffer_budget`.
+    throttling: bool,
+    // packets are left in the tun by `TunConfig::tun_read_budget` or `TunConfig::new_session_budget`.
+    tun_backlog: bool,
+    // destroyed TCP sessions, see `TunConfig::tcp_quarantine`, with the end of the quarantine and the
+    // initial sequence number of their SYN.
+    quarantine: HashMap<SessionInfo, (std::time::Instant, Option<TcpSeqNumber>)>,
+    socket_pool: SocketPool<'a>,
+    shutdown_reason: Option<ShutdownReason>,
 }
 
 impl<'a> Processor<'a> {
-    pub(crate) fn new(file_descriptor: i32) -> std::io::Result<Processor<'a>> {
+    pub(crate) fn new(file_descriptor: i32, config: crate::TunConfig) -> std::io::Result<Processor<'a>> {
+        let (command_sender, command_receiver) = channel();
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let poll = mio::Poll::new()?;
+        // mio allows a single waker per poll, it is shared by stop requests and commands.
+        let waker = Arc::new(Waker::new(poll.registry(), TOKEN_WAKER)?);
+        #[cfg(feature = "doh")]
+        let doh_resolver = match &config.doh {
+            Some(doh) => Some(doh::Resolver::new(doh, config.strict_socket_setup, waker.clone())?),
+            None => None,
+        };
+        // DNS over TCP goes through the resolver too, validate() keeps `dns_upstream` free for it.
+        #[cfg(feature = "doh")]
+        let config = match &doh_resolver {
+            Some(resolver) => crate::TunConfig {
+                dns_upstream: Some(crate::Upstream::Custom(resolver.stream_connector())),
+                ..config
+            },
+            None => config,
+        };
         Ok(Processor {
             #[cfg(target_family = "unix")]
             file_descriptor,
             #[cfg(target_family = "unix")]
             file: unsafe { std::fs::File::from_raw_fd(file_descriptor) },
-            poll: mio::Poll::new()?,
+            poll,
             sessions: SessionHashMap::new(),
+            raw_sessions: RawSessionHashMap::new(),
+            tokens: HashMap::new(),
             next_token_id: TOKEN_START_ID,
-            waker: None,
+            waker,
             exit_flag: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            command_sender,
+            command_receiver,
+            next_reap: clock.now() + config.reaper_interval,
+            #[cfg(feature = "doh")]
+            doh_resolver,
+            #[cfg(feature = "connection-log")]
+            connection_log: config.connection_log.as_ref().map(ConnectionLog::new),
+            packet_logger: PacketLogger::new(&config.packet_log, None),
+            health: ProcessorHealth::default(),
+            load: Load::new(clock.now()),
+            clock,
+            destination_sessions: HashMap::new(),
+            tun_read_errors: 0,
+            tun_buffer: vec![0; config.mtu],
+            captures: CaptureRegistry::new(&config),
+            buffer_usage: BufferUsage::default(),
+            throttling: false,
+            tun_backlog: false,
+            quarantine: HashMap::new(),
+            socket_pool: SocketPool::new(config.socket_pool_size),
+            shutdown_reason: None,
+            config,
         })
     }
 
+    /// Replaces the clock driving session expiry and reaping, e.g. by one advanced by hand.
+    #[cfg(test)]
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.next_reap = clock.now() + self.config.reaper_interval;
+        self.load = Load::new(clock.now());
+        self.clock = clock;
+    }
+
     pub(crate) fn exit_flag(&self) -> std::sync::Arc<std::sync::atomic::AtomicBool> {
         self.exit_flag.clone()
     }
 
-    pub(crate) fn new_stop_waker(&mut self) -> std::io::Result<std::sync::Arc<Waker>> {
-        self.create_stop_waker()?;
-        Ok(self.waker.clone().unwrap())
+    pub(crate) fn command_sender(&self) -> CommandSender {
+        CommandSender::new(self.command_sender.clone(), self.waker.clone())
     }
 
-    fn create_stop_waker(&mut self) -> std::io::Result<()> {
-        if self.waker.is_none() {
-            self.waker = Some(std::sync::Arc::new(Waker::new(self.poll.registry(), TOKEN_WAKER)?));
-        }
-        Ok(())
+    pub(crate) fn new_stop_waker(&self) -> Arc<Waker> {
+        self.waker.clone()
     }
 
+    // tokens only grow, the token of a destroyed session is never handed out again.
     fn generate_new_token(&mut self) -> Token {
         self.next_token_id += 1;
         Token(self.next_token_id)
     }
 
+    /// Makes `Token(id)` the next token, so a test knows the tokens of the sessions it creates.
+    #[cfg(test)]
+    pub(crate) fn set_next_token(&mut self, id: usize) {
+        self.next_token_id = id - 1;
+    }
+
+    // the session of a server event, unset once the session was destroyed, e.g. by an earlier event
+    // of the same batch.
+    fn token_session(&self, token: Token) -> Option<SessionInfo> {
+        self.tokens.get(&token).copied()
+    }
+
+    fn index_token(&mut self, token: Token, session_info: SessionInfo) {
+        let previous = self.tokens.insert(token, session_info);
+        debug_assert!(previous.is_none(), "token of a live session reused, {:?} {:?}", token, previous);
+    }
+
+    fn unindex_token(&mut self, token: Token, session_info: &SessionInfo) {
+        let indexed = self.tokens.remove(&token);
+        debug_assert_eq!(indexed.as_ref(), Some(session_info), "token indexed another session, {:?}", token);
+    }
+
+    /// Why `run` returned, unset while it runs.
+    pub(crate) fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        self.shutdown_reason
+    }
+
     pub(crate) fn run(&mut self) -> std::io::Result<()> {
-        log::info!("starting vpn");
+        let result = self.poll_loop();
+        if let Err(error) = &result {
+            // the failures of the tun are the ones propagated out of the loop.
+            let reason = ShutdownReason::from_error(error, ShutdownReason::TunError);
+            self.shutdown_reason.get_or_insert(reason);
+        }
+        result
+    }
+
+    fn poll_loop(&mut self) -> std::io::Result<()> {
+        log::info!("starting vpn, {}", crate::build_info::BUILD_INFO);
 
         #[cfg(target_family = "unix")]
         let registry = self.poll.registry();
@@ -74,23 +241,52 @@ impl<'a> Processor<'a> {
         registry.register(&mut SourceFd(&self.file_descriptor), TOKEN_TUN, Interest::READABLE | Interest::WRITABLE)?;
 
         let mut events = Events::with_capacity(EVENTS_CAPACITY);
-        let timeout = Some(std::time::Duration::from_secs(crate::POLL_TIMEOUT));
-
-        self.create_stop_waker()?;
+        let poll_timeout = std::time::Duration::from_secs(crate::POLL_TIMEOUT);
 
         'poll_loop: loop {
+            // wake up in time for the next reap and smoltcp timers even when no event arrives.
+            let mut timeout = poll_timeout.min(self.next_reap.saturating_duration_since(self.clock.now()));
+            if let Some(delay) = self.sessions.values_mut().filter_map(|session| session.poll_delay()).min() {
+                timeout = timeout.min(delay.max(MIN_TIMER_DELAY));
+            }
+            if self.sessions.values().any(|session| session.has_pending_server_data()) {
+                let delay = if self.config.immediate_forward { MIN_TIMER_DELAY } else { IDLE_FLUSH_DELAY };
+                timeout = timeout.min(delay);
+            }
+            if self.tun_backlog {
+                timeout = std::time::Duration::ZERO;
+            }
+            let timeout = Some(timeout);
             if let Err(e) = self.poll.poll(&mut events, timeout) {
-                log::debug!("failed to poll, error={:?}", e);
+                if e.kind() == ErrorKind::Interrupted {
+                    // a signal arrived meanwhile, which is routine and not worth a log.
+                    continue;
+                }
+                // other errors mean the poll itself is broken, retrying would only spin.
+                log::error!("failed to poll, stopping vpn, error={:?}", e);
+                self.shutdown_reason = Some(ShutdownReason::from_error(&e, ShutdownReason::PollError));
+                return Err(e);
             }
 
+            self.update_load();
+            self.handle_commands();
+            #[cfg(feature = "doh")]
+            self.handle_dns_answers();
+
             log::trace!("handling events, count={:?}", events.iter().count());
 
-            for event in events.iter() {
+            let mut ordered = events.iter().collect::<Vec<_>>();
+            if self.is_prioritizing() {
+                // the sort is stable, the sessions of the same priority keep the order of the kernel.
+                ordered.sort_by_key(|event| std::cmp::Reverse(self.token_priority(event.token())));
+            }
+            for event in ordered {
                 if event.token() == TOKEN_TUN {
                     self.handle_tun_event(event)?;
                 } else if event.token() == TOKEN_WAKER {
                     if self.exit_flag.load(std::sync::atomic::Ordering::Relaxed) {
                         log::info!("stopping vpn");
+                        self.shutdown_reason = Some(ShutdownReason::UserRequested);
                         break 'poll_loop;
                     }
                 } else {
@@ -98,27 +294,801 @@ impl<'a> Processor<'a> {
                 }
             }
 
-            self.clearup_expired_sessions();
+            // the tun is edge triggered, no event announces the packets a budget left unread.
+            if self.tun_backlog && !events.iter().any(|event| event.token() == TOKEN_TUN && event.is_readable()) {
+                self.read_tun()?;
+            }
+
+            if self.tun_read_errors >= MAX_TUN_READ_ERRORS {
+                let err = format!("tun keeps failing, errors={}", self.tun_read_errors);
+                self.shutdown_reason = Some(ShutdownReason::TunError);
+                return Err(std::io::Error::other(err));
+            }
+
+            self.handle_smoltcp_timers()?;
+
+            // without events the loop went idle, the pending data is retried rather than left to
+            // writable events that may not come, e.g. after a WouldBlock of a custom transport.
+            if self.config.immediate_forward || events.is_empty() {
+                self.flush_pending_server_data();
+            }
+
+            self.enforce_buffer_budget()?;
+
+            if self.next_reap <= self.clock.now() {
+                self.clearup_expired_sessions();
+                self.next_reap = self.clock.now() + self.config.reaper_interval;
+            }
             log::trace!("sessions count={}", self.sessions.len());
         }
         Ok(())
     }
 
+    /// Reads and drops the packets of the guest until stopped, once `run` failed with
+    /// `TunConfig::kill_switch` set, so none is forwarded while the processor is unhealthy.
+    pub(crate) fn drop_packets(&mut self) {
+        log::error!("kill switch engaged, dropping packets until stopped");
+        // the state the failure left the sessions in is not trusted, their sockets are closed.
+        self.sessions.clear();
+        self.raw_sessions.clear();
+        self.tokens.clear();
+
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut dropped: u64 = 0;
+        let mut tun_failed = false;
+        loop {
+            if let Err(error) = self.poll.poll(&mut events, None) {
+                if error.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("failed to poll, stopping vpn, error={:?}", error);
+                return;
+            }
+            // the commands are not served anymore. dropping one drops its reply sender, so its caller
+            // gets `Error::NotRunning` right away instead of waiting out the command timeout.
+            while self.command_receiver.try_recv().is_ok() {}
+            if self.exit_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                log::info!("stopping vpn, dropped={}", dropped);
+                return;
+            }
+            #[cfg(target_family = "unix")]
+            while !tun_failed {
+                match self.file.read(&mut self.tun_buffer) {
+                    Ok(0) => break,
+                    Ok(_) => dropped += 1,
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => {
+                        // nothing to drop from a tun that is gone, only the stop is waited for.
+                        log::error!("failed to read from tun, error={:?}", error);
+                        if let Err(error) = self.poll.registry().deregister(&mut SourceFd(&self.file_descriptor)) {
+                            log::error!("failed to deregister tun, error={:?}", error);
+                        }
+                        tun_failed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn update_load(&mut self) {
+        let bytes = self.sessions.values_mut().map(|session| session.take_transferred()).sum();
+        self.load.record_bytes(bytes);
+        self.load.update(self.clock.now());
+    }
+
+    fn handle_smoltcp_timers(&mut self) -> std::io::Result<()> {
+        let mut targets = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(session_info, session)| (session.poll_delay() == Some(std::time::Duration::ZERO)).then_some(*session_info))
+            .collect::<Vec<_>>();
+        self.sort_by_priority(&mut targets);
+        for session_info in targets {
+            if let Some(session) = self.sessions.get_mut(&session_info) {
+                session.write_to_smoltcp()?;
+
+                #[cfg(target_family = "unix")]
+                session.write_to_tun(&mut self.file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_prioritizing(&self) -> bool {
+        self.config.dscp_priority || !self.config.port_priorities.is_empty()
+    }
+
+    // the tun and the waker come first, as without priorities.
+    fn token_priority(&self, token: Token) -> u16 {
+        match self.tokens.get(&token) {
+            Some(session_info) => self.sessions.get(session_info).map_or(0, |session| u16::from(session.priority())),
+            None => u16::MAX,
+        }
+    }
+
+    fn sort_by_priority(&self, targets: &mut [SessionInfo]) {
+        if self.is_prioritizing() {
+            targets.sort_by_key(|session_info| std::cmp::Reverse(self.sessions.get(session_info).map_or(0, |session| session.priority())));
+        }
+    }
+
+    fn session_priority(&self, session_info: &SessionInfo, bytes: &[u8]) -> u8 {
+        match self.config.port_priorities.get(&session_info.destination.port()) {
+            Some(priority) => *priority,
+            None if self.config.dscp_priority => ip_packet::dscp(bytes).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    // throttles the sessions holding the most until the bytes they hold cover the excess over the
+    // budget, and resumes them all once the buffers are back under it.
+    fn enforce_buffer_budget(&mut self) -> std::io::Result<()> {
+        let Some(max_buffered_bytes) = self.config.max_buffered_bytes else {
+            return Ok(());
+        };
+        let buffered_bytes = self.buffer_usage.bytes();
+        if buffered_bytes <= max_buffered_bytes && !self.throttling {
+            return Ok(());
+        }
+        if buffered_bytes > max_buffered_bytes {
+            // the throttled sessions are draining already, only what they do not cover is left.
+            let throttled_bytes = self.sessions.values().filter(|s| s.is_throttled()).map(|s| s.buffered_bytes()).sum::<usize>();
+            let mut excess = (buffered_bytes - max_buffered_bytes).saturating_sub(throttled_bytes);
+            let mut busiest = self
+                .sessions
+                .iter()
+                .filter(|(_, s)| !s.is_throttled() && s.buffered_bytes() > 0)
+                .map(|(i, s)| (s.buffered_bytes(), *i))
+                .collect::<Vec<_>>();
+            busiest.sort_unstable_by(|a, b| b.cmp(a));
+            for (bytes, session_info) in busiest {
+                if excess == 0 {
+                    break;
+                }
+                if let Some(session) = self.sessions.get_mut(&session_info) {
+                    log::debug!("throttling session, {:?} buffered={} total={}", session_info, bytes, buffered_bytes);
+                    session.set_throttled(true);
+                    self.throttling = true;
+                }
+                excess = excess.saturating_sub(bytes);
+            }
+            return Ok(());
+        }
+        self.throttling = false;
+        let throttled = self.sessions.iter().filter(|(_, s)| s.is_throttled()).map(|(i, _)| *i).collect::<Vec<_>>();
+        for session_info in throttled {
+            log::debug!("resuming session, {:?} total={}", session_info, buffered_bytes);
+            let mut is_closed = false;
+            if let Some(session) = self.sessions.get_mut(&session_info) {
+                session.set_throttled(false);
+                // no event announces what was left unread meanwhile, on either side.
+                session.read_from_smoltcp()?;
+                session.write_to_server(&mut is_closed)?;
+
+                #[cfg(target_family = "unix")]
+                session.write_to_tun(&mut self.file)?;
+            }
+            if !is_closed {
+                self.read_server_n_write_client(session_info, &mut is_closed)?;
+            }
+            if is_closed {
+                if let Err(error) = self.close_upstream(&session_info) {
+                    log::error!("failed to close session upstream, error={:?}", error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // retries what the upstream sockets refused earlier, without waiting for a writable event.
+    fn flush_pending_server_data(&mut self) {
+        let mut targets = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.has_pending_server_data())
+            .map(|(i, _)| *i)
+            .collect::<Vec<_>>();
+        self.sort_by_priority(&mut targets);
+        for session_info in targets {
+            let mut is_closed = false;
+            if let Some(session) = self.sessions.get_mut(&session_info) {
+                if let Err(error) = session.write_to_server(&mut is_closed) {
+                    log::debug!("failed to flush to server, {:?} error={:?}", session_info, error);
+                }
+            }
+            if is_closed {
+                if let Err(error) = self.close_upstream(&session_info) {
+                    log::error!("failed to close session upstream, error={:?}", error);
+                }
+            }
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                Command::CloseSessions(predicate, reply_sender) => {
+                    let count = self.close_sessions(predicate);
+                    if let Err(error) = reply_sender.send(count) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::Health(reply_sender) => {
+                    if let Err(error) = reply_sender.send(self.health()) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::ResetStats(sessions, reply_sender) => {
+                    log::debug!("resetting stats, sessions={}", sessions);
+                    let snapshot = self.reset_stats(sessions);
+                    if let Err(error) = reply_sender.send(snapshot) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::ReloadRules(rules, enforce, reply_sender) => {
+                    log::debug!("reloading rules, enforce={}", enforce);
+                    self.config.set_rules(rules);
+                    let count = if enforce { self.close_refused_sessions() } else { 0 };
+                    if let Err(error) = reply_sender.send(count) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::SetMtu(mtu, reply_sender) => {
+                    log::debug!("setting mtu, mtu={}", mtu);
+                    self.config.mtu = mtu;
+                    self.tun_buffer.resize(mtu, 0);
+                    let count = self.set_mtu();
+                    if let Err(error) = reply_sender.send(count) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::DumpSession(session_info, reply_sender) => {
+                    let dump = self.dump_session(&session_info);
+                    if let Err(error) = reply_sender.send(dump) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::StartCapture(filter, directory, reply_sender) => {
+                    log::debug!("starting capture, filter={:?} directory={:?}", filter, directory);
+                    let count = self.start_capture(filter, directory);
+                    if let Err(error) = reply_sender.send(count) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::StopCapture(filter, reply_sender) => {
+                    log::debug!("stopping capture, filter={:?}", filter);
+                    let count = self.stop_capture(&filter);
+                    if let Err(error) = reply_sender.send(count) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+                Command::ListSessions(reply_sender) => {
+                    let sessions = self.sessions.keys().chain(self.raw_sessions.keys()).copied().collect::<Vec<_>>();
+                    if let Err(error) = reply_sender.send(sessions) {
+                        log::error!("failed to send result, error={:?}", error);
+                    }
+                }
+            }
+        }
+    }
+
+    fn health(&self) -> ProcessorHealth {
+        ProcessorHealth {
+            sessions: self.sessions.len() + self.raw_sessions.len(),
+            half_open_connections: self.half_open_connections(),
+            buffered_bytes: self.buffer_usage.bytes(),
+            throttled_sessions: self.sessions.values().filter(|session| session.is_throttled()).count(),
+            sessions_per_second: self.load.sessions_per_second(),
+            bytes_per_second: self.load.bytes_per_second(),
+            ..self.health.clone()
+        }
+    }
+
+    // the gauges and the moving averages describe the present, only the cumulative counters are zeroed.
+    fn reset_stats(&mut self, sessions: bool) -> StatsSnapshot {
+        let health = self.health();
+        self.health = ProcessorHealth::default();
+        let sessions = if sessions {
+            self.sessions.values_mut().map(|session| session.take_counters()).collect()
+        } else {
+            Vec::new()
+        };
+        StatsSnapshot { health, sessions }
+    }
+
+    pub(crate) fn dump_session(&mut self, session_info: &SessionInfo) -> Option<SessionDump> {
+        let session = self.sessions.get_mut(session_info)?;
+        match session.dump() {
+            Ok(dump) => Some(dump),
+            Err(error) => {
+                log::error!("failed to dump session, {:?} error={:?}", session_info, error);
+                None
+            }
+        }
+    }
+
+    fn start_capture(&mut self, filter: CaptureFilter, directory: PathBuf) -> usize {
+        self.stop_capture(&filter);
+        self.captures.start(filter.clone(), directory);
+        self.attach_captures();
+        let sessions = self.sessions.values().filter(|session| session.is_captured_by(&filter)).count();
+        sessions + self.raw_sessions.values().filter(|session| session.is_captured_by(&filter)).count()
+    }
+
+    fn stop_capture(&mut self, filter: &CaptureFilter) -> usize {
+        if !self.captures.stop(filter) {
+            return 0;
+        }
+        let mut count = 0;
+        for session in self.sessions.values_mut().filter(|session| session.is_captured_by(filter)) {
+            session.set_capture(None);
+            count += 1;
+        }
+        for session in self.raw_sessions.values_mut().filter(|session| session.is_captured_by(filter)) {
+            session.set_capture(None);
+            count += 1;
+        }
+        // the sessions may match a capture started later.
+        self.attach_captures();
+        count
+    }
+
+    // hands the sessions not recorded yet to the first capture matching them.
+    fn attach_captures(&mut self) {
+        for (session_info, session) in self.sessions.iter_mut().filter(|(_, session)| !session.is_captured()) {
+            session.set_capture(self.captures.open(session_info));
+        }
+        for (session_info, session) in self.raw_sessions.iter_mut().filter(|(_, session)| !session.is_captured()) {
+            session.set_capture(self.captures.open(session_info));
+        }
+    }
+
+    // new sessions pick the mtu up from the config, the existing ones are updated here.
+    fn set_mtu(&mut self) -> usize {
+        let mut count = 0;
+        for (session_info, session) in self.sessions.iter_mut() {
+            match session.set_mtu(&self.config) {
+                Ok(()) => count += 1,
+                Err(error) => log::error!("failed to set mtu, {:?} error={:?}", session_info, error),
+            }
+        }
+        count
+    }
+
+    #[cfg(feature = "doh")]
+    fn handle_dns_answers(&mut self) {
+        let (answers, streams) = match self.doh_resolver.as_mut() {
+            Some(resolver) => resolver.answers(),
+            None => return,
+        };
+        for (session_info, response) in answers {
+            self.write_dns_response(&session_info, &response);
+        }
+        // the responses of the DNS over TCP sessions come without a server event.
+        for session_info in streams {
+            let mut is_closed = false;
+            if let Err(error) = self.read_server_n_write_client(session_info, &mut is_closed) {
+                log::error!("failed to write dns response, {:?} error={:?}", session_info, error);
+            }
+            if is_closed {
+                if let Err(error) = self.close_upstream(&session_info) {
+                    log::error!("failed to close session upstream, error={:?}", error);
+                }
+            }
+        }
+    }
+
+    /// Destroys the UDP session whose port the ICMP error `bytes` of the guest reports unreachable,
+    /// instead of waiting for its idle timeout, returns whether `bytes` was such an error.
+    fn close_unreachable_session(&mut self, bytes: &[u8]) -> bool {
+        let Some(session_info) = icmp::closed_udp_session(bytes).filter(|session_info| self.sessions.contains_key(session_info)) else {
+            return false;
+        };
+        if let Err(error) = self.destroy_session(&session_info, DestroyReason::Unreachable) {
+            log::error!("failed to destroy session, error={:?}", error);
+        }
+        true
+    }
+
+    /// Answers DNS queries through DNS-over-HTTPS when configured, returns whether `bytes` was one.
+    #[cfg(feature = "doh")]
+    fn intercept_dns(&mut self, bytes: &[u8]) -> bool {
+        let Some(resolver) = self.doh_resolver.as_mut() else {
+            return false;
+        };
+        let mut is_closed = false;
+        let Ok(session_info) = SessionInfo::new(bytes, &mut is_closed) else {
+            return false;
+        };
+        if session_info.ip_protocol != IpProtocol::Udp || session_info.destination.port() != crate::DNS_PORT {
+            return false;
+        }
+        if !Self::is_allowed_source(&self.config, &session_info) {
+            // dropped along with the other packets of unexpected sources.
+            return false;
+        }
+        let message = match udp_packet::payload(bytes) {
+            Ok(message) => message,
+            Err(error) => {
+                log::debug!("failed to parse dns query, error={:?}", error);
+                return true;
+            }
+        };
+        if let Some(response) = resolver.resolve(session_info, message) {
+            self.write_dns_response(&session_info, &response);
+        }
+        true
+    }
+
+    #[cfg(feature = "doh")]
+    fn write_dns_response(&mut self, session_info: &SessionInfo, response: &[u8]) {
+        match udp_packet::build(session_info.destination, session_info.source, response, self.config.hop_limit) {
+            Ok(packet) => {
+                self.packet_logger.log_packet("in", &packet);
+                #[cfg(target_family = "unix")]
+                if let Err(error) = self.file.write_all(&packet) {
+                    log::error!("failed to write dns response to tun, error={:?}", error);
+                }
+            }
+            Err(error) => {
+                log::error!("failed to build dns response, error={:?}", error);
+            }
+        }
+    }
+
+    pub(crate) fn close_sessions(&mut self, predicate: SessionPredicate) -> usize {
+        let targets = self.sessions.keys().filter(|info| predicate(info)).copied().collect::<Vec<_>>();
+        for session_info in targets.iter() {
+            log::debug!("closing session on request, {:?}", session_info);
+            if let Err(error) = self.destroy_session(session_info, DestroyReason::Closed) {
+                log::error!("failed to destroy session, error={:?}", error);
+            }
+        }
+        let raw_targets = self.raw_sessions.keys().filter(|info| predicate(info)).copied().collect::<Vec<_>>();
+        for session_info in raw_targets.iter() {
+            self.destroy_raw_session(session_info, DestroyReason::Closed);
+        }
+        targets.len() + raw_targets.len()
+    }
+
+    // closes the sessions the current rules would not have created.
+    fn close_refused_sessions(&mut self) -> usize {
+        let targets = self
+            .sessions
+            .keys()
+            .filter(|info| !Self::is_allowed_source(&self.config, info) || self.is_blocked_destination(info) || self.is_blocked_quic(info))
+            .copied()
+            .collect::<Vec<_>>();
+        for session_info in targets.iter() {
+            log::debug!("closing session refused by the rules, {:?}", session_info);
+            if let Err(error) = self.destroy_session(session_info, DestroyReason::Refused) {
+                log::error!("failed to destroy session, error={:?}", error);
+            }
+        }
+        let raw_targets = self
+            .raw_sessions
+            .keys()
+            .filter(|info| !Self::is_allowed_source(&self.config, info) || self.is_blocked_destination(info))
+            .copied()
+            .collect::<Vec<_>>();
+        for session_info in raw_targets.iter() {
+            self.destroy_raw_session(session_info, DestroyReason::Refused);
+        }
+        targets.len() + raw_targets.len()
+    }
+
     fn retrieve_or_create_session(&mut self, bytes: &[u8], is_closed: &mut bool) -> crate::Result<SessionInfo> {
-        let session_info = SessionInfo::new(bytes, is_closed)?;
-        if self.sessions.get(&session_info).is_some() {
-            return Ok(session_info);
+        let session_info = SessionInfo::new(bytes, is_closed).inspect_err(|error| match error {
+            crate::Error::UnsupportedProtocol(_) => {}
+            crate::Error::ShortPacket(..) => session_error::report(None, SessionErrorCause::ShortPacket),
+            _ => session_error::report(None, SessionErrorCause::MalformedPacket),
+        })?;
+        if !self.is_accepted(&session_info) {
+            return Err(crate::Error::NotAccepted(session_info));
+        }
+        if let Some(session) = self.sessions.get_mut(&session_info) {
+            // the guest opens a new connection from the port of one it just reset or closed, the
+            // session lingering until the next reap would answer it with a RST.
+            let reopened =
+                session.is_guest_side_terminated() && tcp_packet::syn_sequence(bytes).is_some_and(|sequence| Some(sequence) != session.syn_sequence());
+            if !reopened {
+                return Ok(session_info);
+            }
+            log::debug!("replacing session, {:?}", session_info);
+            self.destroy_session(&session_info, DestroyReason::Terminated)?;
+            self.quarantine.remove(&session_info);
+        }
+        if let Some(&(_, syn_sequence)) = self.quarantine.get(&session_info).filter(|(until, _)| *until > self.clock.now()) {
+            match tcp_packet::syn_sequence(bytes) {
+                // the guest opens a new connection from the same port.
+                Some(sequence) if Some(sequence) != syn_sequence => {
+                    self.quarantine.remove(&session_info);
+                }
+                Some(_) => return Err(crate::Error::Quarantined(session_info)),
+                None => {
+                    self.write_refusal(&session_info, bytes);
+                    return Err(crate::Error::Quarantined(session_info));
+                }
+            }
+        }
+        if !Self::is_valid_destination(&session_info) {
+            return Err(crate::Error::InvalidDestination(session_info));
+        }
+        if !Self::is_allowed_source(&self.config, &session_info) {
+            return Err(crate::Error::UnexpectedSource(session_info));
+        }
+        if self.is_blocked_destination(&session_info) {
+            return Err(crate::Error::Blocked(session_info));
+        }
+        if self.is_blocked_quic(&session_info) {
+            if self.config.quic_port_unreachable {
+                self.write_refusal(&session_info, bytes);
+            }
+            return Err(crate::Error::Blocked(session_info));
+        }
+        if session_info.ip_protocol == IpProtocol::Tcp && self.config.max_half_open_connections.is_some_and(|max| self.half_open_connections() >= max) {
+            // the guest retransmits the SYN, by then some connects may have completed.
+            return Err(crate::Error::TooManyHalfOpen(session_info));
+        }
+        let destination = session_info.destination.ip();
+        if self
+            .config
+            .max_sessions_per_destination
+            .is_some_and(|max| self.destination_sessions.get(&destination).is_some_and(|count| *count >= max))
+        {
+            // the guest retransmits the SYN or the datagram, by then some sessions may have ended.
+            self.health.destination_limited += 1;
+            return Err(crate::Error::TooManyToDestination(session_info));
         }
         let token = self.generate_new_token();
-        let session = Session::new(&session_info, &mut self.poll, token)?;
+        let mut session = match Session::new(
+            &session_info,
+            &mut self.poll,
+            token,
+            &self.config,
+            self.clock.clone(),
+            self.buffer_usage.clone(),
+            &mut self.socket_pool,
+        ) {
+            Err(error @ crate::Error::SocketSetup(_)) => {
+                // abort rather than leave the guest retrying a session that cannot be set up.
+                self.write_refusal(&session_info, bytes);
+                return Err(error);
+            }
+            Err(error) => {
+                let kind = match &error {
+                    crate::Error::Io(error) => Some(error.kind()),
+                    _ => None,
+                };
+                if kind == Some(ErrorKind::AddrNotAvailable) {
+                    self.health.address_not_available += 1;
+                }
+                session_error::report(Some(session_info), SessionErrorCause::CreateFailed(kind));
+                return Err(error);
+            }
+            Ok(session) => session,
+        };
+        session.set_capture(self.captures.open(&session_info));
+        if session_info.ip_protocol == IpProtocol::Tcp {
+            session.set_syn_sequence(tcp_packet::syn_sequence(bytes));
+        }
+        session.set_priority(self.session_priority(&session_info, bytes));
         self.sessions.insert(session_info, session);
+        self.index_token(token, session_info);
+        *self.destination_sessions.entry(destination).or_default() += 1;
+        self.load.record_session();
         log::debug!("created session, {:?} {:?}", token, session_info);
         Ok(session_info)
     }
 
-    fn destroy_session(&mut self, session_info: &SessionInfo) -> crate::Result<()> {
+    fn forward_raw(&mut self, bytes: &[u8]) -> crate::Result<()> {
+        let session_info = SessionInfo::new_raw(bytes)?;
+        self.forward_packet(session_info, bytes)
+    }
+
+    // relayed through a raw session, like the protocols other than TCP and UDP. the multicast
+    // networks are among the IPv6 housekeeping ones, so this comes before dropping those.
+    fn forward_multicast(&mut self, bytes: &[u8]) -> bool {
+        if !self.config.forward_multicast {
+            return false;
+        }
+        let mut is_closed = false;
+        let Ok(session_info) = SessionInfo::new(bytes, &mut is_closed) else {
+            return false;
+        };
+        if !self.is_forwarded_multicast(&session_info) {
+            return false;
+        }
+        if let Err(error) = self.forward_packet(session_info, bytes) {
+            log::debug!("failed to forward multicast packet, error={}", error);
+        }
+        true
+    }
+
+    fn is_forwarded_multicast(&self, session_info: &SessionInfo) -> bool {
+        let is_multicast = match session_info.destination.ip() {
+            IpAddr::V4(address) => address.is_multicast() || address.is_broadcast(),
+            IpAddr::V6(address) => address.is_multicast(),
+        };
+        self.config.forward_multicast && session_info.ip_protocol == IpProtocol::Udp && is_multicast
+    }
+
+    fn forward_packet(&mut self, session_info: SessionInfo, bytes: &[u8]) -> crate::Result<()> {
+        if !self.is_accepted(&session_info) {
+            return Err(crate::Error::NotAccepted(session_info));
+        }
+        if !self.raw_sessions.contains_key(&session_info) {
+            if !Self::is_valid_destination(&session_info) {
+                return Err(crate::Error::InvalidDestination(session_info));
+            }
+            if !Self::is_allowed_source(&self.config, &session_info) {
+                return Err(crate::Error::UnexpectedSource(session_info));
+            }
+            if self.is_blocked_destination(&session_info) {
+                return Err(crate::Error::Blocked(session_info));
+            }
+            let token = self.generate_new_token();
+            let mut session = RawSession::new(&session_info, &mut self.poll, token, &self.config, self.clock.clone())?;
+            session.set_capture(self.captures.open(&session_info));
+            self.raw_sessions.insert(session_info, session);
+            self.index_token(token, session_info);
+            self.load.record_session();
+            log::debug!("created raw session, {:?} {:?}", token, session_info);
+        }
+        if let Some(session) = self.raw_sessions.get_mut(&session_info) {
+            session.write_to_server(bytes)?;
+        }
+        Ok(())
+    }
+
+    fn destroy_raw_session(&mut self, session_info: &SessionInfo, reason: DestroyReason) {
+        if let Some(mut session) = self.raw_sessions.remove(session_info) {
+            self.unindex_token(session.token, session_info);
+            session.close(&mut self.poll);
+            log::debug!("destroyed raw session, {:?} {:?} reason={:?}", session.token, session_info, reason);
+        }
+    }
+
+    fn half_open_connections(&self) -> usize {
+        self.sessions.values().filter(|session| session.is_connecting()).count()
+    }
+
+    fn is_allowed_source(config: &crate::TunConfig, session_info: &SessionInfo) -> bool {
+        let source = session_info.source.ip();
+        config.allowed_sources.is_empty() || config.allowed_sources.iter().any(|network| network.contains(&source))
+    }
+
+    fn is_blocked_destination(&self, session_info: &SessionInfo) -> bool {
+        let destination = session_info.destination.ip();
+        let multicast = self.is_forwarded_multicast(session_info);
+        self.config
+            .blocked_destinations
+            .iter()
+            .filter(|network| !(multicast && MULTICAST_NETWORKS.contains(network)))
+            .any(|network| network.contains(&destination))
+    }
+
+    // nothing can be connected to an unspecified address or port 0, only a crafted or garbled packet
+    // names one. the ports of raw sessions are always 0.
+    fn is_valid_destination(session_info: &SessionInfo) -> bool {
+        let destination = session_info.destination;
+        !destination.ip().is_unspecified() && (destination.port() != 0 || !matches!(session_info.ip_protocol, IpProtocol::Tcp | IpProtocol::Udp))
+    }
+
+    // whether the any-ip interface takes the packet at all, before any session or rule.
+    fn is_accepted(&self, session_info: &SessionInfo) -> bool {
+        let destination = session_info.destination.ip();
+        self.config.ip_version_policy.allows(session_info.ip_version) && !self.config.any_ip_excluded.iter().any(|network| network.contains(&destination))
+    }
+
+    fn is_ipv6_housekeeping(bytes: &[u8]) -> bool {
+        let Ok(packet) = Ipv6Packet::new_checked(bytes) else {
+            return false;
+        };
+        if packet.version() != 6 {
+            return false;
+        }
+        let (source, destination) = (Ipv6Addr::from(packet.src_addr()).into(), Ipv6Addr::from(packet.dst_addr()).into());
+        IPV6_HOUSEKEEPING_NETWORKS
+            .iter()
+            .any(|network| network.contains(&source) || network.contains(&destination))
+    }
+
+    fn is_blocked_quic(&self, session_info: &SessionInfo) -> bool {
+        self.config.block_quic && session_info.ip_protocol == IpProtocol::Udp && session_info.destination.port() == self.config.quic_port
+    }
+
+    /// Tells the guest that the session of `bytes` is refused, with a TCP reset or an ICMP port unreachable.
+    fn write_refusal(&mut self, session_info: &SessionInfo, bytes: &[u8]) {
+        let reply = match session_info.ip_protocol {
+            IpProtocol::Tcp => tcp_packet::reset(bytes, self.config.hop_limit),
+            _ => icmp::port_unreachable(bytes, self.config.hop_limit),
+        };
+        match reply {
+            Ok(reply) => {
+                self.packet_logger.log_packet("in", &reply);
+                #[cfg(target_family = "unix")]
+                if let Err(error) = self.file.write_all(&reply) {
+                    log::error!("failed to write refusal to tun, error={:?}", error);
+                }
+            }
+            Err(error) => {
+                log::debug!("failed to build refusal, {:?} error={:?}", session_info, error);
+            }
+        }
+    }
+
+    /// Relays the ICMP error an upstream udp socket failed with to the guest, quoting a datagram of
+    /// the session since the one that triggered it is long gone.
+    fn write_unreachable(&mut self, session_info: &SessionInfo, reason: icmp::Unreachable) {
+        let hop_limit = self.config.hop_limit;
+        let reply = udp_packet::build(session_info.source, session_info.destination, &[], hop_limit)
+            .and_then(|datagram| icmp::unreachable(&datagram, reason, hop_limit));
+        match reply {
+            Ok(reply) => {
+                log::debug!("relaying unreachable, {:?} reason={:?}", session_info, reason);
+                self.packet_logger.log_packet("in", &reply);
+                #[cfg(target_family = "unix")]
+                if let Err(error) = self.file.write_all(&reply) {
+                    log::error!("failed to write unreachable to tun, error={:?}", error);
+                }
+            }
+            Err(error) => {
+                log::debug!("failed to build unreachable, {:?} error={:?}", session_info, error);
+            }
+        }
+    }
+
+    fn destroy_session(&mut self, session_info: &SessionInfo, reason: DestroyReason) -> crate::Result<()> {
         if let Some(mut session) = self.sessions.remove(session_info) {
-            // push any pending data back to tun device before destroying session.
+            self.unindex_token(session.token, session_info);
+            let destination = session_info.destination.ip();
+            if let Some(count) = self.destination_sessions.get_mut(&destination) {
+                *count -= 1;
+                if *count == 0 {
+                    self.destination_sessions.remove(&destination);
+                }
+            }
+
+            if reason.aborts() {
+                session.abort(&mut self.poll)?;
+            } else {
+                // push all pending data back to tun device before destroying session.
+                #[cfg(target_family = "unix")]
+                session.flush_to_client(&mut self.file)?;
+                #[cfg(target_family = "windows")]
+                assert!(false, "windows not supported yet");
+
+                session.close(&mut self.poll)?;
+            }
+
+            // push the FIN or RST packet emitted by closing the smoltcp socket.
+            #[cfg(target_family = "unix")]
+            session.write_to_tun(&mut self.file)?;
+            self.load.record_bytes(session.take_transferred());
+            #[cfg(feature = "connection-log")]
+            if let Some(connection_log) = &self.connection_log {
+                connection_log.record(&session.counters(), session.age(), reason);
+            }
+            if session_info.ip_protocol == IpProtocol::Tcp && !self.config.tcp_quarantine.is_zero() {
+                let until = self.clock.now() + self.config.tcp_quarantine;
+                self.quarantine.insert(*session_info, (until, session.syn_sequence()));
+            }
+
+            log::debug!("destroyed session, {:?} {:?} reason={:?}", session.token, session_info, reason);
+            session.recycle_socket(&mut self.socket_pool);
+        }
+        Ok(())
+    }
+
+    fn close_upstream(&mut self, session_info: &SessionInfo) -> crate::Result<()> {
+        let unreachable = self.sessions.get_mut(session_info).and_then(|session| session.take_unreachable());
+        if let Some(reason) = unreachable.filter(|_| self.config.forward_icmp_errors) {
+            self.write_unreachable(session_info, reason);
+        }
+        if let Some(session) = self.sessions.get_mut(session_info) {
+            session.close_upstream(&mut self.poll);
             session.write_to_smoltcp()?;
 
             #[cfg(target_family = "unix")]
@@ -126,79 +1096,188 @@ impl<'a> Processor<'a> {
             #[cfg(target_family = "windows")]
             assert!(false, "windows not supported yet");
 
-            session.destroy(&mut self.poll)?;
-            log::debug!("destroyed session, {:?} {:?}", session.token, session_info);
+            if session.is_terminated() {
+                self.destroy_session(session_info, DestroyReason::Terminated)?;
+            }
         }
         Ok(())
     }
 
-    fn handle_tun_event(&mut self, event: &Event) -> std::io::Result<()> {
-        if event.is_readable() {
-            log::trace!("handle tun event");
+    // reads the packets of the guest until the tun is drained or a budget is spent.
+    fn read_tun(&mut self) -> std::io::Result<()> {
+        let (mut packets, mut new_sessions) = (0, 0);
+        self.tun_backlog = false;
+        loop {
+            if self.config.tun_read_budget.is_some_and(|budget| packets >= budget)
+                || self.config.new_session_budget.is_some_and(|budget| new_sessions >= budget)
+            {
+                // the rest is read in the next iteration, once the events of the existing sessions were served.
+                log::trace!("tun read budget spent, packets={} new_sessions={}", packets, new_sessions);
+                self.tun_backlog = true;
+                break;
+            }
+            #[cfg(target_family = "unix")]
+            let count = self.file.read(&mut self.tun_buffer);
+            #[cfg(target_family = "windows")]
+            let count: Result<usize, std::io::Error> = Ok(0_usize);
+            #[cfg(target_family = "windows")]
+            assert!(false, "windows not supported yet");
+            let count = match count {
+                Ok(count) => count,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    log::error!("failed to read from tun, error={:?}", error);
+                    // retry at once, a tun that is gone fails every read and would not signal
+                    // readiness again.
+                    self.tun_read_errors += 1;
+                    if self.tun_read_errors >= MAX_TUN_READ_ERRORS {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            self.tun_read_errors = 0;
+            if count == 0 {
+                break;
+            }
+            let read_buffer = self.tun_buffer[..count].to_vec();
+
+            if self.forward_multicast(&read_buffer) {
+                continue;
+            }
+
+            if self.config.drop_ipv6_housekeeping && Self::is_ipv6_housekeeping(&read_buffer) {
+                log::trace!("dropped ipv6 housekeeping packet, len={}", read_buffer.len());
+                continue;
+            }
+
+            #[cfg(feature = "doh")]
+            if self.intercept_dns(&read_buffer) {
+                continue;
+            }
+
+            if self.close_unreachable_session(&read_buffer) {
+                continue;
+            }
+
+            packets += 1;
+            let mut is_closed = false;
+            let session_count = self.sessions.len();
+            let session_info = self.retrieve_or_create_session(&read_buffer, &mut is_closed);
+            if self.sessions.len() > session_count {
+                new_sessions += 1;
+            }
+            if let Err(error) = session_info {
+                match error {
+                    crate::Error::Blocked(_)
+                    | crate::Error::NotAccepted(_)
+                    | crate::Error::TooManyHalfOpen(_)
+                    | crate::Error::TooManyToDestination(_)
+                    | crate::Error::Quarantined(_) => log::trace!("{}", error),
+                    crate::Error::UnexpectedSource(_) | crate::Error::InvalidDestination(_) => log::debug!("dropped packet, error={}", error),
+                    crate::Error::ShortPacket(..) => {
+                        log::debug!("dropped short packet, error={}", error);
+                        self.health.short_packets += 1;
+                    }
+                    crate::Error::UnsupportedProtocol(_) if self.config.unsupported_protocols == crate::UnsupportedProtocols::ForwardRaw => {
+                        if let Err(error) = self.forward_raw(&read_buffer) {
+                            log::debug!("failed to forward raw packet, error={}", error);
+                        }
+                    }
+                    _ => log::info!("failed to create session, error={}", error),
+                }
+                continue;
+            }
+            let session_info = session_info?;
+            let mut is_upstream_closed = false;
+            let mut continue_read = false;
+            if let Some(session) = self.sessions.get_mut(&session_info) {
+                session.store_tun_data(read_buffer);
 
-            let mut buffer = [0_u8; crate::MAX_PACKET_SIZE];
-            loop {
                 #[cfg(target_family = "unix")]
-                let count = self.file.read(&mut buffer);
-                #[cfg(target_family = "windows")]
-                let count: Result<usize, std::io::Error> = Ok(0_usize);
+                session.write_to_tun(&mut self.file)?;
                 #[cfg(target_family = "windows")]
                 assert!(false, "windows not supported yet");
-                if let Err(error) = count {
-                    if error.kind() != ErrorKind::WouldBlock {
-                        log::error!("failed to read from tun, error={:?}", error);
-                    }
-                    break;
-                }
-                let count = count?;
-                if count == 0 {
-                    break;
-                }
-                let read_buffer = buffer[..count].to_vec();
 
-                let mut is_closed = false;
-                let session_info = self.retrieve_or_create_session(&read_buffer, &mut is_closed);
-                if let Err(error) = session_info {
-                    log::info!("failed to create session, error={}", error);
-                    continue;
-                }
-                let session_info = session_info?;
-                if let Some(session) = self.sessions.get_mut(&session_info) {
-                    session.store_tun_data(read_buffer);
+                session.read_from_smoltcp()?;
+                session.write_to_server(&mut is_upstream_closed)?;
+
+                if session.has_pending_client_data() {
+                    // the guest may have acknowledged data, so smoltcp can take more from the server.
+                    session.write_to_smoltcp()?;
 
                     #[cfg(target_family = "unix")]
                     session.write_to_tun(&mut self.file)?;
-                    #[cfg(target_family = "windows")]
-                    assert!(false, "windows not supported yet");
+                }
 
-                    session.read_from_smoltcp()?;
-                    session.write_to_server(&mut is_closed)?;
+                // server data left unread for lack of room is fetched once smoltcp took everything,
+                // no server event announces it again.
+                continue_read = (session.continue_read() || session.has_unannounced_server_data()) && !session.has_pending_client_data();
 
-                    // delay tcp socket close to avoid RST packet
-                    session.update_expiry_timestamp(is_closed);
+                // delay tcp socket close by the close grace to avoid RST packet. a guest that only ended its sending
+                // half still waits for the answer of the server, the session lives on.
+                let is_closed = is_closed && !session.is_half_closed();
+                session.update_expiry_timestamp(is_closed || is_upstream_closed);
+            }
+            if continue_read {
+                self.read_server_n_write_client(session_info, &mut is_upstream_closed)?;
+            }
+            if is_upstream_closed {
+                if let Err(error) = self.close_upstream(&session_info) {
+                    log::error!("failed to close session upstream, error={:?}", error);
                 }
             }
+            // a burst of the guest can fill the buffers within a single event.
+            self.enforce_buffer_budget()?;
+        }
+        Ok(())
+    }
+
+    fn handle_tun_event(&mut self, event: &Event) -> std::io::Result<()> {
+        if event.is_readable() {
+            log::trace!("handle tun event");
+            self.read_tun()?;
         }
         if event.is_writable() {
-            let targets = self.sessions.iter().filter(|(_, s)| s.continue_read()).map(|(i, _)| *i).collect::<Vec<_>>();
+            // the tun is practically always writable, the event comes along with every readable one.
+            // only the sessions able to take more server data are worth a visit, a session stalled
+            // on a slow guest would only poll smoltcp for nothing.
+            let targets = self.sessions.iter().filter(|(_, s)| s.can_continue_read()).map(|(i, _)| *i).collect::<Vec<_>>();
             for session_info in targets {
                 let mut is_closed = false;
                 self.read_server_n_write_client(session_info, &mut is_closed)?;
+                if is_closed {
+                    if let Err(error) = self.close_upstream(&session_info) {
+                        log::error!("failed to close session upstream, error={:?}", error);
+                    }
+                }
             }
         }
         Ok(())
     }
 
     fn read_server_n_write_client(&mut self, session_info: SessionInfo, is_closed: &mut bool) -> crate::Result<()> {
+        self.read_server_n_write_smoltcp(session_info, is_closed)?;
         if let Some(session) = self.sessions.get_mut(&session_info) {
-            let mut _is_closed = false;
-            session.read_from_server(&mut _is_closed)?;
-            session.write_to_smoltcp()?;
-
             #[cfg(target_family = "unix")]
             session.write_to_tun(&mut self.file)?;
             #[cfg(target_family = "windows")]
             assert!(false, "windows not supported yet");
+        }
+        Ok(())
+    }
+
+    // hands the server data to smoltcp, the packets it makes of them are left for a write to tun.
+    fn read_server_n_write_smoltcp(&mut self, session_info: SessionInfo, is_closed: &mut bool) -> crate::Result<()> {
+        if let Some(session) = self.sessions.get_mut(&session_info) {
+            let mut _is_closed = false;
+            session.read_from_server(&mut _is_closed)?;
+            if let Some(latency) = session.take_first_byte_latency() {
+                log::trace!("first byte from server, {:?} latency={:?}", session_info, latency);
+                self.health.first_byte_latency.record(latency);
+            }
+            session.write_to_smoltcp()?;
 
             session.update_expiry_timestamp(_is_closed);
             *is_closed = _is_closed;
@@ -207,31 +1286,64 @@ impl<'a> Processor<'a> {
     }
 
     fn handle_server_event(&mut self, event: &Event) -> crate::Result<()> {
-        if let Some((session_info, _)) = self.sessions.iter().find(|(_, session)| session.token == event.token()) {
-            let session_info = *session_info;
-
+        let Some(session_info) = self.token_session(event.token()) else {
+            log::trace!("event of no session, {:?}", event.token());
+            return Ok(());
+        };
+        if let Some(session) = self.raw_sessions.get_mut(&session_info) {
+            #[cfg(target_family = "unix")]
+            session.write_to_tun(&mut self.file)?;
+            #[cfg(target_family = "windows")]
+            assert!(false, "windows not supported yet");
+            return Ok(());
+        }
+        if self.sessions.contains_key(&session_info) {
             let mut is_closed = false;
+            // an event can be readable and writable at once, the smoltcp socket is polled and its
+            // packets written to tun once for both, after all the steps that changed it.
+            let mut smoltcp_changed = false;
             if event.is_readable() {
                 log::trace!("handle server event read, {:?}", session_info);
 
-                self.read_server_n_write_client(session_info, &mut is_closed)?;
+                self.read_server_n_write_smoltcp(session_info, &mut is_closed)?;
+                smoltcp_changed = true;
             }
             if event.is_writable() {
                 log::trace!("handle server event write, {:?}", session_info);
 
                 if let Some(session) = self.sessions.get_mut(&session_info) {
-                    session.read_from_smoltcp()?;
+                    if let Some(latency) = session.take_connect_latency() {
+                        log::trace!("connected to server, {:?} latency={:?}", session_info, latency);
+                        self.health.connect_latency.record(latency);
+                    }
+                    // the window update of data read from smoltcp has to be emitted, no tun event may
+                    // follow to do it. writing to the server alone leaves smoltcp as it was.
+                    smoltcp_changed |= session.read_from_smoltcp()?;
                     session.write_to_server(&mut is_closed)?;
                 }
             }
-            let force_set = event.is_read_closed() || event.is_write_closed() || is_closed;
+            if smoltcp_changed {
+                if let Some(session) = self.sessions.get_mut(&session_info) {
+                    #[cfg(target_family = "unix")]
+                    session.write_to_tun(&mut self.file)?;
+                    #[cfg(target_family = "windows")]
+                    assert!(false, "windows not supported yet");
+                }
+            }
+            let mut force_set = event.is_read_closed() || event.is_write_closed() || is_closed;
             if let Some(session) = self.sessions.get_mut(&session_info) {
+                if event.is_error() {
+                    session.take_upstream_error();
+                }
+                // unread server data is still pending, the close is handled once it has been read.
+                force_set &= !session.continue_read();
                 session.update_expiry_timestamp(force_set);
             }
             if force_set {
-                // since the session is closed by server, we can destroy it immediately.
-                if let Err(error) = self.destroy_session(&session_info) {
-                    log::error!("failed to destroy session, error={:?}", error);
+                // the server is gone, but the guest still has to receive the remaining data
+                // and complete the close handshake before the session can be destroyed.
+                if let Err(error) = self.close_upstream(&session_info) {
+                    log::error!("failed to close session upstream, error={:?}", error);
                 }
             }
         }
@@ -239,11 +1351,28 @@ impl<'a> Processor<'a> {
     }
 
     fn clearup_expired_sessions(&mut self) {
-        let expired_sessions = self.sessions.iter().filter(|(_, s)| s.is_expired()).map(|(i, _)| *i).collect::<Vec<_>>();
-        for session_info in expired_sessions {
-            if let Err(error) = self.destroy_session(&session_info) {
+        let expired_sessions = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(i, s)| match (s.is_terminated(), s.is_expired()) {
+                (true, _) => Some((*i, DestroyReason::Terminated)),
+                (false, true) => Some((*i, DestroyReason::Expired)),
+                (false, false) => None,
+            })
+            .collect::<Vec<_>>();
+        for (session_info, reason) in expired_sessions {
+            if let Err(error) = self.destroy_session(&session_info, reason) {
                 log::error!("failed to destroy session, error={:?}", error);
             }
         }
+        let expired_raw_sessions = self.raw_sessions.iter().filter(|(_, s)| s.is_expired()).map(|(i, _)| *i).collect::<Vec<_>>();
+        for session_info in expired_raw_sessions {
+            self.destroy_raw_session(&session_info, DestroyReason::Expired);
+        }
+        let now = self.clock.now();
+        self.quarantine.retain(|_, (until, _)| *until > now);
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1190 @@
+use super::*;
+use crate::vpn::{clock::ManualClock, health::CONNECT_LATENCY_BOUNDS, mio_socket, upstream::UpstreamTransport};
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    socket::{tcp, udp},
+    wire::{IpAddress, IpVersion, Ipv4Packet, Ipv4Repr, TcpControl, TcpPacket, TcpRepr},
+};
+use std::{
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::unix::{io::IntoRawFd, net::UnixDatagram},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+const GUEST: &str = "10.0.0.2:40000";
+
+// long enough for a loaded machine, the packets of the processor usually come within milliseconds.
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A processor running on a thread of its own, with a datagram socket pair standing in for the
+/// tun: every datagram the test sends is a packet of the guest, and the other way round.
+struct TestProcessor {
+    guest: UnixDatagram,
+    command_sender: CommandSender,
+    exit_flag: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl TestProcessor {
+    fn start(config: crate::TunConfig) -> TestProcessor {
+        Self::start_with(config, |_| {})
+    }
+
+    fn start_with(config: crate::TunConfig, prepare: impl FnOnce(&mut Processor<'static>)) -> TestProcessor {
+        Self::launch(config, prepare, |mut processor| processor.run())
+    }
+
+    /// A processor that failed with the kill switch set, only dropping the packets of the guest.
+    fn start_dropping(config: crate::TunConfig) -> TestProcessor {
+        Self::launch(
+            config,
+            |_| {},
+            |mut processor| {
+                processor.drop_packets();
+                Ok(())
+            },
+        )
+    }
+
+    fn launch(
+        config: crate::TunConfig,
+        prepare: impl FnOnce(&mut Processor<'static>),
+        body: impl FnOnce(Processor<'static>) -> std::io::Result<()> + Send + 'static,
+    ) -> TestProcessor {
+        let (guest, tun) = UnixDatagram::pair().unwrap();
+        tun.set_nonblocking(true).unwrap();
+        guest.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        let mut processor = Processor::new(tun.into_raw_fd(), config).unwrap();
+        prepare(&mut processor);
+        let (command_sender, exit_flag, waker) = (processor.command_sender(), processor.exit_flag(), processor.new_stop_waker());
+        let thread = std::thread::spawn(move || body(processor));
+        TestProcessor {
+            guest,
+            command_sender,
+            exit_flag,
+            waker,
+            thread: Some(thread),
+        }
+    }
+
+    fn send(&self, packet: &[u8]) {
+        self.guest.send(packet).unwrap();
+    }
+
+    /// The next TCP segment written to the tun, unset when none comes in time.
+    fn receive(&self) -> Option<Segment> {
+        let mut buffer = vec![0; crate::MAX_PACKET_SIZE];
+        let count = self.guest.recv(&mut buffer).ok()?;
+        Some(Segment::parse(&buffer[..count]))
+    }
+
+    /// The next TCP segment written to the tun when one is waiting already.
+    fn try_receive(&self) -> Option<Segment> {
+        self.guest.set_nonblocking(true).unwrap();
+        let segment = self.receive();
+        self.guest.set_nonblocking(false).unwrap();
+        segment
+    }
+
+    /// The payload of the next UDP datagram written to the tun, unset when none comes in time.
+    fn receive_datagram(&self) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; crate::MAX_PACKET_SIZE];
+        let count = self.guest.recv(&mut buffer).ok()?;
+        Some(udp_packet::payload(&buffer[..count]).unwrap().to_vec())
+    }
+
+    /// The TCP segments written to the tun until none comes for `quiet`.
+    fn receive_until_quiet(&self, quiet: Duration) -> Vec<Segment> {
+        self.guest.set_read_timeout(Some(quiet)).unwrap();
+        let segments = std::iter::from_fn(|| self.receive()).collect();
+        self.guest.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        segments
+    }
+
+    fn sessions(&self) -> Vec<SessionInfo> {
+        self.command_sender.sessions().unwrap()
+    }
+}
+
+impl Drop for TestProcessor {
+    fn drop(&mut self) {
+        self.exit_flag.store(true, Ordering::Relaxed);
+        self.waker.wake().unwrap();
+        if let Some(thread) = self.thread.take() {
+            let result = thread.join();
+            // a failed test is not hidden by a second panic while unwinding.
+            if !std::thread::panicking() {
+                result.unwrap().unwrap();
+            }
+        }
+    }
+}
+
+/// The fields of a TCP segment the tests look at, copied out of the packet.
+#[derive(Debug)]
+struct Segment {
+    control: TcpControl,
+    seq_number: TcpSeqNumber,
+    ack_number: Option<TcpSeqNumber>,
+    window_len: u16,
+    payload: Vec<u8>,
+}
+
+impl Segment {
+    fn parse(bytes: &[u8]) -> Segment {
+        let packet = Ipv4Packet::new_checked(bytes).unwrap();
+        let (src_addr, dst_addr) = (IpAddress::Ipv4(packet.src_addr()), IpAddress::Ipv4(packet.dst_addr()));
+        let tcp_packet = TcpPacket::new_checked(packet.payload()).unwrap();
+        let tcp_repr = TcpRepr::parse(&tcp_packet, &src_addr, &dst_addr, &ChecksumCapabilities::default()).unwrap();
+        Segment {
+            control: tcp_repr.control,
+            seq_number: tcp_repr.seq_number,
+            ack_number: tcp_repr.ack_number,
+            window_len: tcp_repr.window_len,
+            payload: tcp_repr.payload.to_vec(),
+        }
+    }
+
+    /// The sequence number following the segment.
+    fn end(&self) -> TcpSeqNumber {
+        let control_len = usize::from(matches!(self.control, TcpControl::Syn | TcpControl::Fin));
+        self.seq_number + self.payload.len() + control_len
+    }
+}
+
+/// The guest end of a TCP connection, building its segments from the sequence numbers it tracks.
+struct Guest {
+    source: SocketAddr,
+    destination: SocketAddr,
+    seq_number: TcpSeqNumber,
+    ack_number: Option<TcpSeqNumber>,
+}
+
+impl Guest {
+    fn new(destination: SocketAddr) -> Guest {
+        Guest {
+            source: GUEST.parse().unwrap(),
+            destination,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+        }
+    }
+
+    fn segment(&mut self, control: TcpControl, payload: &[u8]) -> Vec<u8> {
+        let (SocketAddr::V4(source), SocketAddr::V4(destination)) = (self.source, self.destination) else {
+            panic!("the tests use IPv4");
+        };
+        let tcp_repr = TcpRepr {
+            src_port: source.port(),
+            dst_port: destination.port(),
+            control,
+            seq_number: self.seq_number,
+            ack_number: self.ack_number,
+            window_len: 0xffff,
+            window_scale: None,
+            max_seg_size: (control == TcpControl::Syn).then_some(1400),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr: (*source.ip()).into(),
+            dst_addr: (*destination.ip()).into(),
+            next_header: IpProtocol::Tcp,
+            payload_len: tcp_repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let checksum = ChecksumCapabilities::default();
+        let mut buffer = vec![0; ip_repr.buffer_len() + tcp_repr.buffer_len()];
+        ip_repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer[..]), &checksum);
+        let (src_addr, dst_addr) = (IpAddress::Ipv4(ip_repr.src_addr), IpAddress::Ipv4(ip_repr.dst_addr));
+        tcp_repr.emit(
+            &mut TcpPacket::new_unchecked(&mut buffer[ip_repr.buffer_len()..]),
+            &src_addr,
+            &dst_addr,
+            &checksum,
+        );
+        let control_len = usize::from(matches!(control, TcpControl::Syn | TcpControl::Fin));
+        self.seq_number += payload.len() + control_len;
+        buffer
+    }
+
+    /// Acknowledges everything up to the end of `segment`.
+    fn acknowledge(&mut self, segment: &Segment) {
+        self.ack_number = Some(segment.end());
+    }
+}
+
+// a server on the loopback interface stands in for the destination, nothing leaves the host.
+fn config() -> crate::TunConfig {
+    let mut config = crate::TunConfig {
+        reaper_interval: Duration::from_millis(50),
+        tcp_close_grace: Duration::from_millis(100),
+        ..crate::TunConfig::default()
+    };
+    config.blocked_destinations.clear();
+    config
+}
+
+// completes the handshake of `guest` through `processor`, returning the stream accepted by the server.
+fn connect(processor: &TestProcessor, guest: &mut Guest, listener: &TcpListener) -> TcpStream {
+    processor.send(&guest.segment(TcpControl::Syn, &[]));
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let syn_ack = processor.receive().expect("no SYN-ACK");
+    assert_eq!(syn_ack.control, TcpControl::Syn);
+    assert_eq!(syn_ack.ack_number, Some(guest.seq_number));
+    guest.acknowledge(&syn_ack);
+    processor.send(&guest.segment(TcpControl::None, &[]));
+    stream
+}
+
+// the segments of the processor until its FIN, acknowledging them, with their payloads.
+fn receive_until_fin(processor: &TestProcessor, guest: &mut Guest) -> (Vec<Segment>, Vec<u8>) {
+    let mut segments = Vec::new();
+    let mut payload = Vec::new();
+    while !segments.iter().any(|segment: &Segment| segment.control == TcpControl::Fin) {
+        let segment = processor.receive().expect("no FIN");
+        assert_ne!(segment.control, TcpControl::Rst, "reset before the FIN, {:?}", segments);
+        payload.extend_from_slice(&segment.payload);
+        guest.acknowledge(&segment);
+        segments.push(segment);
+    }
+    (segments, payload)
+}
+
+fn wait_for_no_sessions(processor: &TestProcessor) {
+    let deadline = std::time::Instant::now() + READ_TIMEOUT;
+    while !processor.sessions().is_empty() {
+        assert!(std::time::Instant::now() < deadline, "session not destroyed, {:?}", processor.sessions());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn assert_no_reset(segments: &[Segment]) {
+    assert!(segments.iter().all(|segment| segment.control != TcpControl::Rst), "reset, {:?}", segments);
+}
+
+#[test]
+fn server_close_ends_without_reset() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+
+    stream.write_all(b"hello").unwrap();
+    drop(stream);
+    let (_, payload) = receive_until_fin(&processor, &mut guest);
+    assert_eq!(payload, b"hello");
+
+    // the processor closed first, the FIN of the guest completes the handshake.
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    let last_ack = processor.receive().expect("no ACK of the FIN");
+    assert_eq!(last_ack.control, TcpControl::None);
+    assert_eq!(last_ack.ack_number, Some(guest.seq_number));
+
+    wait_for_no_sessions(&processor);
+    assert_no_reset(&processor.receive_until_quiet(Duration::from_millis(200)));
+}
+
+#[test]
+fn guest_close_ends_without_reset() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+
+    processor.send(&guest.segment(TcpControl::Psh, b"ping"));
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    // the FIN of the guest is passed on once the data before it was written.
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"ping");
+
+    stream.write_all(b"pong").unwrap();
+    stream.shutdown(Shutdown::Both).unwrap();
+    let (segments, payload) = receive_until_fin(&processor, &mut guest);
+    assert_eq!(payload, b"pong");
+    assert_eq!(segments.last().unwrap().ack_number, Some(guest.seq_number), "FIN of the guest not acknowledged");
+
+    processor.send(&guest.segment(TcpControl::None, &[]));
+    wait_for_no_sessions(&processor);
+    assert_no_reset(&processor.receive_until_quiet(Duration::from_millis(200)));
+}
+
+#[test]
+fn commands_fail_while_dropping_packets() {
+    let processor = TestProcessor::start_dropping(config());
+    let started = std::time::Instant::now();
+    assert!(matches!(processor.command_sender.sessions(), Err(crate::Error::NotRunning)));
+    // the dropped command fails its caller right away, not after the command timeout.
+    assert!(started.elapsed() < Duration::from_secs(crate::COMMAND_TIMEOUT));
+}
+
+// the expiry follows the clock of the processor alone, real time passing does not end a session.
+#[test]
+fn udp_session_expires_after_udp_timeout() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let clock = Arc::new(ManualClock::new());
+    let config = config();
+    let (udp_timeout, reaper_interval) = (config.udp_timeout, config.reaper_interval);
+    let processor = TestProcessor::start_with(config, |processor| processor.set_clock(clock.clone()));
+
+    let packet = udp_packet::build(GUEST.parse().unwrap(), server.local_addr().unwrap(), b"ping", 64).unwrap();
+    processor.send(&packet);
+    let mut buffer = [0; 16];
+    assert_eq!(server.recv(&mut buffer).unwrap(), 4);
+    assert_eq!(processor.sessions().len(), 1);
+
+    std::thread::sleep(Duration::from_millis(200));
+    // the reap then due is the one at the udp timeout, the clock does not move on to a later one.
+    clock.advance(udp_timeout - reaper_interval);
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(processor.sessions().len(), 1, "expired before the udp timeout");
+
+    clock.advance(reaper_interval);
+    wait_for_no_sessions(&processor);
+}
+
+#[test]
+fn tcp_session_expires_after_close_grace() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let clock = Arc::new(ManualClock::new());
+    let config = config();
+    let tcp_close_grace = config.tcp_close_grace;
+    let processor = TestProcessor::start_with(config, |processor| processor.set_clock(clock.clone()));
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let stream = connect(&processor, &mut guest, &listener);
+
+    // the server closes and the guest never answers the FIN, only the close grace ends the session.
+    drop(stream);
+    let fin = processor.receive().expect("no FIN");
+    assert_eq!(fin.control, TcpControl::Fin);
+    std::thread::sleep(tcp_close_grace * 3);
+    assert_eq!(processor.sessions().len(), 1, "expired before the close grace");
+
+    clock.advance(tcp_close_grace);
+    wait_for_no_sessions(&processor);
+}
+
+fn session_info(ip_protocol: IpProtocol, destination: &str) -> SessionInfo {
+    let destination: SocketAddr = destination.parse().unwrap();
+    let (source, ip_version) = match destination {
+        SocketAddr::V4(_) => (GUEST.parse().unwrap(), IpVersion::Ipv4),
+        SocketAddr::V6(_) => ("[fd00::2]:40000".parse().unwrap(), IpVersion::Ipv6),
+    };
+    SessionInfo {
+        source,
+        destination,
+        ip_protocol,
+        ip_version,
+    }
+}
+
+#[test]
+fn unconnectable_destinations_are_invalid() {
+    for ip_protocol in [IpProtocol::Tcp, IpProtocol::Udp] {
+        for destination in ["0.0.0.0:0", "[::]:0", "0.0.0.0:443", "[::]:443", "10.0.0.4:0", "[fd00::4]:0"] {
+            assert!(
+                !Processor::is_valid_destination(&session_info(ip_protocol, destination)),
+                "{} {}",
+                ip_protocol,
+                destination
+            );
+        }
+        for destination in ["10.0.0.4:443", "[fd00::4]:53"] {
+            assert!(
+                Processor::is_valid_destination(&session_info(ip_protocol, destination)),
+                "{} {}",
+                ip_protocol,
+                destination
+            );
+        }
+    }
+    // raw sessions have no ports, only their address is checked.
+    for ip_protocol in [IpProtocol::Icmp, IpProtocol::Unknown(132)] {
+        assert!(Processor::is_valid_destination(&session_info(ip_protocol, "10.0.0.4:0")));
+        assert!(!Processor::is_valid_destination(&session_info(ip_protocol, "0.0.0.0:0")));
+        assert!(!Processor::is_valid_destination(&session_info(ip_protocol, "[::]:0")));
+    }
+}
+
+#[test]
+fn tcp_and_udp_on_same_addresses_are_separate_sessions() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = UdpSocket::bind(listener.local_addr().unwrap()).unwrap();
+    server.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let _stream = connect(&processor, &mut guest, &listener);
+
+    processor.send(&udp_packet::build(guest.source, guest.destination, b"ping", 64).unwrap());
+    let mut buffer = [0; 16];
+    assert_eq!(server.recv(&mut buffer).unwrap(), 4);
+
+    let mut sessions = processor.sessions();
+    sessions.sort();
+    let protocols = sessions.iter().map(|session_info| session_info.ip_protocol).collect::<Vec<_>>();
+    assert_eq!(protocols, vec![IpProtocol::Tcp, IpProtocol::Udp]);
+    assert!(sessions
+        .iter()
+        .all(|session_info| (session_info.source, session_info.destination) == (guest.source, guest.destination)));
+}
+
+// a processor that is not running, its session table is driven by hand. the guest end of the tun
+// is returned to keep it open.
+fn idle_processor(config: crate::TunConfig) -> (Processor<'static>, UnixDatagram) {
+    let (guest, tun) = UnixDatagram::pair().unwrap();
+    tun.set_nonblocking(true).unwrap();
+    (Processor::new(tun.into_raw_fd(), config).unwrap(), guest)
+}
+
+fn create_udp_session(processor: &mut Processor<'static>, server: &UdpSocket, source_port: u16) -> SessionInfo {
+    let source = SocketAddr::new(GUEST.parse::<SocketAddr>().unwrap().ip(), source_port);
+    let packet = udp_packet::build(source, server.local_addr().unwrap(), b"ping", 64).unwrap();
+    processor.retrieve_or_create_session(&packet, &mut false).unwrap()
+}
+
+#[test]
+fn stale_token_matches_no_session() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (mut processor, _guest) = idle_processor(config());
+    processor.set_next_token(100);
+    let first = create_udp_session(&mut processor, &server, 40000);
+    assert_eq!(processor.token_session(Token(100)), Some(first));
+
+    processor.destroy_session(&first, DestroyReason::Expired).unwrap();
+    // a session created later in the same batch of events gets a token of its own, a pending event
+    // of the destroyed session finds nothing.
+    let second = create_udp_session(&mut processor, &server, 40001);
+    assert_eq!(processor.token_session(Token(100)), None);
+    assert_eq!(processor.token_session(Token(101)), Some(second));
+    assert_eq!(processor.tokens.len(), 1);
+
+    processor.destroy_session(&second, DestroyReason::Expired).unwrap();
+    assert!(processor.tokens.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "token of a live session reused")]
+fn reused_live_token_is_caught() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (mut processor, _guest) = idle_processor(config());
+    processor.set_next_token(100);
+    create_udp_session(&mut processor, &server, 40000);
+    processor.set_next_token(100);
+    create_udp_session(&mut processor, &server, 40001);
+}
+
+// bytes of a pattern whose period is prime to the segment sizes, so lost, repeated or reordered
+// segments show.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// writes `data` from the server and ends its sending half, on a thread of its own.
+fn serve(mut stream: TcpStream, data: &[u8]) -> JoinHandle<TcpStream> {
+    let data = data.to_vec();
+    std::thread::spawn(move || {
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        stream
+    })
+}
+
+// the segments of the processor until its FIN, with their payloads, acknowledging each one at once.
+// the transfer stalls once the window of the guest is in flight without an ACK.
+fn download(processor: &TestProcessor, guest: &mut Guest) -> (Vec<Segment>, Vec<u8>) {
+    let mut segments = Vec::new();
+    let mut payload = Vec::new();
+    while !segments.iter().any(|segment: &Segment| segment.control == TcpControl::Fin) {
+        let segment = processor.receive().expect("transfer stalled");
+        assert_ne!(segment.control, TcpControl::Rst, "reset during the transfer");
+        payload.extend_from_slice(&segment.payload);
+        guest.acknowledge(&segment);
+        processor.send(&guest.segment(TcpControl::None, &[]));
+        segments.push(segment);
+    }
+    (segments, payload)
+}
+
+#[test]
+fn bulk_download_fills_segments() {
+    const MSS: usize = 1400;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let stream = connect(&processor, &mut guest, &listener);
+
+    let data = pattern(1024 * 1024);
+    let server = serve(stream, &data);
+    let (segments, payload) = download(&processor, &mut guest);
+    assert!(segments.iter().all(|segment| segment.payload.len() <= MSS), "segment above the MSS");
+    assert!(payload == data, "received {} of {} bytes, or corrupted", payload.len(), data.len());
+    // only the segments at the edge of the window are partial, the reads from the server do not
+    // turn into small segments.
+    let data_segments = segments.iter().filter(|segment| !segment.payload.is_empty()).count();
+    let full_segments = data.len().div_ceil(MSS);
+    assert!(
+        data_segments <= full_segments * 11 / 10,
+        "{} segments for {} full ones",
+        data_segments,
+        full_segments
+    );
+    server.join().unwrap();
+}
+
+// closes the session on request, which resets the guest side. the server got data of the guest
+// before, so no segment of the guest is left to arrive after the abort and be refused with a RST.
+fn abort_session(processor: &TestProcessor, guest: &mut Guest, stream: &mut TcpStream) {
+    processor.send(&guest.segment(TcpControl::Psh, b"ping"));
+    let mut buffer = [0; 4];
+    stream.read_exact(&mut buffer).unwrap();
+    let ack = processor.receive().expect("no ACK of the data");
+    assert_eq!(ack.ack_number, Some(guest.seq_number));
+
+    assert_eq!(processor.command_sender.close_sessions(|_| true).unwrap(), 1);
+    let reset = processor.receive().expect("no RST");
+    assert_eq!(reset.control, TcpControl::Rst);
+}
+
+#[test]
+fn reconnect_after_abort_is_accepted() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+    abort_session(&processor, &mut guest, &mut stream);
+
+    // the app connects again from the same port, with another initial sequence number.
+    let mut guest = Guest {
+        seq_number: TcpSeqNumber(5000),
+        ..Guest::new(listener.local_addr().unwrap())
+    };
+    let mut stream = connect(&processor, &mut guest, &listener);
+    processor.send(&guest.segment(TcpControl::Psh, b"pong"));
+    let mut buffer = [0; 4];
+    stream.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"pong");
+    assert_eq!(processor.sessions().len(), 1);
+}
+
+#[test]
+fn replayed_syn_is_dropped_while_quarantined() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+    abort_session(&processor, &mut guest, &mut stream);
+
+    // a late retransmission of the SYN that opened the aborted session.
+    let replayed_syn = Guest::new(listener.local_addr().unwrap()).segment(TcpControl::Syn, &[]);
+    processor.send(&replayed_syn);
+    assert!(processor.receive_until_quiet(Duration::from_millis(200)).is_empty());
+    assert!(processor.sessions().is_empty());
+    listener.set_nonblocking(true).unwrap();
+    assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock, "connected to the server again");
+}
+
+#[test]
+fn quarantine_ends_after_tcp_quarantine() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let clock = Arc::new(ManualClock::new());
+    let (mut processor, _guest) = idle_processor(config());
+    processor.set_clock(clock.clone());
+    let syn = Guest::new(listener.local_addr().unwrap()).segment(TcpControl::Syn, &[]);
+    let session_info = processor.retrieve_or_create_session(&syn, &mut false).unwrap();
+    processor.destroy_session(&session_info, DestroyReason::Closed).unwrap();
+    assert!(processor.quarantine.contains_key(&session_info));
+    assert!(matches!(
+        processor.retrieve_or_create_session(&syn, &mut false),
+        Err(crate::Error::Quarantined(_))
+    ));
+
+    clock.advance(processor.config.tcp_quarantine - Duration::from_millis(1));
+    processor.clearup_expired_sessions();
+    assert!(processor.quarantine.contains_key(&session_info), "released before the end of the quarantine");
+    clock.advance(Duration::from_millis(1));
+    processor.clearup_expired_sessions();
+    assert!(processor.quarantine.is_empty());
+    // the same SYN connects again.
+    assert_eq!(processor.retrieve_or_create_session(&syn, &mut false).unwrap(), session_info);
+}
+
+/// What the test upstream does with the writes of the processor, shared with the test.
+#[derive(Default)]
+struct UpstreamControl {
+    // the number of next writes refused with WouldBlock, like a socket whose send buffer is full
+    // but without the writable event that would follow.
+    blocked_writes: AtomicUsize,
+    // the stream accepts nothing anymore, its writes fail like those of the direct sockets do then.
+    write_zero: AtomicBool,
+    // every write tried.
+    writes: AtomicUsize,
+}
+
+/// The direct sockets, with their writes going through an `UpstreamControl`.
+struct TestUpstream {
+    socket: mio_socket::Socket,
+    control: Arc<UpstreamControl>,
+}
+
+impl UpstreamTransport for TestUpstream {
+    fn register_poll(&mut self, poll: &mut mio::Poll, token: Token) -> std::io::Result<()> {
+        self.socket.register_poll(poll, token)
+    }
+
+    fn deregister_poll(&mut self, poll: &mut mio::Poll) -> std::io::Result<()> {
+        self.socket.deregister_poll(poll)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.control.writes.fetch_add(1, Ordering::SeqCst);
+        if self.control.write_zero.load(Ordering::SeqCst) {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        if self
+            .control
+            .blocked_writes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1))
+            .is_ok()
+        {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        self.socket.write(bytes)
+    }
+
+    fn read(&mut self, is_closed: &mut bool, callback: &mut dyn FnMut(&mut [u8]) -> std::io::Result<()>) -> std::io::Result<()> {
+        self.socket.read(is_closed, callback)
+    }
+
+    fn is_connected(&self) -> std::io::Result<bool> {
+        self.socket.is_connected()
+    }
+
+    fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        self.socket.take_error()
+    }
+
+    fn close(&self) {
+        self.socket.close()
+    }
+
+    fn shutdown_write(&self) {
+        self.socket.shutdown_write()
+    }
+}
+
+// the upstream of `config`, controlled by `control`.
+fn test_upstream(control: &Arc<UpstreamControl>) -> crate::Upstream {
+    let control = control.clone();
+    crate::Upstream::Custom(crate::UpstreamConnector::new(move |session_info| {
+        let socket = mio_socket::Socket::new(session_info, &config()).map_err(std::io::Error::other)?;
+        Ok(Box::new(TestUpstream {
+            socket,
+            control: control.clone(),
+        }))
+    }))
+}
+
+// waits for the writable event of the upstream connect, after which the socket stays writable and
+// no further event comes.
+fn wait_for_connected(processor: &TestProcessor) {
+    let deadline = std::time::Instant::now() + READ_TIMEOUT;
+    loop {
+        let session_info = processor.sessions()[0];
+        if processor.command_sender.dump_session(session_info).unwrap().is_some_and(|dump| dump.connected) {
+            return;
+        }
+        assert!(std::time::Instant::now() < deadline, "upstream not connected");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn refused_write_is_retried_when_idle() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let control = Arc::new(UpstreamControl::default());
+    // neither the reaper nor the poll timeout wakes the processor up before the read times out.
+    let config = crate::TunConfig {
+        upstream: test_upstream(&control),
+        reaper_interval: Duration::from_secs(60),
+        ..config()
+    };
+    assert!(READ_TIMEOUT < Duration::from_secs(crate::POLL_TIMEOUT));
+    let processor = TestProcessor::start(config);
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+    wait_for_connected(&processor);
+
+    control.blocked_writes.store(1, Ordering::SeqCst);
+    processor.send(&guest.segment(TcpControl::Psh, b"ping"));
+    let ack = processor.receive().expect("no ACK of the data");
+    assert_eq!(ack.ack_number, Some(guest.seq_number));
+    // the guest sends nothing more and the socket reports no event, only the shortened poll timeout
+    // of the pending data wakes the processor up to retry.
+    let mut buffer = [0; 4];
+    stream.read_exact(&mut buffer).expect("refused write not retried");
+    assert_eq!(&buffer, b"ping");
+    assert_eq!(control.blocked_writes.load(Ordering::SeqCst), 0, "the write was not refused");
+}
+
+#[test]
+fn upstream_burst_drains_through_small_smoltcp_buffer() {
+    const SMOLTCP_BUFFER_SIZE: usize = 4096;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // the session takes its smoltcp socket from the pool, this one holds a small fraction of a
+    // single read from the server.
+    let processor = TestProcessor::start_with(config(), |processor| {
+        let buffer = || tcp::SocketBuffer::new(vec![0; SMOLTCP_BUFFER_SIZE]);
+        processor.socket_pool.put(smoltcp::socket::Socket::Tcp(tcp::Socket::new(buffer(), buffer())));
+    });
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let stream = connect(&processor, &mut guest, &listener);
+
+    let data = pattern(4 * 1024 * 1024);
+    let server = serve(stream, &data);
+    let (_, payload) = download(&processor, &mut guest);
+    assert_eq!(payload.len(), data.len());
+    let mismatch = payload.iter().zip(data.iter()).position(|(received, sent)| received != sent);
+    assert_eq!(mismatch, None, "received data differs");
+    server.join().unwrap();
+}
+
+#[test]
+fn server_closing_at_once_ends_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    // the server accepts the connect and closes it right away.
+    drop(connect(&processor, &mut guest, &listener));
+    let (_, payload) = receive_until_fin(&processor, &mut guest);
+    assert!(payload.is_empty());
+
+    // the guest did not see the FIN yet and sends data to the closed stream before closing too.
+    processor.send(&guest.segment(TcpControl::Psh, b"ping"));
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    wait_for_no_sessions(&processor);
+}
+
+#[test]
+fn stream_accepting_nothing_ends_session_without_spinning() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let control = Arc::new(UpstreamControl::default());
+    let config = crate::TunConfig {
+        upstream: test_upstream(&control),
+        ..config()
+    };
+    let processor = TestProcessor::start(config);
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let _stream = connect(&processor, &mut guest, &listener);
+    wait_for_connected(&processor);
+
+    control.write_zero.store(true, Ordering::SeqCst);
+    processor.send(&guest.segment(TcpControl::Psh, b"ping"));
+    // the failed write closes the upstream, which the guest sees as the close of the server.
+    let (segments, _) = receive_until_fin(&processor, &mut guest);
+    assert_eq!(
+        segments.last().unwrap().ack_number,
+        Some(guest.seq_number),
+        "data of the guest not acknowledged"
+    );
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    wait_for_no_sessions(&processor);
+    // the data left behind is not written again on every wake-up of the processor.
+    assert_eq!(control.writes.load(Ordering::SeqCst), 1);
+}
+
+// sends `data` from the guest in segments of the MSS, keeping no more unacknowledged than the
+// window of the processor allows. the guest never retransmits, so the transfer stalls once a
+// segment is lost.
+fn upload(processor: &TestProcessor, guest: &mut Guest, data: &[u8]) {
+    const MSS: usize = 1400;
+    let end = guest.seq_number + data.len();
+    let (mut acknowledged, mut window_len) = (guest.seq_number, usize::from(u16::MAX));
+    let mut chunks = data.chunks(MSS).peekable();
+    while acknowledged != end {
+        while let Some(chunk) = chunks.next_if(|chunk| guest.seq_number + chunk.len() <= acknowledged + window_len) {
+            processor.send(&guest.segment(TcpControl::None, chunk));
+        }
+        let segment = processor.receive().expect("upload stalled");
+        assert_ne!(segment.control, TcpControl::Rst, "reset during the upload");
+        match segment.ack_number {
+            Some(ack_number) if ack_number >= acknowledged => {
+                acknowledged = ack_number;
+                window_len = usize::from(segment.window_len);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn upload_drains_through_small_upstream_send_buffer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // the accepted stream takes a small receive buffer as well, so the server fills up at once.
+    socket2::SockRef::from(&listener).set_recv_buffer_size(4096).unwrap();
+    let config = crate::TunConfig {
+        upstream_send_buffer_size: Some(1024),
+        ..config()
+    };
+    let processor = TestProcessor::start(config);
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+
+    let data = pattern(1024 * 1024);
+    // the server only starts reading once the writes of the processor are refused.
+    let server = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    upload(&processor, &mut guest, &data);
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    let received = server.join().unwrap();
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "received data differs");
+}
+
+#[test]
+fn guest_data_and_fin_in_one_burst_arrive_before_close() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+
+    // within the window of the processor, the guest does not wait for an ACK.
+    let data = pattern(32 * 1400);
+    for chunk in data.chunks(1400) {
+        processor.send(&guest.segment(TcpControl::None, chunk));
+    }
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "received data differs");
+}
+
+#[test]
+fn last_server_bytes_arrive_before_fin() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+
+    // the last bytes and the close come in one read of the processor.
+    let data = pattern(3 * 1400 + 100);
+    let start = guest.ack_number.unwrap();
+    stream.write_all(&data).unwrap();
+    drop(stream);
+
+    // the guest loses the segment with the last bytes and ignores what follows it, only the
+    // retransmission timer of the processor brings them.
+    let mut payload = Vec::new();
+    let mut lost = false;
+    loop {
+        let segment = processor.receive().expect("last bytes not retransmitted");
+        assert_ne!(segment.control, TcpControl::Rst, "reset before the last bytes");
+        if Some(segment.seq_number) != guest.ack_number {
+            continue;
+        }
+        if !lost && segment.seq_number + segment.payload.len() == start + data.len() {
+            lost = true;
+            continue;
+        }
+        payload.extend_from_slice(&segment.payload);
+        guest.acknowledge(&segment);
+        processor.send(&guest.segment(TcpControl::None, &[]));
+        if segment.control == TcpControl::Fin {
+            assert_eq!(segment.seq_number + segment.payload.len(), start + data.len(), "FIN before the last bytes");
+            break;
+        }
+    }
+    assert!(lost);
+    assert!(payload == data, "received {} of {} bytes, or corrupted", payload.len(), data.len());
+}
+
+#[test]
+fn udp_answers_reach_guest_when_session_expires_right_after() {
+    const ANSWERS: usize = 5;
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let clock = Arc::new(ManualClock::new());
+    let config = config();
+    let udp_timeout = config.udp_timeout;
+    // the smoltcp socket of the session takes two datagrams at a time, the other answers wait in
+    // the session buffers.
+    let processor = TestProcessor::start_with(config, |processor| {
+        processor.set_clock(clock.clone());
+        let buffer = || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 2], vec![0; 4096]);
+        processor.socket_pool.put(smoltcp::socket::Socket::Udp(udp::Socket::new(buffer(), buffer())));
+    });
+
+    processor.send(&udp_packet::build(GUEST.parse().unwrap(), server.local_addr().unwrap(), b"query", 64).unwrap());
+    let mut buffer = [0; 16];
+    let (_, upstream) = server.recv_from(&mut buffer).unwrap();
+    for answer in 0..ANSWERS {
+        server.send_to(format!("answer {}", answer).as_bytes(), upstream).unwrap();
+    }
+    assert_eq!(processor.receive_datagram().expect("no answer"), b"answer 0");
+    // the events of the answers are handled before the clock moves, the session is left idle.
+    std::thread::sleep(Duration::from_millis(200));
+    let dump = processor.command_sender.dump_session(processor.sessions()[0]).unwrap().unwrap();
+    assert!(dump.pending_to_client > 0, "no answer left in the session");
+
+    // the session expires before anything else wakes it up, its last answers go out as it is destroyed.
+    clock.advance(udp_timeout);
+    wait_for_no_sessions(&processor);
+    let answers = (1..ANSWERS).map_while(|_| processor.receive_datagram()).collect::<Vec<_>>();
+    let expected = (1..ANSWERS).map(|answer| format!("answer {}", answer).into_bytes()).collect::<Vec<_>>();
+    assert_eq!(answers, expected);
+}
+
+#[test]
+fn refused_datagrams_are_sent_in_order() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let control = Arc::new(UpstreamControl::default());
+    let config = crate::TunConfig {
+        upstream: test_upstream(&control),
+        ..config()
+    };
+    let processor = TestProcessor::start(config);
+    let send = |payload: &[u8]| processor.send(&udp_packet::build(GUEST.parse().unwrap(), server.local_addr().unwrap(), payload, 64).unwrap());
+    let mut buffer = [0; 16];
+    send(b"0");
+    assert_eq!(server.recv(&mut buffer).unwrap(), 1);
+
+    // the datagrams of the guest queue up behind the refused one, none is dropped or reordered.
+    control.blocked_writes.store(3, Ordering::SeqCst);
+    for datagram in 1..=5 {
+        send(datagram.to_string().as_bytes());
+    }
+    let received = (1..=5)
+        .map(|_| {
+            let count = server.recv(&mut buffer).expect("refused datagram not sent again");
+            String::from_utf8(buffer[..count].to_vec()).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(received, ["1", "2", "3", "4", "5"]);
+    assert_eq!(control.blocked_writes.load(Ordering::SeqCst), 0, "the writes were not refused");
+}
+
+#[test]
+fn download_to_guest_delaying_acks_completes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // the smoltcp socket holds a fraction of the data, the rest waits in the session for the ACKs
+    // of the guest to make room.
+    let processor = TestProcessor::start_with(config(), |processor| {
+        let buffer = || tcp::SocketBuffer::new(vec![0; 16 * 1024]);
+        processor.socket_pool.put(smoltcp::socket::Socket::Tcp(tcp::Socket::new(buffer(), buffer())));
+    });
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let stream = connect(&processor, &mut guest, &listener);
+
+    let data = pattern(256 * 1024);
+    let server = serve(stream, &data);
+    // the guest takes whatever the processor sends until it stops, then waits before a single
+    // cumulative ACK.
+    let mut payload = Vec::new();
+    loop {
+        let segments = processor.receive_until_quiet(Duration::from_millis(30));
+        assert!(!segments.is_empty(), "transfer stalled after {} of {} bytes", payload.len(), data.len());
+        assert_no_reset(&segments);
+        for segment in &segments {
+            if Some(segment.seq_number) != guest.ack_number {
+                // a retransmission of data taken already.
+                continue;
+            }
+            payload.extend_from_slice(&segment.payload);
+            guest.acknowledge(segment);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        processor.send(&guest.segment(TcpControl::None, &[]));
+        if segments.iter().any(|segment| segment.control == TcpControl::Fin) {
+            break;
+        }
+    }
+    assert_eq!(payload.len(), data.len());
+    assert!(payload == data, "received data differs");
+    server.join().unwrap();
+}
+
+// polls an idle processor and handles the events of its upstream sockets until `done`.
+fn handle_server_events(processor: &mut Processor<'static>, done: impl Fn(&Processor<'static>) -> bool) {
+    let deadline = std::time::Instant::now() + READ_TIMEOUT;
+    let mut events = Events::with_capacity(16);
+    while !done(processor) {
+        assert!(std::time::Instant::now() < deadline, "no server event");
+        processor.poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        for event in events.iter() {
+            processor.handle_server_event(event).unwrap();
+        }
+    }
+}
+
+#[test]
+fn connect_and_first_byte_latencies_are_bucketed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let clock = Arc::new(ManualClock::new());
+    let (mut processor, _guest) = idle_processor(config());
+    processor.set_clock(clock.clone());
+    let syn = Guest::new(listener.local_addr().unwrap()).segment(TcpControl::Syn, &[]);
+    processor.retrieve_or_create_session(&syn, &mut false).unwrap();
+
+    // the latencies follow the clock of the processor, however long the connect took in real time.
+    // a latency at a bound falls into the bucket above it.
+    clock.advance(CONNECT_LATENCY_BOUNDS[1]);
+    handle_server_events(&mut processor, |processor| processor.health.connect_latency.total() == 1);
+    let mut stream = listener.accept().unwrap().0;
+    clock.advance(Duration::from_millis(250));
+    stream.write_all(b"x").unwrap();
+    handle_server_events(&mut processor, |processor| processor.health.first_byte_latency.total() == 1);
+
+    assert_eq!(processor.health.connect_latency.counts, [0, 0, 1, 0, 0, 0, 0, 0]);
+    // 300ms from the SYN, between the bounds of 250ms and 500ms.
+    assert_eq!(processor.health.first_byte_latency.counts, [0, 0, 0, 0, 1, 0, 0, 0]);
+}
+
+#[test]
+fn data_in_syn_reaches_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let initial_seq_number = guest.seq_number;
+    // TCP Fast Open, the request rides in the SYN.
+    processor.send(&guest.segment(TcpControl::Syn, b"early"));
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let syn_ack = processor.receive().expect("no SYN-ACK");
+    assert_eq!(syn_ack.control, TcpControl::Syn);
+
+    // only the SYN is acknowledged, like by a server refusing the cookie, the guest sends the data
+    // again after the handshake.
+    assert_eq!(syn_ack.ack_number, Some(initial_seq_number + 1));
+    guest.seq_number = initial_seq_number + 1;
+    guest.acknowledge(&syn_ack);
+    processor.send(&guest.segment(TcpControl::Psh, b"early"));
+    let mut buffer = [0; 5];
+    stream.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"early");
+    let ack = processor.receive().expect("no ACK of the data");
+    assert_eq!(ack.ack_number, Some(guest.seq_number));
+
+    // the data reached the server once, not from the SYN as well.
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "{:?} after the data", rest);
+}
+
+// creates and destroys a UDP and a TCP session from each of `count` ports, one after the other,
+// returning the sessions per second.
+fn churn_sessions(config: crate::TunConfig, count: u16) -> f64 {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut processor, _guest) = idle_processor(config);
+    let started = std::time::Instant::now();
+    for source_port in 20000..20000 + count {
+        let session_info = create_udp_session(&mut processor, &server, source_port);
+        processor.destroy_session(&session_info, DestroyReason::Expired).unwrap();
+        let mut guest = Guest::new(listener.local_addr().unwrap());
+        guest.source.set_port(source_port);
+        let session_info = processor.retrieve_or_create_session(&guest.segment(TcpControl::Syn, &[]), &mut false).unwrap();
+        processor.destroy_session(&session_info, DestroyReason::Closed).unwrap();
+    }
+    f64::from(count) * 2.0 / started.elapsed().as_secs_f64()
+}
+
+// cargo test --release -p tuncore -- --ignored --nocapture session_churn
+#[test]
+#[ignore = "benchmark"]
+fn session_churn_benchmark() {
+    const SESSIONS: u16 = 200;
+    let socket_pool_size = crate::TunConfig::default().socket_pool_size;
+    let unpooled = churn_sessions(
+        crate::TunConfig {
+            socket_pool_size: 0,
+            ..config()
+        },
+        SESSIONS,
+    );
+    let pooled = churn_sessions(crate::TunConfig { socket_pool_size, ..config() }, SESSIONS);
+    println!("socket pool of 0: {:.0} sessions/s", unpooled);
+    println!("socket pool of {}: {:.0} sessions/s, {:.1}x", socket_pool_size, pooled, pooled / unpooled);
+}
+
+// sends `data` from the guest to an echo server like `upload`, taking and acknowledging the echo
+// as it comes back, until all of it is back. the socket pair standing in for the tun holds a few
+// hundred kilobytes only, so the guest keeps little data on its way and takes the segments waiting
+// for it between its own.
+fn echo(processor: &TestProcessor, guest: &mut Guest, data: &[u8]) -> Vec<u8> {
+    const MSS: usize = 1400;
+    const IN_FLIGHT: usize = 32 * 1024;
+    let start = guest.seq_number;
+    let (mut acknowledged, mut window_len) = (guest.seq_number, usize::from(u16::MAX));
+    let mut chunks = data.chunks(MSS).peekable();
+    let mut echoed = Vec::with_capacity(data.len());
+    while echoed.len() < data.len() {
+        let sent = chunks
+            .next_if(|chunk| guest.seq_number + chunk.len() <= acknowledged + window_len && guest.seq_number - start + chunk.len() <= echoed.len() + IN_FLIGHT);
+        if let Some(chunk) = sent {
+            processor.send(&guest.segment(TcpControl::None, chunk));
+        }
+        let mut segments = std::iter::from_fn(|| processor.try_receive()).collect::<Vec<_>>();
+        if segments.is_empty() && sent.is_none() {
+            segments.push(processor.receive().expect("echo stalled"));
+        }
+        for segment in segments {
+            assert_ne!(segment.control, TcpControl::Rst, "reset during the echo");
+            match segment.ack_number {
+                Some(ack_number) if ack_number >= acknowledged => {
+                    acknowledged = ack_number;
+                    window_len = usize::from(segment.window_len);
+                }
+                _ => {}
+            }
+            if !segment.payload.is_empty() && Some(segment.seq_number) == guest.ack_number {
+                echoed.extend_from_slice(&segment.payload);
+                guest.acknowledge(&segment);
+                processor.send(&guest.segment(TcpControl::None, &[]));
+            }
+        }
+    }
+    echoed
+}
+
+// cargo test --release -p tuncore -- --ignored --nocapture echo_throughput
+#[test]
+#[ignore = "benchmark"]
+fn echo_throughput_benchmark() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let processor = TestProcessor::start(config());
+    let mut guest = Guest::new(listener.local_addr().unwrap());
+    let mut stream = connect(&processor, &mut guest, &listener);
+    let server = std::thread::spawn(move || {
+        let mut reader = stream.try_clone().unwrap();
+        std::io::copy(&mut reader, &mut stream).unwrap();
+    });
+
+    let data = pattern(64 * 1024 * 1024);
+    let started = std::time::Instant::now();
+    let echoed = echo(&processor, &mut guest, &data);
+    let elapsed = started.elapsed();
+    assert!(echoed == data, "echo differs");
+    // every byte crossed the processor twice, once in each direction.
+    let mebibytes = (2 * data.len()) as f64 / (1024.0 * 1024.0);
+    println!(
+        "echo of {} MiB: {:.0} MiB/s through the processor",
+        data.len() / (1024 * 1024),
+        mebibytes / elapsed.as_secs_f64()
+    );
+
+    processor.send(&guest.segment(TcpControl::Fin, &[]));
+    server.join().unwrap();
+}
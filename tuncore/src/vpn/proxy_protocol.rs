@@ -0,0 +1,67 @@
+use crate::{vpn::session_info::SessionInfo, ProxyProtocol};
+use std::net::SocketAddr;
+
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+
+// version 2, PROXY command: the connection is relayed on behalf of another host.
+const V2_PROXY: u8 = 0x21;
+
+// the address family in the upper and the transport protocol in the lower four bits.
+const V2_TCP_OVER_IPV4: u8 = 0x11;
+const V2_TCP_OVER_IPV6: u8 = 0x21;
+
+/// The PROXY protocol header announcing the addresses of the guest to the server of a TCP session,
+/// written ahead of the data of the guest.
+pub(crate) fn header(version: ProxyProtocol, session_info: &SessionInfo) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => v1_header(session_info.source, session_info.destination),
+        ProxyProtocol::V2 => v2_header(session_info.source, session_info.destination),
+    }
+}
+
+fn v1_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn v2_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut addresses = Vec::with_capacity(36);
+    let family = match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            addresses.extend_from_slice(&source.ip().octets());
+            addresses.extend_from_slice(&destination.ip().octets());
+            V2_TCP_OVER_IPV4
+        }
+        _ => {
+            addresses.extend_from_slice(&to_ipv6(source).octets());
+            addresses.extend_from_slice(&to_ipv6(destination).octets());
+            V2_TCP_OVER_IPV6
+        }
+    };
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+    let mut header = Vec::with_capacity(V2_SIGNATURE.len() + 4 + addresses.len());
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(V2_PROXY);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+// both ends of a session share their IP version, the mapping is only there to be exhaustive.
+fn to_ipv6(address: SocketAddr) -> std::net::Ipv6Addr {
+    match address {
+        SocketAddr::V4(address) => address.ip().to_ipv6_mapped(),
+        SocketAddr::V6(address) => *address.ip(),
+    }
+}
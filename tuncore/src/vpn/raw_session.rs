@@ -0,0 +1,137 @@
+use crate::vpn::{
+    capture::{Capture, CaptureFilter},
+    clock::Clock,
+    ip_packet, mio_socket,
+    session_info::SessionInfo,
+    udp_packet,
+    utils::PacketLogger,
+};
+use mio::{Poll, Token};
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+/// A session relayed packet by packet without smoltcp: of a protocol other than TCP and UDP through
+/// a raw socket, see `UnsupportedProtocols::ForwardRaw`, or of UDP to a multicast group or the
+/// broadcast address, see `TunConfig::forward_multicast`.
+pub(crate) struct RawSession {
+    pub(crate) token: Token,
+    mio_socket: mio_socket::Socket,
+    session_info: SessionInfo,
+    expiry: Instant,
+    idle_timeout: ::std::time::Duration,
+    hop_limit: u8,
+    packet_logger: PacketLogger,
+    capture: Option<Capture>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RawSession {
+    pub(crate) fn new(
+        session_info: &SessionInfo,
+        poll: &mut Poll,
+        token: Token,
+        config: &crate::TunConfig,
+        clock: Arc<dyn Clock>,
+    ) -> crate::Result<RawSession> {
+        let mut mio_socket = match session_info.ip_protocol {
+            IpProtocol::Udp => mio_socket::Socket::new_multicast(session_info, config)?,
+            _ => mio_socket::Socket::new(session_info, config)?,
+        };
+        if let Err(error) = mio_socket.register_poll(poll, token) {
+            log::error!("failed to register poll, error={:?}", error);
+            return Err(error.into());
+        }
+        Ok(RawSession {
+            token,
+            mio_socket,
+            session_info: *session_info,
+            expiry: clock.now() + config.udp_timeout,
+            idle_timeout: config.udp_timeout,
+            hop_limit: config.hop_limit,
+            packet_logger: PacketLogger::new(&config.packet_log, Some(session_info)),
+            capture: None,
+            clock,
+        })
+    }
+
+    /// Sends the payload of the packet from the guest, the kernel adds the IP header, and the UDP
+    /// header for a multicast session.
+    pub(crate) fn write_to_server(&mut self, raw_ip_packet: &[u8]) -> crate::Result<()> {
+        self.packet_logger.log_packet("out", raw_ip_packet);
+        if let Some(capture) = self.capture.as_mut() {
+            capture.write_packet(raw_ip_packet);
+        }
+        let payload = match (self.session_info.ip_protocol, self.session_info.ip_version) {
+            (IpProtocol::Udp, _) => udp_packet::payload(raw_ip_packet)?,
+            (_, IpVersion::Ipv4) => Ipv4Packet::new_checked(raw_ip_packet)?.payload(),
+            (_, IpVersion::Ipv6) => Ipv6Packet::new_checked(raw_ip_packet)?.payload(),
+        };
+        match self.mio_socket.write(payload) {
+            Ok(_) => {}
+            // like a router out of buffers, the packet is dropped and left to the protocol to recover.
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                log::trace!("dropped raw packet, {:?} {:?}", self.token, self.session_info);
+            }
+            Err(error) => return Err(error.into()),
+        }
+        self.expiry = self.clock.now() + self.idle_timeout;
+        Ok(())
+    }
+
+    /// Writes the packets received from the server to the guest, each wrapped in an IP header. The
+    /// answers of a multicast session come from the responders rather than from the group.
+    pub(crate) fn write_to_tun(&mut self, tun: &mut impl std::io::Write) -> crate::Result<()> {
+        let mut payloads: Vec<(SocketAddr, Vec<u8>)> = Vec::new();
+        let mut is_closed = false;
+        let result = match self.session_info.ip_protocol {
+            IpProtocol::Udp => self.mio_socket.read_from(|payload, source| {
+                payloads.push((source, payload.to_vec()));
+                Ok(())
+            }),
+            _ => self.mio_socket.read(&mut is_closed, |payload| {
+                payloads.push((self.session_info.destination, payload.to_vec()));
+                Ok(())
+            }),
+        };
+        if let Err(error) = result {
+            log::debug!("failed to read from raw socket, {:?} error={:?}", self.token, error);
+        }
+        let destination = self.session_info.source;
+        for (source, payload) in payloads {
+            let packet = match self.session_info.ip_protocol {
+                IpProtocol::Udp => udp_packet::build(source, destination, &payload, self.hop_limit)?,
+                protocol => ip_packet::build(protocol, source.ip(), destination.ip(), &payload, self.hop_limit)?,
+            };
+            self.packet_logger.log_packet("in", &packet);
+            if let Some(capture) = self.capture.as_mut() {
+                capture.write_packet(&packet);
+            }
+            tun.write_all(&packet)?;
+        }
+        self.expiry = self.clock.now() + self.idle_timeout;
+        Ok(())
+    }
+
+    /// Records the packets exchanged with the guest from now on, or stops recording them.
+    pub(crate) fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    pub(crate) fn is_captured(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    pub(crate) fn is_captured_by(&self, filter: &CaptureFilter) -> bool {
+        self.capture.as_ref().is_some_and(|capture| capture.is_of(filter))
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expiry <= self.clock.now()
+    }
+
+    pub(crate) fn close(&mut self, poll: &mut Poll) {
+        if let Err(error) = self.mio_socket.deregister_poll(poll) {
+            log::error!("failed to deregister socket from poll, error={:?}", error);
+        }
+    }
+}
@@ -1,80 +1,480 @@
 use crate::vpn::{
+    buffer_usage::BufferUsage,
     buffers::{Buffers, IncomingDataEvent, IncomingDirection, OutgoingDirection, TcpBuffers, UdpBuffers},
-    mio_socket,
+    capture::{Capture, CaptureFilter},
+    clock::Clock,
+    guest_rtt::GuestRtt,
+    health::{SessionCounters, SessionDump},
+    icmp::Unreachable,
+    mio_socket, proxy_protocol,
+    session_error::{self, SessionErrorCause},
     session_info::SessionInfo,
     smoltcp_socket,
+    socket_pool::SocketPool,
+    tcp_packet,
+    tee::Tee,
+    upstream::UpstreamTransport,
+    utils::PacketLogger,
     vpn_device::VpnDevice,
 };
 use mio::{Poll, Token};
 use smoltcp::{
     iface::{Config, Interface, SocketSet},
     time::Instant,
-    wire::{HardwareAddress, IpAddress, IpCidr, IpProtocol, Ipv4Address},
+    wire::{HardwareAddress, IpAddress, IpCidr, IpProtocol, Ipv4Address, TcpSeqNumber},
 };
 
+/// Why a session is destroyed, deciding whether the guest side ends with a FIN or a RST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DestroyReason {
+    /// Both sides finished their close handshake.
+    Terminated,
+    /// The session was idle, or alive, for too long.
+    Expired,
+    /// The session was closed on request, e.g. through `tun::close_sessions`.
+    Closed,
+    /// Reloaded rules refuse the session.
+    Refused,
+    /// The guest reported the port of the UDP session unreachable, e.g. the app using it is gone.
+    Unreachable,
+}
+
+impl DestroyReason {
+    /// Whether the guest side is reset rather than closed cleanly.
+    pub(crate) fn aborts(&self) -> bool {
+        matches!(self, DestroyReason::Closed | DestroyReason::Refused | DestroyReason::Unreachable)
+    }
+}
+
 pub(crate) struct Session<'a> {
     pub(crate) token: Token,
     smoltcp_socket: smoltcp_socket::Socket,
-    mio_socket: mio_socket::Socket,
-    buffers: Buffers,
+    upstream: Box<dyn UpstreamTransport>,
+    buffers: Buffers<std::time::Instant>,
+    buffer_usage: BufferUsage,
+    // bytes of the buffers last reported to `buffer_usage`.
+    accounted_bytes: usize,
+    throttled: bool,
     interface: Interface,
     sockets: SocketSet<'a>,
     device: VpnDevice,
     expiry: Option<::std::time::Instant>,
     session_info: SessionInfo,
     lifetime: ::std::time::Instant,
+    created: ::std::time::Instant,
+    connected: bool,
+    connect_failed: bool,
+    idle_timeout: ::std::time::Duration,
+    unanswered_timeout: Option<::std::time::Duration>,
+    send_deadline: ::std::time::Duration,
+    close_grace: ::std::time::Duration,
     continue_read: bool,
+    upstream_closed: bool,
+    upstream_write_shutdown: bool,
+    tee: Option<Tee>,
+    // bytes of the PROXY protocol header at the front of the data to the server, kept out of the tee.
+    proxy_header_len: usize,
+    packet_logger: PacketLogger,
+    capture: Option<Capture>,
+    // initial sequence number of the SYN of the guest that opened the TCP session.
+    syn_sequence: Option<TcpSeqNumber>,
+    // see `TunConfig::port_priorities`.
+    priority: u8,
+    clock: std::sync::Arc<dyn Clock>,
+    unreachable: Option<Unreachable>,
+    transferred: u64,
+    bytes_from_guest: u64,
+    bytes_to_guest: u64,
+    guest_rtt: Option<GuestRtt>,
+    first_byte_seen: bool,
+    first_byte_latency: Option<::std::time::Duration>,
 }
 
 impl<'a> Session<'a> {
-    pub(crate) fn new(session_info: &SessionInfo, poll: &mut Poll, token: Token) -> crate::Result<Session<'a>> {
-        let mut device = VpnDevice::new();
+    pub(crate) fn new(
+        session_info: &SessionInfo,
+        poll: &mut Poll,
+        token: Token,
+        config: &crate::TunConfig,
+        clock: std::sync::Arc<dyn Clock>,
+        buffer_usage: BufferUsage,
+        socket_pool: &mut SocketPool<'a>,
+    ) -> crate::Result<Session<'a>> {
+        let now = clock.now();
+        let mut device = VpnDevice::new(config.mtu);
         let mut sockets = SocketSet::new([]);
 
+        let idle_timeout = if session_info.ip_protocol == IpProtocol::Udp {
+            config.udp_timeout_for_port(session_info.destination.port())
+        } else {
+            ::std::time::Duration::from_secs(crate::UDP_TIMEOUT)
+        };
+        let unanswered_timeout = config.udp_unanswered_timeout.filter(|_| session_info.ip_protocol == IpProtocol::Udp);
         let expiry = if session_info.ip_protocol == IpProtocol::Udp {
-            Some(Self::generate_expiry_timestamp(crate::UDP_TIMEOUT))
+            let timeout = unanswered_timeout.map_or(idle_timeout, |timeout| timeout.min(idle_timeout));
+            Some(Self::generate_expiry_timestamp(now, timeout))
         } else {
             None
         };
 
-        let session = Session {
-            smoltcp_socket: Self::create_smoltcp_socket(session_info, &mut sockets)?,
-            mio_socket: Self::create_mio_socket(session_info, poll, token)?,
+        let smoltcp_socket = Self::create_smoltcp_socket(session_info, config, &mut sockets, socket_pool)?;
+        let upstream = Self::create_upstream(session_info, poll, token, config)?;
+        // udp sockets and the loopback upstream have no handshake, there is no connect to wait for.
+        // a custom transport may have connected already.
+        let connected = match config.upstream_for(session_info) {
+            _ if session_info.ip_protocol != IpProtocol::Tcp => true,
+            crate::Upstream::Direct => false,
+            crate::Upstream::Loopback => true,
+            crate::Upstream::Custom(_) => upstream.is_connected().unwrap_or(false),
+        };
+        let mut session = Session {
+            smoltcp_socket,
+            upstream,
             token,
             buffers: Self::create_buffer(session_info.ip_protocol)?,
-            interface: Self::create_interface(&mut device)?,
+            buffer_usage,
+            accounted_bytes: 0,
+            throttled: false,
+            interface: Self::create_interface(&mut device, config)?,
             sockets,
             device,
             expiry,
             session_info: *session_info,
-            lifetime: ::std::time::Instant::now(),
+            lifetime: now,
+            created: now,
+            connected,
+            connect_failed: false,
+            idle_timeout,
+            unanswered_timeout,
+            send_deadline: config.udp_send_deadline,
+            close_grace: config.tcp_close_grace,
             continue_read: false,
+            upstream_closed: false,
+            upstream_write_shutdown: false,
+            tee: config.tee.as_ref().map(|target| Tee::new(target, session_info)),
+            proxy_header_len: 0,
+            packet_logger: PacketLogger::new(&config.packet_log, Some(session_info)),
+            capture: None,
+            syn_sequence: None,
+            priority: 0,
+            clock,
+            unreachable: None,
+            transferred: 0,
+            bytes_from_guest: 0,
+            bytes_to_guest: 0,
+            guest_rtt: (session_info.ip_protocol == IpProtocol::Tcp).then(GuestRtt::default),
+            first_byte_seen: false,
+            first_byte_latency: None,
         };
+        // queued ahead of any data of the guest, it is written as soon as the connect completes.
+        if let Some(version) = config
+            .proxy_protocol
+            .filter(|_| session_info.ip_protocol == IpProtocol::Tcp && *config.upstream_for(session_info) == crate::Upstream::Direct)
+        {
+            let header = proxy_protocol::header(version, session_info);
+            session.proxy_header_len = header.len();
+            let event = IncomingDataEvent {
+                direction: IncomingDirection::FromClient,
+                buffer: &header,
+            };
+            session.buffers.store_data(event, now);
+            session.update_buffer_usage();
+        }
 
         Ok(session)
     }
 
+    /// Applies `config.mtu` to the segments sent from now on, including the retransmissions of data
+    /// in flight. The MSS announced in the handshake stays as it was.
+    pub(crate) fn set_mtu(&mut self, config: &crate::TunConfig) -> crate::Result<()> {
+        self.device.set_mtu(config.mtu);
+        // the sockets live in their own set, only the interface holding the capabilities is replaced.
+        self.interface = Self::create_interface(&mut self.device, config)?;
+        Ok(())
+    }
+
+    /// Records the packets exchanged with the guest from now on, or stops recording them.
+    pub(crate) fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    pub(crate) fn set_syn_sequence(&mut self, syn_sequence: Option<TcpSeqNumber>) {
+        self.syn_sequence = syn_sequence;
+    }
+
+    pub(crate) fn syn_sequence(&self) -> Option<TcpSeqNumber> {
+        self.syn_sequence
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    pub(crate) fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub(crate) fn is_captured(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    pub(crate) fn is_captured_by(&self, filter: &CaptureFilter) -> bool {
+        self.capture.as_ref().is_some_and(|capture| capture.is_of(filter))
+    }
+
+    pub(crate) fn dump(&mut self) -> crate::Result<SessionDump> {
+        let now = self.clock.now();
+        let socket = self.smoltcp_socket.get(&mut self.sockets)?;
+        let (smoltcp_send_queue, smoltcp_recv_queue) = socket.queues();
+        Ok(SessionDump {
+            session_info: self.session_info,
+            token: self.token.0,
+            tcp_state: socket.tcp_state().map(|state| state.to_string()),
+            pending_to_server: self.buffers.len(OutgoingDirection::ToServer),
+            pending_to_client: self.buffers.len(OutgoingDirection::ToClient),
+            smoltcp_send_queue,
+            smoltcp_recv_queue,
+            connected: self.connected,
+            connect_failed: self.connect_failed,
+            upstream_closed: self.upstream_closed,
+            upstream_write_shutdown: self.upstream_write_shutdown,
+            continue_read: self.continue_read,
+            age: self.age(),
+            idle: now.saturating_duration_since(self.lifetime),
+            expires_in: self.expiry.map(|expiry| expiry.saturating_duration_since(now)),
+            bytes_from_guest: self.bytes_from_guest,
+            bytes_to_guest: self.bytes_to_guest,
+            guest_rtt: self.guest_rtt.as_ref().and_then(|guest_rtt| guest_rtt.smoothed()),
+            priority: self.priority,
+        })
+    }
+
+    pub(crate) fn counters(&self) -> SessionCounters {
+        SessionCounters {
+            session_info: self.session_info,
+            bytes_from_guest: self.bytes_from_guest,
+            bytes_to_guest: self.bytes_to_guest,
+        }
+    }
+
+    /// Returns the traffic counters of the session and zeroes them.
+    pub(crate) fn take_counters(&mut self) -> SessionCounters {
+        let counters = self.counters();
+        self.bytes_from_guest = 0;
+        self.bytes_to_guest = 0;
+        counters
+    }
+
+    /// Time since the session was created.
+    pub(crate) fn age(&self) -> ::std::time::Duration {
+        self.clock.now().saturating_duration_since(self.created)
+    }
+
+    /// Returns the time from the creation of the session to the first byte from the server, once.
+    pub(crate) fn take_first_byte_latency(&mut self) -> Option<::std::time::Duration> {
+        self.first_byte_latency.take()
+    }
+
     pub(crate) fn continue_read(&self) -> bool {
         self.continue_read
     }
 
-    pub(crate) fn destroy(&mut self, poll: &mut Poll) -> crate::Result<()> {
+    /// Whether server data left unread for lack of room can be read now. Sessions waiting for the
+    /// guest to drain smoltcp, or throttled, cannot take it yet and are resumed by the guest or by
+    /// the end of the throttling instead.
+    pub(crate) fn can_continue_read(&self) -> bool {
+        self.continue_read && !self.upstream_closed && !self.throttled && !self.has_pending_client_data()
+    }
+
+    /// Whether the upstream connect is still in progress.
+    pub(crate) fn is_connecting(&self) -> bool {
+        !self.connected && !self.upstream_closed
+    }
+
+    /// Returns the time the upstream connect took, once, when the server socket reports connected.
+    pub(crate) fn take_connect_latency(&mut self) -> Option<::std::time::Duration> {
+        if self.connected {
+            return None;
+        }
+        match self.upstream.is_connected() {
+            Ok(true) => {
+                self.connected = true;
+                Some(self.clock.now().saturating_duration_since(self.created))
+            }
+            Ok(false) => None,
+            Err(error) => {
+                log::debug!("failed to connect to server, {:?} error={:?}", self.session_info, error);
+                self.note_upstream_error(&error);
+                None
+            }
+        }
+    }
+
+    /// Ends the guest side cleanly with a FIN, after the data already handed to smoltcp.
+    pub(crate) fn close(&mut self, poll: &mut Poll) -> crate::Result<()> {
         let mut smoltcp_socket = self.smoltcp_socket.get(&mut self.sockets)?;
         smoltcp_socket.close();
 
-        let mio_socket = &mut self.mio_socket;
-        if let Err(err) = mio_socket.deregister_poll(poll) {
+        self.close_upstream(poll);
+
+        Ok(())
+    }
+
+    /// Hands the smoltcp socket of a destroyed session to `socket_pool`, once its last packets are
+    /// written to the tun.
+    pub(crate) fn recycle_socket(mut self, socket_pool: &mut SocketPool<'a>) {
+        self.smoltcp_socket.recycle(&mut self.sockets, socket_pool);
+    }
+
+    /// Ends the guest side at once with a RST, for sessions ending on an error or a refusal.
+    pub(crate) fn abort(&mut self, poll: &mut Poll) -> crate::Result<()> {
+        let mut smoltcp_socket = self.smoltcp_socket.get(&mut self.sockets)?;
+        smoltcp_socket.abort();
+
+        self.close_upstream(poll);
+
+        Ok(())
+    }
+
+    /// Releases the server side of the session while the guest side keeps draining.
+    ///
+    /// Data already read from the server is still delivered to the guest, the smoltcp socket is
+    /// closed (FIN) once it has all been handed to smoltcp.
+    pub(crate) fn close_upstream(&mut self, poll: &mut Poll) {
+        if self.upstream_closed {
+            return;
+        }
+        self.upstream_closed = true;
+
+        if let Err(err) = self.upstream.deregister_poll(poll) {
             log::error!("failed to deregister socket from poll, error={:?}", err);
         }
-        mio_socket.close();
+        self.upstream.close();
+    }
+
+    /// Whether the server side is closed and the guest side finished its close handshake.
+    pub(crate) fn is_terminated(&mut self) -> bool {
+        if !self.upstream_closed {
+            return false;
+        }
+        match self.smoltcp_socket.get(&mut self.sockets) {
+            Ok(socket) => socket.is_terminated(),
+            Err(_) => true,
+        }
+    }
+
+    /// Whether the guest side finished its close handshake or was reset, whatever the server side.
+    pub(crate) fn is_guest_side_terminated(&mut self) -> bool {
+        match self.smoltcp_socket.get(&mut self.sockets) {
+            Ok(socket) => socket.is_terminated(),
+            Err(_) => true,
+        }
+    }
+
+    /// Whether the guest ended its sending half while it still reads what the server sends.
+    pub(crate) fn is_half_closed(&mut self) -> bool {
+        if self.upstream_closed {
+            return false;
+        }
+        match self.smoltcp_socket.get(&mut self.sockets) {
+            Ok(socket) => socket.is_receive_finished(),
+            Err(_) => false,
+        }
+    }
 
+    /// Passes the FIN of the guest on to the server once all the data before it was written.
+    fn shutdown_upstream_write(&mut self) -> crate::Result<()> {
+        if self.upstream_write_shutdown || !self.connected || !self.buffers.is_empty(OutgoingDirection::ToServer) {
+            return Ok(());
+        }
+        let socket = self.smoltcp_socket.get(&mut self.sockets)?;
+        if socket.can_receive() || !socket.is_receive_finished() {
+            return Ok(());
+        }
+        log::trace!("guest finished sending, {:?} {:?}", self.token, self.session_info);
+        self.upstream_write_shutdown = true;
+        self.upstream.shutdown_write();
         Ok(())
     }
 
-    pub(crate) fn read_from_smoltcp(&mut self) -> crate::Result<()> {
+    /// Whether the server side holds data that no poll event will announce, like the echo of the
+    /// loopback upstream.
+    pub(crate) fn has_unannounced_server_data(&self) -> bool {
+        !self.upstream_closed && self.upstream.has_unannounced_data()
+    }
+
+    /// Returns, once, what the ICMP error the upstream udp socket failed with reported unreachable.
+    pub(crate) fn take_unreachable(&mut self) -> Option<Unreachable> {
+        self.unreachable.take()
+    }
+
+    /// Records the error an error event of the upstream socket announced.
+    pub(crate) fn take_upstream_error(&mut self) {
+        match self.upstream.take_error() {
+            Ok(Some(error)) => {
+                log::debug!("server socket error, {:?} error={:?}", self.session_info, error);
+                self.note_upstream_error(&error);
+            }
+            Ok(None) => {}
+            Err(error) => log::debug!("failed to get server socket error, {:?} error={:?}", self.session_info, error),
+        }
+    }
+
+    fn note_upstream_error(&mut self, error: &std::io::Error) {
+        if !self.connected {
+            // the error ends a connect still in progress.
+            self.connected = true;
+            self.connect_failed = true;
+            session_error::report(Some(self.session_info), SessionErrorCause::ConnectFailed(error.kind()));
+        } else {
+            session_error::report(Some(self.session_info), SessionErrorCause::UpstreamFailed(error.kind()));
+        }
+        if self.session_info.ip_protocol == IpProtocol::Udp && self.unreachable.is_none() {
+            self.unreachable = Unreachable::from_error(error);
+        }
+    }
+
+    /// Bytes held in the buffers of the session, in both directions.
+    pub(crate) fn buffered_bytes(&self) -> usize {
+        self.accounted_bytes
+    }
+
+    /// Stops, or resumes, reading from the guest and the server while the buffers of the engine are
+    /// over `TunConfig::max_buffered_bytes`. Writing goes on, draining the buffers.
+    pub(crate) fn set_throttled(&mut self, throttled: bool) {
+        self.throttled = throttled;
+    }
+
+    pub(crate) fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    // reports the growth or the shrink of the buffers to the usage of the engine.
+    fn update_buffer_usage(&mut self) {
+        let bytes = self.buffers.bytes();
+        self.buffer_usage.update(self.accounted_bytes, bytes);
+        self.accounted_bytes = bytes;
+    }
+
+    pub(crate) fn has_pending_client_data(&self) -> bool {
+        !self.buffers.is_empty(OutgoingDirection::ToClient)
+    }
+
+    /// Whether data of the guest waits for the upstream socket to accept it.
+    pub(crate) fn has_pending_server_data(&self) -> bool {
+        !self.upstream_closed && !self.buffers.is_empty(OutgoingDirection::ToServer)
+    }
+
+    /// Moves the data of the guest from smoltcp to the buffers, returns whether there was any.
+    pub(crate) fn read_from_smoltcp(&mut self) -> crate::Result<bool> {
         log::trace!("read from smoltcp, {:?}", self.session_info);
 
+        if self.throttled {
+            // the data stays in smoltcp, whose shrinking window holds the guest back.
+            return Ok(false);
+        }
         let mut data = [0_u8; crate::MAX_PACKET_SIZE];
+        let mut received = false;
         loop {
             let mut socket = self.smoltcp_socket.get(&mut self.sockets)?;
             if !socket.can_receive() {
@@ -90,23 +490,56 @@ impl<'a> Session<'a> {
                 direction: IncomingDirection::FromClient,
                 buffer: &data[..data_len],
             };
-            self.buffers.store_data(event);
+            self.buffers.store_data(event, self.clock.now());
+            received = true;
         }
-        Ok(())
+        if received {
+            self.update_buffer_usage();
+        }
+        Ok(received)
     }
 
+    /// Hands the server data to smoltcp. What does not fit in its send buffer stays in the buffers of
+    /// the session: the processor calls this again for every packet of the guest while data is
+    /// pending, so the ACKs and window updates freeing room in smoltcp resume the transfer.
     pub(crate) fn write_to_smoltcp(&mut self) -> crate::Result<()> {
         log::trace!("write to smoltcp, {:?}", self.session_info);
 
         let mut socket = self.smoltcp_socket.get(&mut self.sockets)?;
         if socket.can_send() {
-            self.buffers.consume_data_with_fn(OutgoingDirection::ToClient, |b| socket.send(b))?;
+            if let Err(error) = self.buffers.consume_data_with_fn(OutgoingDirection::ToClient, |b| socket.send(b)) {
+                self.update_buffer_usage();
+                return Err(error);
+            }
+        }
+        if self.upstream_closed && self.connect_failed {
+            // the server was never reached, reset the connection the guest believes established.
+            socket.abort();
+        } else if self.upstream_closed && self.buffers.is_empty(OutgoingDirection::ToClient) {
+            // everything the server sent is queued in smoltcp, the FIN follows it.
+            socket.close();
         }
+        self.update_buffer_usage();
         Ok(())
     }
 
+    /// Bytes of the IP packets exchanged with the guest since the previous call.
+    pub(crate) fn take_transferred(&mut self) -> u64 {
+        std::mem::take(&mut self.transferred)
+    }
+
     pub(crate) fn store_tun_data(&mut self, raw_ip_packet: Vec<u8>) {
-        crate::vpn::utils::log_packet("out", &raw_ip_packet);
+        self.packet_logger.log_packet("out", &raw_ip_packet);
+        if let Some(capture) = self.capture.as_mut() {
+            capture.write_packet(&raw_ip_packet);
+        }
+        self.transferred += raw_ip_packet.len() as u64;
+        self.bytes_from_guest += raw_ip_packet.len() as u64;
+        if let Some(guest_rtt) = self.guest_rtt.as_mut() {
+            if let Some((_, _, Some(ack_number))) = tcp_packet::sequence(&raw_ip_packet) {
+                guest_rtt.on_ack(ack_number, self.clock.now());
+            }
+        }
         self.device.store_data(raw_ip_packet);
     }
 
@@ -120,17 +553,64 @@ impl<'a> Session<'a> {
 
         // write the cooked data(raw IP packets) to tun.
         while let Some(bytes) = self.device.pop_data() {
-            crate::vpn::utils::log_packet("in", &bytes);
+            self.packet_logger.log_packet("in", &bytes);
+            if let Some(capture) = self.capture.as_mut() {
+                capture.write_packet(&bytes);
+            }
             tun.write_all(&bytes[..])?;
+            self.transferred += bytes.len() as u64;
+            self.bytes_to_guest += bytes.len() as u64;
+            if let Some(guest_rtt) = self.guest_rtt.as_mut() {
+                if let Some((seq_number, segment_len, _)) = tcp_packet::sequence(&bytes) {
+                    guest_rtt.on_sent(seq_number, segment_len, self.clock.now());
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Pushes the data still queued for the guest through smoltcp to tun, e.g. the last DNS answer of
+    /// an expiring session, until nothing is left or smoltcp accepts no more.
+    pub(crate) fn flush_to_client(&mut self, tun: &mut impl std::io::Write) -> crate::Result<()> {
+        loop {
+            let pending = self.buffers.len(OutgoingDirection::ToClient);
+            self.write_to_smoltcp()?;
+            self.write_to_tun(tun)?;
+            let remaining = self.buffers.len(OutgoingDirection::ToClient);
+            if remaining == 0 || remaining == pending {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Time until smoltcp has to be polled for its own timers, like retransmissions and window
+    /// probes, which no packet from either side would trigger.
+    pub(crate) fn poll_delay(&mut self) -> Option<::std::time::Duration> {
+        let delay = self.interface.poll_delay(Instant::now(), &self.sockets)?;
+        Some(::std::time::Duration::from_micros(delay.total_micros()))
+    }
+
     pub(crate) fn read_from_server(&mut self, is_closed: &mut bool) -> crate::Result<()> {
+        if self.upstream_closed {
+            self.continue_read = false;
+            return Ok(());
+        }
+        if self.has_pending_client_data() {
+            // smoltcp is full and the guest is not draining it, stop reading so a slow guest does not
+            // grow the buffer without bound. the read resumes once smoltcp took everything.
+            log::trace!("client buffer is not drained, {:?} {:?}", self.token, self.session_info);
+            self.continue_read = true;
+            return Ok(());
+        }
+        if self.throttled {
+            // like a full smoltcp, the read resumes once the session is no longer throttled.
+            self.continue_read = true;
+            return Ok(());
+        }
         let mut read_seqs = Vec::new();
         self.continue_read = false;
-        let error = self.mio_socket.read(is_closed, |bytes| {
+        let error = self.upstream.read(is_closed, &mut |bytes| {
             read_seqs.push(bytes.to_vec());
 
             let len = read_seqs.iter().map(|b| b.len()).sum::<usize>();
@@ -149,114 +629,144 @@ impl<'a> Session<'a> {
             if error.kind() == std::io::ErrorKind::OutOfMemory {
                 log::trace!("read buffer is full, {:?} {:?}", self.token, self.session_info);
                 self.continue_read = true;
+            } else {
+                self.note_upstream_error(&error);
             }
         };
 
+        if !self.first_byte_seen && read_seqs.iter().any(|bytes| !bytes.is_empty()) {
+            self.first_byte_seen = true;
+            self.first_byte_latency = Some(self.clock.now().saturating_duration_since(self.created));
+        }
+
         // here we can hijeck the data from server to client
 
+        // empty reads only come from udp, where they are empty datagrams forwarded as such.
         for bytes in read_seqs {
-            if !bytes.is_empty() {
-                let event = IncomingDataEvent {
-                    direction: IncomingDirection::FromServer,
-                    buffer: &bytes[..],
-                };
-                self.buffers.store_data(event);
-            }
+            let event = IncomingDataEvent {
+                direction: IncomingDirection::FromServer,
+                buffer: &bytes[..],
+            };
+            self.buffers.store_data(event, self.clock.now());
         }
+        self.update_buffer_usage();
         Ok(())
     }
 
     pub(crate) fn write_to_server(&mut self, is_closed: &mut bool) -> crate::Result<()> {
         log::trace!("write to server, {:?} {:?}", self.token, self.session_info);
 
-        // here we can hijeck the data from client to server
-
-        /*
-        if let Some(data) = self.buffers.peek_data(OutgoingDirection::ToServer) {
-            if data.is_empty() {
-                self.buffers.consume_data(OutgoingDirection::ToServer, 0);
-                return Ok(());
-            }
-            let mut size = 0;
-            match self.mio_socket.write(data) {
-                Ok(len) => {
-                    size = len;
-                }
-                Err(error) => {
-                    if error.kind() != std::io::ErrorKind::WouldBlock {
-                        log::debug!("write to server, error={:?}", error);
-                    }
-                }
-            }
+        if self.upstream_closed {
+            return Ok(());
+        }
+        if self.buffers.is_empty(OutgoingDirection::ToServer) {
+            // nothing to write, a FIN of the guest may still have to be passed on.
+            return self.shutdown_upstream_write();
+        }
 
-            self.buffers.consume_data(OutgoingDirection::ToServer, size);
+        let dropped = self.buffers.drop_stale(OutgoingDirection::ToServer, self.send_deadline, self.clock.now());
+        if dropped > 0 {
+            log::debug!("dropped stale datagrams, {:?} {:?} count={}", self.token, self.session_info, dropped);
         }
-        // */
 
-        let result = self
-            .buffers
-            .consume_data_with_fn(OutgoingDirection::ToServer, |b| self.mio_socket.write(b).map_err(|e| e.into()));
+        // here we can hijeck the data from client to server
+
+        let upstream = &mut self.upstream;
+        let tee = &mut self.tee;
+        let proxy_header_len = &mut self.proxy_header_len;
+        let result = self.buffers.consume_data_with_fn(OutgoingDirection::ToServer, |b| {
+            let count = upstream.write(b)?;
+            let header_count = count.min(*proxy_header_len);
+            *proxy_header_len -= header_count;
+            if let Some(tee) = tee.as_mut() {
+                tee.write(&b[header_count..count]);
+            }
+            Ok(count)
+        });
+        self.update_buffer_usage();
         if let Err(error) = result {
             log::debug!("write to server, {:?} error={:?}", self.token, error);
+            if let crate::Error::Io(error) = &error {
+                self.note_upstream_error(error);
+            }
             *is_closed = true;
+            return Ok(());
         }
-        Ok(())
+        self.shutdown_upstream_write()
     }
 
     pub(crate) fn update_expiry_timestamp(&mut self, force_set: bool) {
-        self.lifetime = ::std::time::Instant::now();
+        let now = self.clock.now();
+        self.lifetime = now;
         if force_set {
-            self.expiry = Some(Self::generate_expiry_timestamp(crate::TCP_TIMEOUT));
+            self.expiry = Some(Self::generate_expiry_timestamp(now, self.close_grace));
         } else if let Some(expiry) = self.expiry.as_mut() {
-            *expiry = Self::generate_expiry_timestamp(crate::UDP_TIMEOUT);
+            let idle_timeout = match self.unanswered_timeout {
+                Some(timeout) if !self.first_byte_seen => timeout.min(self.idle_timeout),
+                _ => self.idle_timeout,
+            };
+            *expiry = Self::generate_expiry_timestamp(now, idle_timeout);
         }
     }
 
     pub(crate) fn is_expired(&self) -> bool {
-        if self.session_info.ip_protocol == IpProtocol::Tcp && self.lifetime.elapsed().as_secs() >= crate::TCP_MAX_LIFETIME {
+        let now = self.clock.now();
+        if self.session_info.ip_protocol == IpProtocol::Tcp && now.saturating_duration_since(self.lifetime).as_secs() >= crate::TCP_MAX_LIFETIME {
             // TCP session is expired if it's lifetime is greater than 2 hours.
             return true;
         }
         if let Some(expiry) = self.expiry {
-            expiry <= ::std::time::Instant::now()
+            expiry <= now
         } else {
             false
         }
     }
 
-    fn create_smoltcp_socket(info: &SessionInfo, sockets: &mut SocketSet<'_>) -> crate::Result<smoltcp_socket::Socket> {
-        smoltcp_socket::Socket::new(info.ip_protocol, info.source, info.destination, sockets)
+    fn create_smoltcp_socket(
+        info: &SessionInfo,
+        config: &crate::TunConfig,
+        sockets: &mut SocketSet<'a>,
+        socket_pool: &mut SocketPool<'a>,
+    ) -> crate::Result<smoltcp_socket::Socket> {
+        // the smoltcp socket stands in for the server, so it sees the session from the other end.
+        let info = info.reverse();
+        smoltcp_socket::Socket::new(info.ip_protocol, info.source, info.destination, config.hop_limit, sockets, socket_pool)
     }
 
-    fn create_mio_socket(info: &SessionInfo, poll: &mut Poll, token: Token) -> std::io::Result<mio_socket::Socket> {
-        let mut mio_socket = mio_socket::Socket::new(info.ip_protocol, info.ip_version, info.destination)?;
+    fn create_upstream(info: &SessionInfo, poll: &mut Poll, token: Token, config: &crate::TunConfig) -> crate::Result<Box<dyn UpstreamTransport>> {
+        let mut upstream: Box<dyn UpstreamTransport> = match config.upstream_for(info) {
+            crate::Upstream::Custom(connector) => connector.connect(info)?,
+            crate::Upstream::Direct | crate::Upstream::Loopback => Box::new(mio_socket::Socket::new(info, config)?),
+        };
 
-        if let Err(error) = mio_socket.register_poll(poll, token) {
+        if let Err(error) = upstream.register_poll(poll, token) {
             log::error!("failed to register poll, error={:?}", error);
-            return Err(error);
+            return Err(error.into());
         }
 
-        Ok(mio_socket)
+        Ok(upstream)
     }
 
-    fn create_interface<D>(device: &mut D) -> crate::Result<Interface>
+    fn create_interface<D>(device: &mut D, config: &crate::TunConfig) -> crate::Result<Interface>
     where
         D: ::smoltcp::phy::Device + ?Sized,
     {
-        let default_gateway_ipv4 = Ipv4Address::new(0, 0, 0, 1);
-        let config = Config::new(HardwareAddress::Ip);
+        let default_gateway_ipv4 = Ipv4Address::from(config.gateway);
+        let interface_address = IpCidr::new(IpAddress::Ipv4(config.interface_address.into()), config.interface_prefix_len);
 
-        let mut interface = Interface::new(config, device, Instant::now());
+        let mut interface = Interface::new(Config::new(HardwareAddress::Ip), device, Instant::now());
         interface.set_any_ip(true);
+        let mut pushed = Ok(());
         interface.update_ip_addrs(|ip_addrs| {
-            ip_addrs.push(IpCidr::new(IpAddress::v4(0, 0, 0, 1), 0)).unwrap();
+            pushed = ip_addrs.push(interface_address);
         });
+        pushed.map_err(|address| crate::Error::String(format!("no room for interface address {}", address)))?;
         interface.routes_mut().add_default_ipv4_route(default_gateway_ipv4)?;
 
         Ok(interface)
     }
 
-    fn create_buffer(ip_protocol: IpProtocol) -> crate::Result<Buffers> {
+    fn create_buffer(ip_protocol: IpProtocol) -> crate::Result<Buffers<std::time::Instant>> {
         match ip_protocol {
             IpProtocol::Tcp => Ok(Buffers::Tcp(TcpBuffers::new())),
             IpProtocol::Udp => Ok(Buffers::Udp(UdpBuffers::new())),
@@ -264,7 +774,14 @@ impl<'a> Session<'a> {
         }
     }
 
-    fn generate_expiry_timestamp(secs: u64) -> ::std::time::Instant {
-        ::std::time::Instant::now() + ::std::time::Duration::from_secs(secs)
+    fn generate_expiry_timestamp(now: ::std::time::Instant, timeout: ::std::time::Duration) -> ::std::time::Instant {
+        now + timeout
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        // the buffers of a destroyed session are released along with it.
+        self.buffer_usage.update(self.accounted_bytes, 0);
     }
 }
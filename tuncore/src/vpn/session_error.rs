@@ -0,0 +1,36 @@
+use crate::vpn::session_info::SessionInfo;
+use std::io::ErrorKind;
+
+/// A failure the processor recovers from, passed to the callback set with
+/// `tun_callbacks::set_session_error_callback`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionError {
+    /// The session the failure belongs to, unset for packets too malformed to tell.
+    pub session_info: Option<SessionInfo>,
+    pub cause: SessionErrorCause,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionErrorCause {
+    /// The packet of the guest was cut within its TCP or UDP header.
+    ShortPacket,
+    /// The packet of the guest is neither a valid IPv4 nor a valid IPv6 packet.
+    MalformedPacket,
+    /// The socket created callback failed to prepare the upstream socket, e.g. to protect it from
+    /// the tunnel. See `TunConfig::strict_socket_setup` for whether the session is refused.
+    SocketSetupFailed,
+    /// The session could not be created, e.g. for lack of file descriptors, with the kind of the
+    /// I/O error when there was one.
+    CreateFailed(Option<ErrorKind>),
+    /// The upstream connect failed, e.g. it was refused or the network is unreachable.
+    ConnectFailed(ErrorKind),
+    /// Reading from or writing to the server failed once connected, e.g. on a reset.
+    UpstreamFailed(ErrorKind),
+}
+
+pub(crate) fn report(session_info: Option<SessionInfo>, cause: SessionErrorCause) {
+    #[cfg(target_family = "unix")]
+    crate::tun_callbacks::on_session_error(SessionError { session_info, cause });
+    #[cfg(not(target_family = "unix"))]
+    let _ = (session_info, cause);
+}
@@ -1,3 +1,4 @@
+use crate::vpn::socket_pool::SocketPool;
 use smoltcp::{
     iface::{SocketHandle, SocketSet},
     socket::{tcp, udp},
@@ -9,22 +10,33 @@ use std::net::SocketAddr;
 pub(crate) struct Socket {
     socket_handle: SocketHandle,
     ip_protocol: IpProtocol,
-    local_endpoint: IpEndpoint,
+    remote_endpoint: IpEndpoint,
 }
 
 impl Socket {
-    pub(crate) fn new(ip_protocol: IpProtocol, local_address: SocketAddr, remote_address: SocketAddr, sockets: &mut SocketSet<'_>) -> crate::Result<Socket> {
+    /// Creates a socket listening on `local_address` for the guest at `remote_address`, reusing one
+    /// of `pool` when it has any.
+    pub(crate) fn new<'a>(
+        ip_protocol: IpProtocol,
+        local_address: SocketAddr,
+        remote_address: SocketAddr,
+        hop_limit: u8,
+        sockets: &mut SocketSet<'a>,
+        pool: &mut SocketPool<'a>,
+    ) -> crate::Result<Socket> {
         let local_endpoint = IpEndpoint::from(local_address);
 
         let remote_endpoint = IpEndpoint::from(remote_address);
 
         let socket_handle = match ip_protocol {
             IpProtocol::Tcp => {
-                let socket = Self::create_tcp_socket(remote_endpoint)?;
+                let mut socket = Self::create_tcp_socket(local_endpoint, pool)?;
+                socket.set_hop_limit(Some(hop_limit));
                 sockets.add(socket)
             }
             IpProtocol::Udp => {
-                let socket = Self::create_udp_socket(remote_endpoint)?;
+                let mut socket = Self::create_udp_socket(local_endpoint, pool)?;
+                socket.set_hop_limit(Some(hop_limit));
                 sockets.add(socket)
             }
             _ => return Err(crate::Error::UnsupportedProtocol(ip_protocol)),
@@ -33,28 +45,40 @@ impl Socket {
         let socket = Socket {
             socket_handle,
             ip_protocol,
-            local_endpoint,
+            remote_endpoint,
         };
 
         Ok(socket)
     }
 
-    fn create_tcp_socket<'a>(endpoint: IpEndpoint) -> crate::Result<tcp::Socket<'a>> {
-        let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 1024 * 1024]), tcp::SocketBuffer::new(vec![0; 1024 * 1024]));
+    fn create_tcp_socket<'a>(endpoint: IpEndpoint, pool: &mut SocketPool<'a>) -> crate::Result<tcp::Socket<'a>> {
+        let mut socket = pool
+            .take_tcp()
+            .unwrap_or_else(|| tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 1024 * 1024]), tcp::SocketBuffer::new(vec![0; 1024 * 1024])));
+        // a listening socket does not acknowledge data carried in the SYN (TCP Fast Open), the guest
+        // retransmits it once the handshake completes. data received before the upstream connect
+        // completes waits in the session buffers until the upstream socket becomes writable.
         socket.listen(endpoint)?;
         socket.set_ack_delay(None);
         Ok(socket)
     }
 
-    fn create_udp_socket<'a>(endpoint: IpEndpoint) -> crate::Result<udp::Socket<'a>> {
-        let mut socket = udp::Socket::new(
-            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1024 * 1024], vec![0; 1024 * 1024]),
-            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1024 * 1024], vec![0; 1024 * 1024]),
-        );
+    fn create_udp_socket<'a>(endpoint: IpEndpoint, pool: &mut SocketPool<'a>) -> crate::Result<udp::Socket<'a>> {
+        let mut socket = pool.take_udp().unwrap_or_else(|| {
+            udp::Socket::new(
+                udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1024 * 1024], vec![0; 1024 * 1024]),
+                udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1024 * 1024], vec![0; 1024 * 1024]),
+            )
+        });
         socket.bind(endpoint)?;
         Ok(socket)
     }
 
+    /// Removes the socket from `sockets` and hands it to `pool` for reuse.
+    pub(crate) fn recycle<'a>(&self, sockets: &mut SocketSet<'a>, pool: &mut SocketPool<'a>) {
+        pool.put(sockets.remove(self.socket_handle));
+    }
+
     pub(crate) fn get<'a, 'b>(&self, sockets: &'b mut SocketSet<'a>) -> crate::Result<SocketInstance<'a, 'b>> {
         let socket = match self.ip_protocol {
             IpProtocol::Tcp => {
@@ -63,7 +87,7 @@ impl Socket {
             }
             IpProtocol::Udp => {
                 let socket = sockets.get_mut::<udp::Socket>(self.socket_handle);
-                SocketType::Udp(socket, self.local_endpoint)
+                SocketType::Udp(socket, self.remote_endpoint)
             }
             _ => return Err(crate::Error::UnsupportedProtocol(self.ip_protocol)),
         };
@@ -93,7 +117,12 @@ impl<'a, 'b> SocketInstance<'a, 'b> {
     pub(crate) fn send(&mut self, data: &[u8]) -> crate::Result<usize> {
         match &mut self.instance {
             SocketType::Tcp(socket) => Ok(socket.send_slice(data)?),
-            SocketType::Udp(socket, local_endpoint) => Ok(socket.send_slice(data, *local_endpoint).and(Ok(data.len()))?),
+            SocketType::Udp(socket, remote_endpoint) => match socket.send_slice(data, *remote_endpoint) {
+                Ok(()) => Ok(data.len()),
+                // the datagram stays queued in the session until smoltcp sent those before it.
+                Err(udp::SendError::BufferFull) => Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into()),
+                Err(error) => Err(error.into()),
+            },
         }
     }
 
@@ -117,4 +146,49 @@ impl<'a, 'b> SocketInstance<'a, 'b> {
             SocketType::Udp(socket, _) => socket.close(),
         }
     }
+
+    /// Closes the socket at once, with a RST for TCP, dropping the data not yet sent.
+    pub(crate) fn abort(&mut self) {
+        match &mut self.instance {
+            SocketType::Tcp(socket) => socket.abort(),
+            SocketType::Udp(socket, _) => socket.close(),
+        }
+    }
+
+    /// State of the TCP socket, unset for UDP.
+    pub(crate) fn tcp_state(&self) -> Option<tcp::State> {
+        match &self.instance {
+            SocketType::Tcp(socket) => Some(socket.state()),
+            SocketType::Udp(_, _) => None,
+        }
+    }
+
+    /// Bytes in the send and the receive queue of the TCP socket, none for UDP.
+    pub(crate) fn queues(&self) -> (usize, usize) {
+        match &self.instance {
+            SocketType::Tcp(socket) => (socket.send_queue(), socket.recv_queue()),
+            SocketType::Udp(_, _) => (0, 0),
+        }
+    }
+
+    /// Whether the guest ended its sending half with a FIN, always unset for UDP.
+    pub(crate) fn is_receive_finished(&self) -> bool {
+        match &self.instance {
+            SocketType::Tcp(socket) => matches!(
+                socket.state(),
+                tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing | tcp::State::TimeWait
+            ),
+            SocketType::Udp(_, _) => false,
+        }
+    }
+
+    /// Whether the socket has nothing left to exchange with the guest.
+    ///
+    /// TCP sockets in TIME_WAIT have sent the final ACK of the close handshake.
+    pub(crate) fn is_terminated(&self) -> bool {
+        match &self.instance {
+            SocketType::Tcp(socket) => matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait),
+            SocketType::Udp(_, _) => true,
+        }
+    }
 }
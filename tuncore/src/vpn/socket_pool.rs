@@ -0,0 +1,46 @@
+use smoltcp::socket::{tcp, udp, Socket};
+
+/// smoltcp sockets of destroyed sessions, kept for the sessions created next so a short lived
+/// session does not allocate the megabytes of socket buffers anew. Holds at most
+/// `TunConfig::socket_pool_size` sockets of each protocol.
+pub(crate) struct SocketPool<'a> {
+    tcp: Vec<tcp::Socket<'a>>,
+    udp: Vec<udp::Socket<'a>>,
+    capacity: usize,
+}
+
+impl<'a> SocketPool<'a> {
+    pub(crate) fn new(capacity: usize) -> SocketPool<'a> {
+        SocketPool {
+            tcp: Vec::new(),
+            udp: Vec::new(),
+            capacity,
+        }
+    }
+
+    /// A closed TCP socket, `listen` resets its state and its buffers.
+    pub(crate) fn take_tcp(&mut self) -> Option<tcp::Socket<'a>> {
+        self.tcp.pop()
+    }
+
+    /// A closed UDP socket with empty buffers.
+    pub(crate) fn take_udp(&mut self) -> Option<udp::Socket<'a>> {
+        self.udp.pop()
+    }
+
+    /// Closes the socket of a destroyed session and keeps it, unless the pool is full. It was removed
+    /// from its socket set before, so closing it sends nothing to the guest.
+    pub(crate) fn put(&mut self, socket: Socket<'a>) {
+        match socket {
+            Socket::Tcp(mut socket) if self.tcp.len() < self.capacity => {
+                socket.abort();
+                self.tcp.push(socket);
+            }
+            Socket::Udp(mut socket) if self.udp.len() < self.capacity => {
+                socket.close();
+                self.udp.push(socket);
+            }
+            _ => {}
+        }
+    }
+}
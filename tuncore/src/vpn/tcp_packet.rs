@@ -0,0 +1,106 @@
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr, TcpControl, TcpPacket, TcpRepr, TcpSeqNumber},
+};
+
+/// Builds a TCP reset answering the TCP segment carried by the raw IP packet `bytes`.
+pub(crate) fn reset(bytes: &[u8], hop_limit: u8) -> crate::Result<Vec<u8>> {
+    let checksum = ChecksumCapabilities::default();
+    let (segment_src_addr, segment_dst_addr, payload) = match IpVersion::of_packet(bytes)? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(bytes)?;
+            let (src_addr, dst_addr) = (IpAddress::Ipv4(packet.src_addr()), IpAddress::Ipv4(packet.dst_addr()));
+            (src_addr, dst_addr, &bytes[packet.header_len() as usize..packet.total_len() as usize])
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(bytes)?;
+            let (src_addr, dst_addr) = (IpAddress::Ipv6(packet.src_addr()), IpAddress::Ipv6(packet.dst_addr()));
+            (src_addr, dst_addr, &bytes[packet.header_len()..packet.total_len()])
+        }
+    };
+    let segment = TcpRepr::parse(&TcpPacket::new_checked(payload)?, &segment_src_addr, &segment_dst_addr, &checksum)?;
+    if segment.control == TcpControl::Rst {
+        return Err(crate::Error::from("a reset is never answered with a reset"));
+    }
+
+    // RFC 9293 3.10.7.1: a segment carrying an ACK is reset from the acknowledged sequence number,
+    // any other is reset with an ACK covering the whole segment.
+    let (seq_number, ack_number) = match segment.ack_number {
+        Some(ack_number) => (ack_number, None),
+        None => (TcpSeqNumber(0), Some(segment.seq_number + segment.segment_len())),
+    };
+    let tcp_repr = TcpRepr {
+        src_port: segment.dst_port,
+        dst_port: segment.src_port,
+        control: TcpControl::Rst,
+        seq_number,
+        ack_number,
+        window_len: 0,
+        window_scale: None,
+        max_seg_size: None,
+        sack_permitted: false,
+        sack_ranges: [None; 3],
+        payload: &[],
+    };
+
+    // the reset travels back from the destination of the segment to its source.
+    let (src_addr, dst_addr) = (segment_dst_addr, segment_src_addr);
+    let (mut buffer, header_len) = match (src_addr, dst_addr) {
+        (IpAddress::Ipv4(src_addr), IpAddress::Ipv4(dst_addr)) => {
+            let ip_repr = Ipv4Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Tcp,
+                payload_len: tcp_repr.buffer_len(),
+                hop_limit,
+            };
+            let mut buffer = vec![0; ip_repr.buffer_len() + tcp_repr.buffer_len()];
+            ip_repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer[..]), &checksum);
+            (buffer, ip_repr.buffer_len())
+        }
+        (IpAddress::Ipv6(src_addr), IpAddress::Ipv6(dst_addr)) => {
+            let ip_repr = Ipv6Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Tcp,
+                payload_len: tcp_repr.buffer_len(),
+                hop_limit,
+            };
+            let mut buffer = vec![0; ip_repr.buffer_len() + tcp_repr.buffer_len()];
+            ip_repr.emit(&mut Ipv6Packet::new_unchecked(&mut buffer[..]));
+            (buffer, ip_repr.buffer_len())
+        }
+        _ => return Err(crate::Error::from("mismatching ip versions of tcp packet")),
+    };
+    tcp_repr.emit(&mut TcpPacket::new_unchecked(&mut buffer[header_len..]), &src_addr, &dst_addr, &checksum);
+    Ok(buffer)
+}
+
+/// Sequence number, length in sequence space and acknowledgment number, when it carries one, of
+/// the TCP segment in the raw IP packet `bytes`.
+pub(crate) fn sequence(bytes: &[u8]) -> Option<(TcpSeqNumber, usize, Option<TcpSeqNumber>)> {
+    let packet = segment(bytes)?;
+    let ack_number = packet.ack().then(|| packet.ack_number());
+    Some((packet.seq_number(), packet.segment_len(), ack_number))
+}
+
+/// Initial sequence number of the TCP segment in the raw IP packet `bytes` when it opens a
+/// connection, a SYN without an ACK.
+pub(crate) fn syn_sequence(bytes: &[u8]) -> Option<TcpSeqNumber> {
+    let packet = segment(bytes)?;
+    (packet.syn() && !packet.ack()).then(|| packet.seq_number())
+}
+
+fn segment(bytes: &[u8]) -> Option<TcpPacket<&[u8]>> {
+    let payload = match IpVersion::of_packet(bytes).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(bytes).ok().filter(|packet| packet.next_header() == IpProtocol::Tcp)?;
+            &bytes[packet.header_len() as usize..packet.total_len() as usize]
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(bytes).ok().filter(|packet| packet.next_header() == IpProtocol::Tcp)?;
+            &bytes[packet.header_len()..packet.total_len()]
+        }
+    };
+    TcpPacket::new_checked(payload).ok()
+}
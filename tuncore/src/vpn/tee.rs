@@ -0,0 +1,68 @@
+use crate::{vpn::session_info::SessionInfo, TeeTarget};
+use std::io::Write;
+
+/// Copy of the client to server byte stream of one session.
+///
+/// The tee is best effort: a failing sink is dropped after logging the error, the forwarded
+/// traffic is never held back by it. A unix socket is written without blocking, so a reader that
+/// does not keep up is dropped as well, its copy ends there rather than having a gap.
+pub(crate) struct Tee {
+    writer: Option<Box<dyn Write + Send>>,
+}
+
+impl Tee {
+    pub(crate) fn new(target: &TeeTarget, info: &SessionInfo) -> Tee {
+        let writer = Self::open(target, info)
+            .map_err(|error| log::error!("failed to open tee, {:?} error={:?}", info, error))
+            .ok();
+        Tee { writer }
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        if let Some(writer) = self.writer.as_mut() {
+            match writer.write_all(bytes) {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    log::error!("tee reader does not keep up, dropping tee");
+                    self.writer = None;
+                }
+                Err(error) => {
+                    log::error!("failed to write to tee, error={:?}", error);
+                    self.writer = None;
+                }
+            }
+        }
+    }
+
+    fn open(target: &TeeTarget, info: &SessionInfo) -> std::io::Result<Box<dyn Write + Send>> {
+        match target {
+            TeeTarget::Directory(directory) => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let name = format!(
+                    "{}-{}-{}-{}.bin",
+                    timestamp,
+                    info.ip_protocol,
+                    info.source.to_string().replace(':', "_"),
+                    info.destination.to_string().replace(':', "_")
+                );
+                Ok(Box::new(std::fs::File::create(directory.join(name))?))
+            }
+            #[cfg(unix)]
+            TeeTarget::UnixSocket(path) => {
+                // a reader with a full backlog fails the connect instead of blocking it.
+                let socket = socket2::Socket::new(socket2::Domain::UNIX, socket2::Type::STREAM, None)?;
+                socket.set_nonblocking(true)?;
+                socket.connect(&socket2::SockAddr::unix(path)?)?;
+                let mut stream = std::os::unix::net::UnixStream::from(socket);
+                // the stream starts with a line identifying the session.
+                writeln!(stream, "{}", info)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(not(unix))]
+            TeeTarget::UnixSocket(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "unix sockets are not supported")),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr, UdpPacket, UdpRepr},
+};
+use std::net::{IpAddr, SocketAddr};
+
+/// Builds a raw IP packet carrying `payload` in a UDP datagram from `source` to `destination`.
+pub(crate) fn build(source: SocketAddr, destination: SocketAddr, payload: &[u8], hop_limit: u8) -> crate::Result<Vec<u8>> {
+    let checksum = ChecksumCapabilities::default();
+    let udp_repr = UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    };
+    let udp_len = udp_repr.header_len() + payload.len();
+
+    let (mut buffer, header_len, src_addr, dst_addr) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            let ip_repr = Ipv4Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit,
+            };
+            let mut buffer = vec![0; ip_repr.buffer_len() + udp_len];
+            ip_repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer[..]), &checksum);
+            (
+                buffer,
+                ip_repr.buffer_len(),
+                IpAddress::Ipv4(ip_repr.src_addr),
+                IpAddress::Ipv4(ip_repr.dst_addr),
+            )
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            let ip_repr = Ipv6Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit,
+            };
+            let mut buffer = vec![0; ip_repr.buffer_len() + udp_len];
+            ip_repr.emit(&mut Ipv6Packet::new_unchecked(&mut buffer[..]));
+            (
+                buffer,
+                ip_repr.buffer_len(),
+                IpAddress::Ipv6(ip_repr.src_addr),
+                IpAddress::Ipv6(ip_repr.dst_addr),
+            )
+        }
+        _ => return Err(crate::Error::from("mismatching ip versions of udp packet")),
+    };
+
+    udp_repr.emit(
+        &mut UdpPacket::new_unchecked(&mut buffer[header_len..]),
+        &src_addr,
+        &dst_addr,
+        payload.len(),
+        |buffer| buffer.copy_from_slice(payload),
+        &checksum,
+    );
+    Ok(buffer)
+}
+
+/// Returns the payload of the UDP datagram carried by the raw IP packet `bytes`.
+pub(crate) fn payload(bytes: &[u8]) -> crate::Result<&[u8]> {
+    let payload = match IpVersion::of_packet(bytes)? {
+        IpVersion::Ipv4 => Ipv4Packet::new_checked(bytes)?.payload(),
+        IpVersion::Ipv6 => Ipv6Packet::new_checked(bytes)?.payload(),
+    };
+    Ok(UdpPacket::new_checked(payload)?.payload())
+}
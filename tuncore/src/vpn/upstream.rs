@@ -0,0 +1,120 @@
+use crate::vpn::{mio_socket, session_info::SessionInfo};
+use mio::{Poll, Token};
+use std::{fmt, sync::Arc};
+
+/// The server side of a TCP or UDP session: where the data of the guest is written to and the
+/// answers are read from. The direct sockets and the loopback of `Upstream` are built in, other
+/// transports, e.g. a tunnel of the app, are supplied with `Upstream::Custom`.
+///
+/// The processor drives a transport from its poll loop: it registers it with the token of the
+/// session, reads it on readable events and writes it on writable ones. Every call must return
+/// instead of blocking, with `ErrorKind::WouldBlock` from `write` when nothing can be taken yet.
+/// The processor runs on a thread of its own, so the transports are `Send`.
+pub trait UpstreamTransport: Send {
+    /// Registers the sources whose events announce readiness with `token`. A transport without any,
+    /// like the loopback, reports its data through `has_unannounced_data` instead.
+    fn register_poll(&mut self, poll: &mut Poll, token: Token) -> std::io::Result<()>;
+
+    fn deregister_poll(&mut self, poll: &mut Poll) -> std::io::Result<()>;
+
+    /// Writes what it can of `bytes` for the server and returns the count taken. A stream ends its
+    /// bytes at will, a datagram transport takes `bytes` whole as one datagram.
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize>;
+
+    /// Hands everything readable to `callback`, a chunk of a stream or a datagram per call, until it
+    /// would block. Sets `is_closed` once the server ended the connection; an error of `callback`
+    /// stops the read and is returned, the rest is read on a later call.
+    fn read(&mut self, is_closed: &mut bool, callback: &mut dyn FnMut(&mut [u8]) -> std::io::Result<()>) -> std::io::Result<()>;
+
+    /// Whether the connection to the server is established, checked on the writable events of a TCP
+    /// session and, for a custom transport, once at its creation. A failed connect is reported as an error.
+    fn is_connected(&self) -> std::io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Takes the pending error of the transport, checked on the error events.
+    fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        Ok(None)
+    }
+
+    /// Ends the connection, nothing is read or written afterwards.
+    fn close(&self) {}
+
+    /// Passes the end of the data of the guest on to the server, which can still answer.
+    fn shutdown_write(&self) {}
+
+    /// Whether data is waiting to be read that no poll event will announce.
+    fn has_unannounced_data(&self) -> bool {
+        false
+    }
+}
+
+type Connect = dyn Fn(&SessionInfo) -> std::io::Result<Box<dyn UpstreamTransport>> + Send + Sync;
+
+/// Creates the transport of each TCP and UDP session for `Upstream::Custom`, an error refuses the
+/// session. Called on the processor thread, so it must not block on the connect.
+#[derive(Clone)]
+pub struct UpstreamConnector(Arc<Connect>);
+
+impl UpstreamConnector {
+    pub fn new(connect: impl Fn(&SessionInfo) -> std::io::Result<Box<dyn UpstreamTransport>> + Send + Sync + 'static) -> UpstreamConnector {
+        UpstreamConnector(Arc::new(connect))
+    }
+
+    pub(crate) fn connect(&self, session_info: &SessionInfo) -> std::io::Result<Box<dyn UpstreamTransport>> {
+        (self.0)(session_info)
+    }
+}
+
+impl fmt::Debug for UpstreamConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UpstreamConnector")
+    }
+}
+
+// two connectors are only known to be the same when they are clones of each other.
+impl PartialEq for UpstreamConnector {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for UpstreamConnector {}
+
+impl UpstreamTransport for mio_socket::Socket {
+    fn register_poll(&mut self, poll: &mut Poll, token: Token) -> std::io::Result<()> {
+        mio_socket::Socket::register_poll(self, poll, token)
+    }
+
+    fn deregister_poll(&mut self, poll: &mut Poll) -> std::io::Result<()> {
+        mio_socket::Socket::deregister_poll(self, poll)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        mio_socket::Socket::write(self, bytes)
+    }
+
+    fn read(&mut self, is_closed: &mut bool, callback: &mut dyn FnMut(&mut [u8]) -> std::io::Result<()>) -> std::io::Result<()> {
+        mio_socket::Socket::read(self, is_closed, callback)
+    }
+
+    fn is_connected(&self) -> std::io::Result<bool> {
+        mio_socket::Socket::is_connected(self)
+    }
+
+    fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        mio_socket::Socket::take_error(self)
+    }
+
+    fn close(&self) {
+        mio_socket::Socket::close(self)
+    }
+
+    fn shutdown_write(&self) {
+        mio_socket::Socket::shutdown_write(self)
+    }
+
+    fn has_unannounced_data(&self) -> bool {
+        mio_socket::Socket::has_unannounced_data(self)
+    }
+}
@@ -1,31 +1,97 @@
+use crate::{vpn::session_info::SessionInfo, PacketLogConfig};
 use smoltcp::wire::{IpProtocol, Ipv4Packet, TcpPacket, UdpPacket};
+use std::fmt;
 
-pub fn log_packet(message: &str, bytes: &[u8]) {
+/// Logs the packets selected by a `PacketLogConfig`, keeping count for the sampling.
+pub(crate) struct PacketLogger {
+    enabled: bool,
+    sample_rate: u32,
+    max_bytes: usize,
+    count: u32,
+}
+
+impl PacketLogger {
+    /// Creates the logger of a session, or of packets outside of any session when `session_info` is `None`.
+    pub(crate) fn new(config: &PacketLogConfig, session_info: Option<&SessionInfo>) -> PacketLogger {
+        let enabled = match session_info {
+            Some(session_info) => {
+                let destination = session_info.destination;
+                (config.destinations.is_empty() || config.destinations.iter().any(|network| network.contains(&destination.ip())))
+                    && config.port.is_none_or(|port| port == destination.port())
+            }
+            None => config.destinations.is_empty() && config.port.is_none(),
+        };
+        PacketLogger {
+            enabled,
+            sample_rate: config.sample_rate.max(1),
+            max_bytes: config.max_bytes,
+            count: 0,
+        }
+    }
+
+    pub(crate) fn log_packet(&mut self, message: &str, bytes: &[u8]) {
+        if !self.enabled || !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        let sampled = self.count.is_multiple_of(self.sample_rate);
+        self.count = self.count.wrapping_add(1);
+        if sampled {
+            log_packet(message, bytes, self.max_bytes);
+        }
+    }
+}
+
+/// Logs the headers of the packet, followed by up to `max_bytes` bytes of its TCP or UDP payload.
+pub fn log_packet(message: &str, bytes: &[u8], max_bytes: usize) {
     let result = Ipv4Packet::new_checked(&bytes);
     match result {
+        Ok(ip_packet) if ip_packet.version() != 4 => {
+            log::debug!("[{:?}] len={:?} not an ipv4 packet", message, bytes.len());
+        }
         Ok(ip_packet) => match ip_packet.next_header() {
             IpProtocol::Tcp => {
                 let tcp_bytes = ip_packet.payload();
-                let tcp_packet = TcpPacket::new_checked(tcp_bytes).unwrap();
+                // the packet comes from the guest or the network, a cut header is logged and not trusted.
+                let Ok(tcp_packet) = TcpPacket::new_checked(tcp_bytes) else {
+                    log::debug!(
+                        "[{:?}] len={:?} short tcp header tcp_len={:?} ip=[{}]",
+                        message,
+                        bytes.len(),
+                        tcp_bytes.len(),
+                        ip_packet
+                    );
+                    return;
+                };
                 log::trace!(
-                    "[{:?}] len={:?} tcp=[{}] tcp_len={:?} ip=[{}]",
+                    "[{:?}] len={:?} tcp=[{}] tcp_len={:?} ip=[{}]{}",
                     message,
                     bytes.len(),
                     tcp_packet,
                     tcp_bytes.len(),
-                    ip_packet
+                    ip_packet,
+                    PayloadDump(tcp_packet.payload(), max_bytes)
                 );
             }
             IpProtocol::Udp => {
                 let udp_bytes = ip_packet.payload();
-                let udp_packet = UdpPacket::new_checked(udp_bytes).unwrap();
+                let Ok(udp_packet) = UdpPacket::new_checked(udp_bytes) else {
+                    log::debug!(
+                        "[{:?}] len={:?} short udp header udp_len={:?} ip=[{}]",
+                        message,
+                        bytes.len(),
+                        udp_bytes.len(),
+                        ip_packet
+                    );
+                    return;
+                };
                 log::trace!(
-                    "[{:?}] len={:?} udp=[{}] udp_len={:?} ip=[{}]",
+                    "[{:?}] len={:?} udp=[{}] udp_len={:?} ip=[{}]{}",
                     message,
                     bytes.len(),
                     udp_packet,
                     udp_bytes.len(),
-                    ip_packet
+                    ip_packet,
+                    PayloadDump(udp_packet.payload(), max_bytes)
                 );
             }
             _ => {
@@ -37,3 +103,25 @@ pub fn log_packet(message: &str, bytes: &[u8]) {
         }
     }
 }
+
+// hex dump of the first bytes of a payload, with the count of the bytes left out. nothing at all
+// when no byte is to be dumped.
+struct PayloadDump<'a>(&'a [u8], usize);
+
+impl fmt::Display for PayloadDump<'_> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let PayloadDump(payload, max_bytes) = *self;
+        if max_bytes == 0 || payload.is_empty() {
+            return Ok(());
+        }
+        write!(formatter, " payload=[")?;
+        for byte in payload.iter().take(max_bytes) {
+            write!(formatter, "{:02x}", byte)?;
+        }
+        write!(formatter, "]")?;
+        if payload.len() > max_bytes {
+            write!(formatter, " truncated={}", payload.len() - max_bytes)?;
+        }
+        Ok(())
+    }
+}